@@ -83,8 +83,7 @@ fn binary_data(size: usize) -> Vec<u8> {
 
     let mut f = File::open("/usr/bin/wget").unwrap();
     let f_size = f.metadata().unwrap().len() as usize;
-    let mut v = Vec::with_capacity(size);
-    unsafe { v.set_len(size); }
+    let mut v = vec![0; size];
     f.read_exact(&mut v[..size.min(f_size)]).unwrap();
     if f_size < size {
         v = v.into_iter().cycle().take(size).collect();
@@ -99,10 +98,10 @@ pub fn histogram(data: &[u8]) {
     for p in &pdf {
         if max < *p { max = *p; }
     }
-    for i in 0..256 {
-        let n = (pdf[i] / max * 100.0).round() as usize;
+    for (i, p) in pdf.iter().enumerate().take(256) {
+        let n = (p / max * 100.0).round() as usize;
         if n > 0 {
-            println!("{:3}: {:5.2}% | {}", i, pdf[i], ::std::iter::repeat('#').take(n).collect::<String>());
+            println!("{:3}: {:5.2}% | {}", i, p, "#".repeat(n));
         }
     }
 }
@@ -0,0 +1,21 @@
+extern crate cbindgen;
+
+use std::env;
+use std::path::Path;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(Path::new(&dir).join("cbindgen.toml")).unwrap();
+    // checked against include/bzlib.h by the tests
+    let out = env::var("OUT_DIR").unwrap();
+    cbindgen::generate_with_config(&dir, config)
+        .expect("generating bzlib.h")
+        .write_to_file(Path::new(&out).join("bzlib.h"));
+
+    // what consumers of the C library were linked against
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") {
+        println!("cargo:rustc-cdylib-link-arg=-Wl,-soname,libbz2.so.1.0");
+    }
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
@@ -0,0 +1,616 @@
+//! libbz2's stdio-level interface: `BZFILE`s read from and written to a
+//! `FILE`, through `bufread::BzReader` and `BzWriter`, and the zlib-style
+//! `BZ2_bzopen` family on top of them.
+//!
+//! As with libbz2, a `BZFILE` being read stops at the end of the first
+//! stream. `BZ2_bzReadGetUnused` then hands over what was read past it, to
+//! be passed to the next `BZ2_bzReadOpen` for a concatenated stream.
+
+use std::ffi::CStr;
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::{ptr, slice};
+
+use bzip2_rs::bufread::BzReader;
+use bzip2_rs::{BzWriter, Error};
+
+use {error_code, valid_params, BZ_DATA_ERROR, BZ_IO_ERROR, BZ_OK, BZ_PARAM_ERROR, BZ_SEQUENCE_ERROR,
+     BZ_STREAM_END, BZ_UNEXPECTED_EOF};
+
+/// The most input a `BZFILE` reads ahead of what it decodes, and so the
+/// most `BZ2_bzReadGetUnused` can hand over.
+pub const BZ_MAX_UNUSED: c_int = 5000;
+
+/// A stdio stream, as `<stdio.h>` declares it.
+pub enum FILE {}
+
+/// A bzip2 stream being read from or written to a `FILE`.
+pub type BZFILE = c_void;
+
+extern "C" {
+    fn fopen(path: *const c_char, mode: *const c_char) -> *mut FILE;
+    fn fdopen(fd: c_int, mode: *const c_char) -> *mut FILE;
+    fn fread(ptr: *mut c_void, size: usize, n: usize, f: *mut FILE) -> usize;
+    fn fwrite(ptr: *const c_void, size: usize, n: usize, f: *mut FILE) -> usize;
+    fn ferror(f: *mut FILE) -> c_int;
+    fn fflush(f: *mut FILE) -> c_int;
+    fn fclose(f: *mut FILE) -> c_int;
+    fn dup(fd: c_int) -> c_int;
+}
+
+// A `FILE` as a reader or writer. Once abandoned, writes are dropped.
+struct CFile {
+    file: *mut FILE,
+    abandoned: bool,
+}
+
+impl Read for CFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { fread(buf.as_mut_ptr() as *mut c_void, 1, buf.len(), self.file) };
+        if n == 0 && unsafe { ferror(self.file) } != 0 {
+            return Err(io::Error::other("error reading FILE"));
+        }
+        Ok(n)
+    }
+}
+
+impl Write for CFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.abandoned {
+            return Ok(buf.len());
+        }
+        let n = unsafe { fwrite(buf.as_ptr() as *const c_void, 1, buf.len(), self.file) };
+        if n < buf.len() {
+            return Err(io::Error::other("error writing FILE"));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.abandoned && unsafe { fflush(self.file) } != 0 {
+            return Err(io::Error::other("error flushing FILE"));
+        }
+        Ok(())
+    }
+}
+
+// any input left over from the previous stream comes first
+type Reader = BzReader<BufReader<io::Chain<Cursor<Vec<u8>>, CFile>>>;
+
+enum Handle {
+    Read(Box<Reader>),
+    Write(Box<BzWriter<CFile>>),
+}
+
+struct BzFile {
+    handle: Handle,
+    last_err: c_int,
+    // the `FILE` `BZ2_bzopen` opened, to be closed with the `BZFILE`
+    owned: *mut FILE,
+}
+
+unsafe fn set(bzerror: *mut c_int, file: Option<&mut BzFile>, err: c_int) {
+    if !bzerror.is_null() {
+        *bzerror = err;
+    }
+    if let Some(file) = file {
+        file.last_err = err;
+    }
+}
+
+// The return code for an error from `BzReader`.
+fn read_error_code(err: &io::Error) -> c_int {
+    match err.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+        Some(&Error::UnexpectedEof { .. }) => BZ_UNEXPECTED_EOF,
+        Some(err) => error_code(err),
+        None => BZ_IO_ERROR,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzReadOpen(bzerror: *mut c_int, f: *mut FILE, verbosity: c_int, small: c_int,
+                                        unused: *mut c_void, nUnused: c_int) -> *mut BZFILE {
+    if f.is_null() || !(0..=1).contains(&small) || !(0..=4).contains(&verbosity) ||
+       (unused.is_null() && nUnused != 0) || !(0..=BZ_MAX_UNUSED).contains(&nUnused) {
+        set(bzerror, None, BZ_PARAM_ERROR);
+        return ptr::null_mut();
+    }
+    let unused = if nUnused == 0 {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(unused as *const u8, nUnused as usize).to_vec()
+    };
+    let file = CFile { file: f, abandoned: false };
+    let input = BufReader::with_capacity(BZ_MAX_UNUSED as usize, Cursor::new(unused).chain(file));
+    let file = Box::new(BzFile {
+        handle: Handle::Read(Box::new(BzReader::new(input).multi_stream(false))),
+        last_err: BZ_OK,
+        owned: ptr::null_mut(),
+    });
+    set(bzerror, None, BZ_OK);
+    Box::into_raw(file) as *mut BZFILE
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzRead(bzerror: *mut c_int, b: *mut BZFILE, buf: *mut c_void, len: c_int) -> c_int {
+    let file = match (b as *mut BzFile).as_mut() {
+        Some(file) => file,
+        None => {
+            set(bzerror, None, BZ_PARAM_ERROR);
+            return 0;
+        }
+    };
+    if (buf.is_null() && len != 0) || len < 0 {
+        set(bzerror, Some(file), BZ_PARAM_ERROR);
+        return 0;
+    }
+    let reader = match file.handle {
+        Handle::Read(ref mut reader) if file.last_err != BZ_STREAM_END => reader,
+        _ => {
+            set(bzerror, Some(file), BZ_SEQUENCE_ERROR);
+            return 0;
+        }
+    };
+    if len == 0 {
+        set(bzerror, Some(file), BZ_OK);
+        return 0;
+    }
+
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, len as usize);
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => {
+                set(bzerror, Some(file), BZ_STREAM_END);
+                return n as c_int;
+            }
+            Ok(read) => n += read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                set(bzerror, Some(file), read_error_code(&err));
+                return 0;
+            }
+        }
+    }
+    set(bzerror, Some(file), BZ_OK);
+    n as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzReadGetUnused(bzerror: *mut c_int, b: *mut BZFILE, unused: *mut *mut c_void,
+                                             nUnused: *mut c_int) {
+    let file = match (b as *mut BzFile).as_mut() {
+        Some(file) => file,
+        None => return set(bzerror, None, BZ_PARAM_ERROR),
+    };
+    if unused.is_null() || nUnused.is_null() {
+        return set(bzerror, Some(file), BZ_PARAM_ERROR);
+    }
+    let left = match file.handle {
+        Handle::Read(ref reader) if file.last_err == BZ_STREAM_END => reader.get_ref().buffer(),
+        _ => return set(bzerror, Some(file), BZ_SEQUENCE_ERROR),
+    };
+    *unused = left.as_ptr() as *mut c_void;
+    *nUnused = left.len() as c_int;
+    // the state of the BZFILE itself is unchanged
+    if !bzerror.is_null() {
+        *bzerror = BZ_OK;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzReadClose(bzerror: *mut c_int, b: *mut BZFILE) {
+    let file = match (b as *mut BzFile).as_mut() {
+        Some(file) => file,
+        None => return set(bzerror, None, BZ_OK),
+    };
+    if let Handle::Write(_) = file.handle {
+        return set(bzerror, Some(file), BZ_SEQUENCE_ERROR);
+    }
+    drop(Box::from_raw(file as *mut BzFile));
+    set(bzerror, None, BZ_OK);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzWriteOpen(bzerror: *mut c_int, f: *mut FILE, blockSize100k: c_int,
+                                         verbosity: c_int, workFactor: c_int) -> *mut BZFILE {
+    if f.is_null() || !valid_params(blockSize100k, verbosity, workFactor) {
+        set(bzerror, None, BZ_PARAM_ERROR);
+        return ptr::null_mut();
+    }
+    let file = CFile { file: f, abandoned: false };
+    let file = Box::new(BzFile {
+        handle: Handle::Write(Box::new(BzWriter::new(file, blockSize100k as u32))),
+        last_err: BZ_OK,
+        owned: ptr::null_mut(),
+    });
+    set(bzerror, None, BZ_OK);
+    Box::into_raw(file) as *mut BZFILE
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzWrite(bzerror: *mut c_int, b: *mut BZFILE, buf: *mut c_void, len: c_int) {
+    let file = match (b as *mut BzFile).as_mut() {
+        Some(file) => file,
+        None => return set(bzerror, None, BZ_PARAM_ERROR),
+    };
+    if (buf.is_null() && len != 0) || len < 0 {
+        return set(bzerror, Some(file), BZ_PARAM_ERROR);
+    }
+    let writer = match file.handle {
+        Handle::Write(ref mut writer) if file.last_err == BZ_OK => writer,
+        _ => return set(bzerror, Some(file), BZ_SEQUENCE_ERROR),
+    };
+    if len == 0 {
+        return set(bzerror, Some(file), BZ_OK);
+    }
+    let buf = slice::from_raw_parts(buf as *const u8, len as usize);
+    let err = match writer.write_all(buf) {
+        Ok(()) => BZ_OK,
+        Err(_) => BZ_IO_ERROR,
+    };
+    set(bzerror, Some(file), err);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzWriteClose(bzerror: *mut c_int, b: *mut BZFILE, abandon: c_int,
+                                          nbytes_in: *mut c_uint, nbytes_out: *mut c_uint) {
+    BZ2_bzWriteClose64(bzerror, b, abandon, nbytes_in, ptr::null_mut(), nbytes_out, ptr::null_mut());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzWriteClose64(bzerror: *mut c_int, b: *mut BZFILE, abandon: c_int,
+                                            nbytes_in_lo32: *mut c_uint, nbytes_in_hi32: *mut c_uint,
+                                            nbytes_out_lo32: *mut c_uint, nbytes_out_hi32: *mut c_uint) {
+    for &count in &[nbytes_in_lo32, nbytes_in_hi32, nbytes_out_lo32, nbytes_out_hi32] {
+        if !count.is_null() {
+            *count = 0;
+        }
+    }
+    let file = match (b as *mut BzFile).as_mut() {
+        Some(file) => file,
+        None => return set(bzerror, None, BZ_OK),
+    };
+    let writer = match file.handle {
+        Handle::Write(ref mut writer) => writer,
+        Handle::Read(_) => return set(bzerror, Some(file), BZ_SEQUENCE_ERROR),
+    };
+
+    // like libbz2, a BZFILE that failed is abandoned
+    let mut err = BZ_OK;
+    if abandon == 0 && file.last_err == BZ_OK && writer.try_finish().and_then(|()| writer.flush()).is_err() {
+        err = BZ_IO_ERROR;
+    }
+    let counts = [(nbytes_in_lo32, nbytes_in_hi32, writer.total_in()),
+                      (nbytes_out_lo32, nbytes_out_hi32, writer.total_out())];
+    for &(lo32, hi32, total) in &counts {
+        if !lo32.is_null() {
+            *lo32 = total as c_uint;
+        }
+        if !hi32.is_null() {
+            *hi32 = (total >> 32) as c_uint;
+        }
+    }
+    // nothing more is written once abandoned, even as the writer drops
+    writer.get_mut().abandoned = true;
+    drop(Box::from_raw(file as *mut BzFile));
+    set(bzerror, None, err);
+}
+
+// Opens the file descriptor `fd`, or else `path`, as `BZ2_bzdopen` and
+// `BZ2_bzopen` do.
+unsafe fn open(path: *const c_char, fd: Option<c_int>, mode: *const c_char) -> *mut BZFILE {
+    if mode.is_null() {
+        return ptr::null_mut();
+    }
+    let mut writing = false;
+    let mut small = 0;
+    let mut block_size_100k = 9;
+    for &c in CStr::from_ptr(mode).to_bytes() {
+        match c {
+            b'r' => writing = false,
+            b'w' => writing = true,
+            b's' => small = 1,
+            b'1'..=b'9' => block_size_100k = (c - b'0') as c_int,
+            _ => {}
+        }
+    }
+    let fmode: &[u8] = if writing { b"wb\0" } else { b"rb\0" };
+    let fmode = fmode.as_ptr() as *const c_char;
+
+    let f = match fd {
+        Some(fd) => fdopen(fd, fmode),
+        None if !path.is_null() && *path != 0 => fopen(path, fmode),
+        None => {
+            // a null or empty path is standard input or output, which must
+            // stay open once the BZFILE is closed
+            let fd = dup(if writing { 1 } else { 0 });
+            if fd < 0 {
+                return ptr::null_mut();
+            }
+            fdopen(fd, fmode)
+        }
+    };
+    if f.is_null() {
+        return ptr::null_mut();
+    }
+
+    let mut bzerr = BZ_OK;
+    let b = if writing {
+        BZ2_bzWriteOpen(&mut bzerr, f, block_size_100k, 0, 30)
+    } else {
+        BZ2_bzReadOpen(&mut bzerr, f, 0, small, ptr::null_mut(), 0)
+    };
+    if b.is_null() {
+        fclose(f);
+        return ptr::null_mut();
+    }
+    (*(b as *mut BzFile)).owned = f;
+    b
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzopen(path: *const c_char, mode: *const c_char) -> *mut BZFILE {
+    open(path, None, mode)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzdopen(fd: c_int, mode: *const c_char) -> *mut BZFILE {
+    open(ptr::null(), Some(fd), mode)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzread(b: *mut BZFILE, buf: *mut c_void, len: c_int) -> c_int {
+    match (b as *mut BzFile).as_ref() {
+        Some(file) if file.last_err == BZ_STREAM_END => return 0,
+        _ => {}
+    }
+    let mut bzerr = BZ_OK;
+    let n = BZ2_bzRead(&mut bzerr, b, buf, len);
+    if bzerr == BZ_OK || bzerr == BZ_STREAM_END { n } else { -1 }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzwrite(b: *mut BZFILE, buf: *mut c_void, len: c_int) -> c_int {
+    let mut bzerr = BZ_OK;
+    BZ2_bzWrite(&mut bzerr, b, buf, len);
+    if bzerr == BZ_OK { len } else { -1 }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzflush(_b: *mut BZFILE) -> c_int {
+    // as in libbz2, there is nothing to do
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzclose(b: *mut BZFILE) {
+    let file = match (b as *mut BzFile).as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+    let owned = file.owned;
+    let mut bzerr = BZ_OK;
+    if let Handle::Write(_) = file.handle {
+        BZ2_bzWriteClose(&mut bzerr, b, 0, ptr::null_mut(), ptr::null_mut());
+    } else {
+        BZ2_bzReadClose(&mut bzerr, b);
+    }
+    if !owned.is_null() {
+        fclose(owned);
+    }
+}
+
+static ERRORS: [&[u8]; 10] = [
+    b"OK\0",
+    b"SEQUENCE_ERROR\0",
+    b"PARAM_ERROR\0",
+    b"MEM_ERROR\0",
+    b"DATA_ERROR\0",
+    b"DATA_ERROR_MAGIC\0",
+    b"IO_ERROR\0",
+    b"UNEXPECTED_EOF\0",
+    b"OUTBUFF_FULL\0",
+    b"CONFIG_ERROR\0",
+];
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzerror(b: *mut BZFILE, errnum: *mut c_int) -> *const c_char {
+    let err = match (b as *mut BzFile).as_ref() {
+        Some(file) => file.last_err.min(BZ_OK),
+        None => BZ_PARAM_ERROR,
+    };
+    if !errnum.is_null() {
+        *errnum = err;
+    }
+    let message = ERRORS.get(-err as usize).unwrap_or(&ERRORS[-BZ_DATA_ERROR as usize]);
+    message.as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::io::prelude::*;
+
+    use bzip2_rs::BzReader as Decoder;
+
+    use super::*;
+    use BZ_DATA_ERROR_MAGIC;
+
+    extern "C" {
+        fn tmpfile() -> *mut FILE;
+        fn rewind(f: *mut FILE);
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 99u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            if (x >> 16).is_multiple_of(4) { (x >> 8) as u8 } else { (i / 700) as u8 }
+        }).collect()
+    }
+
+    // Writes `data` through a BZFILE into a temporary FILE, rewound to its
+    // start, returning it and the totals reported.
+    unsafe fn write(data: &[u8], abandon: c_int) -> (*mut FILE, u64, u64) {
+        let f = tmpfile();
+        let mut err = 0;
+        let b = BZ2_bzWriteOpen(&mut err, f, 1, 0, 0);
+        assert_eq!(err, BZ_OK);
+        for chunk in data.chunks(3333) {
+            BZ2_bzWrite(&mut err, b, chunk.as_ptr() as *mut c_void, chunk.len() as c_int);
+            assert_eq!(err, BZ_OK);
+        }
+        let mut counts = [1 as c_uint; 4];
+        let [ref mut in_lo, ref mut in_hi, ref mut out_lo, ref mut out_hi] = counts;
+        BZ2_bzWriteClose64(&mut err, b, abandon, in_lo, in_hi, out_lo, out_hi);
+        assert_eq!(err, BZ_OK);
+        rewind(f);
+        let total = |lo: c_uint, hi: c_uint| (hi as u64) << 32 | lo as u64;
+        (f, total(counts[0], counts[1]), total(counts[2], counts[3]))
+    }
+
+    unsafe fn contents(f: *mut FILE) -> Vec<u8> {
+        let mut out = vec![];
+        CFile { file: f, abandoned: false }.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn writes_and_reads_concatenated_streams() {
+        let data = sample(250_000);
+        unsafe {
+            let (f, total_in, total_out) = write(&data, 0);
+            let mut compressed = contents(f);
+            fclose(f);
+            assert_eq!(total_in, data.len() as u64);
+            assert_eq!(total_out, compressed.len() as u64);
+            let mut out = vec![];
+            Decoder::new(&compressed[..]).read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+
+            // an abandoned stream is left unfinished
+            let (f, total_in, total_out) = write(&data, 1);
+            let abandoned = contents(f);
+            fclose(f);
+            assert_eq!(total_in, data.len() as u64);
+            assert!(total_out > 0 && total_out < compressed.len() as u64);
+            assert!(abandoned.len() as u64 <= total_out);
+            assert!(Decoder::new(&abandoned[..]).read_to_end(&mut vec![]).is_err());
+
+            // two streams, read one after the other with the unused bytes
+            // handed from the first BZFILE to the second
+            let first = compressed.clone();
+            compressed.extend_from_slice(&first);
+            let f = tmpfile();
+            CFile { file: f, abandoned: false }.write_all(&compressed).unwrap();
+            rewind(f);
+
+            let mut err = 0;
+            let mut unused = Vec::new();
+            for _ in 0..2 {
+                let b = BZ2_bzReadOpen(&mut err, f, 0, 0, unused.as_mut_ptr() as *mut c_void, unused.len() as c_int);
+                assert_eq!(err, BZ_OK);
+                let mut out = vec![];
+                let mut buf = [0u8; 7777];
+                loop {
+                    let n = BZ2_bzRead(&mut err, b, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int);
+                    out.extend_from_slice(&buf[..n as usize]);
+                    if err == BZ_STREAM_END {
+                        break;
+                    }
+                    assert_eq!(err, BZ_OK);
+                }
+                assert_eq!(out, data);
+
+                let (mut ptr, mut n) = (ptr::null_mut(), 0);
+                BZ2_bzReadGetUnused(&mut err, b, &mut ptr, &mut n);
+                assert_eq!(err, BZ_OK);
+                unused = slice::from_raw_parts(ptr as *const u8, n as usize).to_vec();
+
+                // the stream has ended
+                assert_eq!(BZ2_bzRead(&mut err, b, buf.as_mut_ptr() as *mut c_void, 1), 0);
+                assert_eq!(err, BZ_SEQUENCE_ERROR);
+                BZ2_bzReadGetUnused(&mut err, b, &mut ptr, &mut n);
+                assert_eq!(err, BZ_SEQUENCE_ERROR);
+                BZ2_bzReadClose(&mut err, b);
+                assert_eq!(err, BZ_OK);
+            }
+            assert!(unused.is_empty());
+            assert!(contents(f).is_empty());
+            fclose(f);
+        }
+    }
+
+    #[test]
+    fn reports_errors() {
+        let data = sample(10_000);
+        unsafe {
+            let (f, _, _) = write(&data, 0);
+            let mut compressed = contents(f);
+            fclose(f);
+            compressed.truncate(compressed.len() - 10);
+
+            let mut err = 0;
+            let mut buf = vec![0u8; data.len() * 2];
+            let cases = [(&compressed[..], BZ_UNEXPECTED_EOF, "UNEXPECTED_EOF"),
+                         (&b"BZh11AY&SX"[..], BZ_DATA_ERROR, "DATA_ERROR"),
+                         (&b"BZh0"[..], BZ_DATA_ERROR_MAGIC, "DATA_ERROR_MAGIC")];
+            for &(input, expected, name) in &cases {
+                let f = tmpfile();
+                CFile { file: f, abandoned: false }.write_all(input).unwrap();
+                rewind(f);
+                let b = BZ2_bzReadOpen(&mut err, f, 0, 0, ptr::null_mut(), 0);
+                assert_eq!(BZ2_bzRead(&mut err, b, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int), 0);
+                assert_eq!(err, expected);
+                let mut errnum = 0;
+                assert_eq!(CStr::from_ptr(BZ2_bzerror(b, &mut errnum)).to_str(), Ok(name));
+                assert_eq!(errnum, expected);
+                BZ2_bzWrite(&mut err, b, buf.as_mut_ptr() as *mut c_void, 1);
+                assert_eq!(err, BZ_SEQUENCE_ERROR);
+                BZ2_bzReadClose(&mut err, b);
+                fclose(f);
+            }
+
+            assert!(BZ2_bzReadOpen(&mut err, ptr::null_mut(), 0, 0, ptr::null_mut(), 0).is_null());
+            assert_eq!(err, BZ_PARAM_ERROR);
+            let f = tmpfile();
+            assert!(BZ2_bzReadOpen(&mut err, f, 0, 0, buf.as_mut_ptr() as *mut c_void, BZ_MAX_UNUSED + 1).is_null());
+            assert!(BZ2_bzWriteOpen(&mut err, f, 0, 0, 0).is_null());
+            assert_eq!(err, BZ_PARAM_ERROR);
+            fclose(f);
+        }
+    }
+
+    #[test]
+    fn opens_paths() {
+        let data = sample(50_000);
+        let path = ::std::env::temp_dir().join(format!("bzip2-rs-capi-{}.bz2", ::std::process::id()));
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let b = BZ2_bzopen(cpath.as_ptr(), b"w1\0".as_ptr() as *const c_char);
+            assert!(!b.is_null());
+            assert_eq!(BZ2_bzwrite(b, data.as_ptr() as *mut c_void, data.len() as c_int), data.len() as c_int);
+            assert_eq!(BZ2_bzflush(b), 0);
+            BZ2_bzclose(b);
+
+            let mut out = vec![];
+            Decoder::new(&fs::read(&path).unwrap()[..]).read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+
+            let b = BZ2_bzopen(cpath.as_ptr(), b"r\0".as_ptr() as *const c_char);
+            let mut buf = vec![0u8; data.len() + 1];
+            assert_eq!(BZ2_bzread(b, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int), data.len() as c_int);
+            assert_eq!(&buf[..data.len()], &data[..]);
+            assert_eq!(BZ2_bzread(b, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int), 0);
+            let mut errnum = 1;
+            assert_eq!(CStr::from_ptr(BZ2_bzerror(b, &mut errnum)).to_str(), Ok("OK"));
+            assert_eq!(errnum, BZ_OK);
+            BZ2_bzclose(b);
+        }
+        fs::remove_file(&path).unwrap();
+        let missing = CString::new("/nonexistent/file.bz2").unwrap();
+        assert!(unsafe { BZ2_bzopen(missing.as_ptr(), b"r\0".as_ptr() as *const c_char) }.is_null());
+    }
+}
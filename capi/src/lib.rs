@@ -0,0 +1,595 @@
+//! libbz2's C interface, implemented with bzip2-rs.
+//!
+//! This crate builds `libbz2.so` and `libbz2.a` with the same symbols, types
+//! and return codes as the C library, so programs linked against it can use
+//! this implementation without being recompiled. `include/bzlib.h` is
+//! generated from the source with
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml --output include/bzlib.h
+//! ```
+//!
+//! run in this directory, and the tests fail when it is out of date.
+//!
+//! Where this implementation differs from the C library:
+//!
+//! - `bzalloc`, `bzfree` and `opaque` are ignored: all memory comes from the
+//!   Rust allocator.
+//! - `verbosity`, `workFactor` and `small` are checked, then ignored.
+//! - A `BZ_FLUSH` ends the block on a byte boundary, so everything passed in
+//!   before it can be decoded from the output straight away.
+
+#![allow(non_camel_case_types, non_snake_case)]
+// the contract of every function is libbz2's, as its manual describes
+#![allow(clippy::missing_safety_doc)]
+
+extern crate bzip2_rs;
+
+mod bzfile;
+
+pub use bzfile::*;
+
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::{ptr, slice};
+
+use bzip2_rs::{Action, Compress, Decompress, Error, Status};
+
+pub const BZ_RUN: c_int = 0;
+pub const BZ_FLUSH: c_int = 1;
+pub const BZ_FINISH: c_int = 2;
+
+pub const BZ_OK: c_int = 0;
+pub const BZ_RUN_OK: c_int = 1;
+pub const BZ_FLUSH_OK: c_int = 2;
+pub const BZ_FINISH_OK: c_int = 3;
+pub const BZ_STREAM_END: c_int = 4;
+pub const BZ_SEQUENCE_ERROR: c_int = -1;
+pub const BZ_PARAM_ERROR: c_int = -2;
+pub const BZ_MEM_ERROR: c_int = -3;
+pub const BZ_DATA_ERROR: c_int = -4;
+pub const BZ_DATA_ERROR_MAGIC: c_int = -5;
+pub const BZ_IO_ERROR: c_int = -6;
+pub const BZ_UNEXPECTED_EOF: c_int = -7;
+pub const BZ_OUTBUFF_FULL: c_int = -8;
+pub const BZ_CONFIG_ERROR: c_int = -9;
+
+/// The state of a stream being compressed or decompressed, laid out as
+/// libbz2 lays it out.
+#[repr(C)]
+pub struct bz_stream {
+    pub next_in: *mut c_char,
+    pub avail_in: c_uint,
+    pub total_in_lo32: c_uint,
+    pub total_in_hi32: c_uint,
+
+    pub next_out: *mut c_char,
+    pub avail_out: c_uint,
+    pub total_out_lo32: c_uint,
+    pub total_out_hi32: c_uint,
+
+    pub state: *mut c_void,
+
+    pub bzalloc: Option<unsafe extern "C" fn(*mut c_void, c_int, c_int) -> *mut c_void>,
+    pub bzfree: Option<unsafe extern "C" fn(*mut c_void, *mut c_void)>,
+    pub opaque: *mut c_void,
+}
+
+// What a finish or flush has got to, as libbz2 tracks it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Running,
+    Flushing,
+    Finishing,
+    Idle,
+}
+
+struct CompressState {
+    // the stream this belongs to, to catch it being used with another
+    strm: *const bz_stream,
+    compress: Compress,
+    mode: Mode,
+    // the input a flush or finish was asked for with, still to be consumed
+    avail_in_expect: c_uint,
+}
+
+struct DecompressState {
+    strm: *const bz_stream,
+    decompress: Decompress,
+    done: bool,
+}
+
+// The input and output `strm` points at. Null pointers are taken as empty.
+unsafe fn buffers<'a>(strm: &bz_stream) -> (&'a [u8], &'a mut [u8]) {
+    let input = if strm.next_in.is_null() || strm.avail_in == 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(strm.next_in as *const u8, strm.avail_in as usize)
+    };
+    let output = if strm.next_out.is_null() || strm.avail_out == 0 {
+        &mut [][..]
+    } else {
+        slice::from_raw_parts_mut(strm.next_out as *mut u8, strm.avail_out as usize)
+    };
+    (input, output)
+}
+
+// Moves `strm` past `consumed` bytes of input and `produced` of output.
+unsafe fn advance(strm: &mut bz_stream, consumed: usize, produced: usize) {
+    if consumed > 0 {
+        strm.next_in = strm.next_in.add(consumed);
+        strm.avail_in -= consumed as c_uint;
+    }
+    if produced > 0 {
+        strm.next_out = strm.next_out.add(produced);
+        strm.avail_out -= produced as c_uint;
+    }
+    let total_in = (strm.total_in_hi32 as u64) << 32 | strm.total_in_lo32 as u64;
+    let total_in = total_in.wrapping_add(consumed as u64);
+    strm.total_in_lo32 = total_in as c_uint;
+    strm.total_in_hi32 = (total_in >> 32) as c_uint;
+    let total_out = (strm.total_out_hi32 as u64) << 32 | strm.total_out_lo32 as u64;
+    let total_out = total_out.wrapping_add(produced as u64);
+    strm.total_out_lo32 = total_out as c_uint;
+    strm.total_out_hi32 = (total_out >> 32) as c_uint;
+}
+
+unsafe fn compress_state<'a>(strm: *mut bz_stream) -> Option<(&'a mut bz_stream, &'a mut CompressState)> {
+    let strm = strm.as_mut()?;
+    let state = (strm.state as *mut CompressState).as_mut()?;
+    if !ptr::eq(state.strm, strm) {
+        return None;
+    }
+    Some((strm, state))
+}
+
+unsafe fn decompress_state<'a>(strm: *mut bz_stream) -> Option<(&'a mut bz_stream, &'a mut DecompressState)> {
+    let strm = strm.as_mut()?;
+    let state = (strm.state as *mut DecompressState).as_mut()?;
+    if !ptr::eq(state.strm, strm) {
+        return None;
+    }
+    Some((strm, state))
+}
+
+fn valid_params(block_size_100k: c_int, verbosity: c_int, work_factor: c_int) -> bool {
+    (1..=9).contains(&block_size_100k) && (0..=4).contains(&verbosity) && (0..=250).contains(&work_factor)
+}
+
+// The return code for a decoding error.
+fn error_code(err: &Error) -> c_int {
+    match *err {
+        Error::BadMagic { bit_offset: 0, .. } | Error::UnsupportedBlockSize { .. } => BZ_DATA_ERROR_MAGIC,
+        _ => BZ_DATA_ERROR,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzCompressInit(strm: *mut bz_stream, blockSize100k: c_int, verbosity: c_int,
+                                            workFactor: c_int) -> c_int {
+    let strm = match strm.as_mut() {
+        Some(strm) => strm,
+        None => return BZ_PARAM_ERROR,
+    };
+    if !valid_params(blockSize100k, verbosity, workFactor) {
+        return BZ_PARAM_ERROR;
+    }
+    let state = Box::new(CompressState {
+        strm,
+        compress: Compress::new(blockSize100k as u32),
+        mode: Mode::Running,
+        avail_in_expect: 0,
+    });
+    strm.state = Box::into_raw(state) as *mut c_void;
+    strm.total_in_lo32 = 0;
+    strm.total_in_hi32 = 0;
+    strm.total_out_lo32 = 0;
+    strm.total_out_hi32 = 0;
+    BZ_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzCompress(strm: *mut bz_stream, action: c_int) -> c_int {
+    let (strm, state) = match compress_state(strm) {
+        Some(found) => found,
+        None => return BZ_PARAM_ERROR,
+    };
+
+    if state.mode == Mode::Running {
+        match action {
+            BZ_RUN => {}
+            BZ_FLUSH => {
+                state.avail_in_expect = strm.avail_in;
+                state.mode = Mode::Flushing;
+            }
+            BZ_FINISH => {
+                state.avail_in_expect = strm.avail_in;
+                state.mode = Mode::Finishing;
+            }
+            _ => return BZ_PARAM_ERROR,
+        }
+    }
+    let action = match state.mode {
+        Mode::Running => Action::Run,
+        Mode::Flushing if action == BZ_FLUSH => Action::Flush,
+        Mode::Finishing if action == BZ_FINISH => Action::Finish,
+        _ => return BZ_SEQUENCE_ERROR,
+    };
+    if action != Action::Run && state.avail_in_expect != strm.avail_in {
+        return BZ_SEQUENCE_ERROR;
+    }
+
+    let (input, output) = buffers(strm);
+    let (consumed, produced, status) = state.compress.compress(input, output, action);
+    advance(strm, consumed, produced);
+    state.avail_in_expect = strm.avail_in;
+    let progress = consumed > 0 || produced > 0;
+
+    match action {
+        Action::Run if progress => BZ_RUN_OK,
+        Action::Run => BZ_PARAM_ERROR,
+        Action::Flush if status == Status::Flushed => {
+            state.mode = Mode::Running;
+            BZ_RUN_OK
+        }
+        Action::Flush => BZ_FLUSH_OK,
+        Action::Finish if status == Status::StreamEnd => {
+            state.mode = Mode::Idle;
+            BZ_STREAM_END
+        }
+        Action::Finish if progress => BZ_FINISH_OK,
+        Action::Finish => BZ_SEQUENCE_ERROR,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzCompressEnd(strm: *mut bz_stream) -> c_int {
+    let (strm, state) = match compress_state(strm) {
+        Some(found) => found,
+        None => return BZ_PARAM_ERROR,
+    };
+    drop(Box::from_raw(state as *mut CompressState));
+    strm.state = ptr::null_mut();
+    BZ_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzDecompressInit(strm: *mut bz_stream, verbosity: c_int, small: c_int) -> c_int {
+    let strm = match strm.as_mut() {
+        Some(strm) => strm,
+        None => return BZ_PARAM_ERROR,
+    };
+    if !(0..=1).contains(&small) || !(0..=4).contains(&verbosity) {
+        return BZ_PARAM_ERROR;
+    }
+    let mut decompress = Decompress::new();
+    // like libbz2, stop at the end of the first stream
+    decompress.set_multi_stream(false);
+    let state = Box::new(DecompressState {
+        strm,
+        decompress,
+        done: false,
+    });
+    strm.state = Box::into_raw(state) as *mut c_void;
+    strm.total_in_lo32 = 0;
+    strm.total_in_hi32 = 0;
+    strm.total_out_lo32 = 0;
+    strm.total_out_hi32 = 0;
+    BZ_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzDecompress(strm: *mut bz_stream) -> c_int {
+    let (strm, state) = match decompress_state(strm) {
+        Some(found) => found,
+        None => return BZ_PARAM_ERROR,
+    };
+    if state.done {
+        return BZ_SEQUENCE_ERROR;
+    }
+
+    let (input, output) = buffers(strm);
+    match state.decompress.decompress(input, output) {
+        Ok((consumed, produced, status)) => {
+            advance(strm, consumed, produced);
+            if status == Status::StreamEnd {
+                state.done = true;
+                return BZ_STREAM_END;
+            }
+            BZ_OK
+        }
+        Err(err) => error_code(&err),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzDecompressEnd(strm: *mut bz_stream) -> c_int {
+    let (strm, state) = match decompress_state(strm) {
+        Some(found) => found,
+        None => return BZ_PARAM_ERROR,
+    };
+    drop(Box::from_raw(state as *mut DecompressState));
+    strm.state = ptr::null_mut();
+    BZ_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzBuffToBuffCompress(dest: *mut c_char, destLen: *mut c_uint, source: *mut c_char,
+                                                  sourceLen: c_uint, blockSize100k: c_int, verbosity: c_int,
+                                                  workFactor: c_int) -> c_int {
+    if dest.is_null() || destLen.is_null() || source.is_null() ||
+       !valid_params(blockSize100k, verbosity, workFactor) {
+        return BZ_PARAM_ERROR;
+    }
+    let input = slice::from_raw_parts(source as *const u8, sourceLen as usize);
+    let output = slice::from_raw_parts_mut(dest as *mut u8, *destLen as usize);
+
+    let mut compress = Compress::new(blockSize100k as u32);
+    let (_, produced, status) = compress.compress(input, output, Action::Finish);
+    if status != Status::StreamEnd {
+        return BZ_OUTBUFF_FULL;
+    }
+    *destLen = produced as c_uint;
+    BZ_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzBuffToBuffDecompress(dest: *mut c_char, destLen: *mut c_uint, source: *mut c_char,
+                                                    sourceLen: c_uint, small: c_int, verbosity: c_int) -> c_int {
+    if dest.is_null() || destLen.is_null() || source.is_null() ||
+       !(0..=1).contains(&small) || !(0..=4).contains(&verbosity) {
+        return BZ_PARAM_ERROR;
+    }
+    let mut input = slice::from_raw_parts(source as *const u8, sourceLen as usize);
+    let output = slice::from_raw_parts_mut(dest as *mut u8, *destLen as usize);
+
+    let mut decompress = Decompress::new();
+    decompress.set_multi_stream(false);
+    let mut out_pos = 0;
+    loop {
+        match decompress.decompress(input, &mut output[out_pos..]) {
+            Ok((consumed, produced, status)) => {
+                input = &input[consumed..];
+                out_pos += produced;
+                if status == Status::StreamEnd {
+                    *destLen = out_pos as c_uint;
+                    return BZ_OK;
+                }
+                if consumed == 0 && produced == 0 {
+                    break;
+                }
+            }
+            Err(err) => return error_code(&err),
+        }
+    }
+    if out_pos < output.len() { BZ_UNEXPECTED_EOF } else { BZ_OUTBUFF_FULL }
+}
+
+#[no_mangle]
+pub extern "C" fn BZ2_bzlibVersion() -> *const c_char {
+    concat!("1.0.8, bzip2-rs ", env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::mem;
+
+    use bzip2_rs::{BzReader, BzWriter};
+
+    use super::*;
+
+    fn stream() -> bz_stream {
+        unsafe { mem::zeroed() }
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 1234u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 3 {
+                0 => (x >> 8) as u8,
+                _ => (i / 500) as u8,
+            }
+        }).collect()
+    }
+
+    fn decode(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        BzReader::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    // Compresses `data` through a `bz_stream`, `chunk` bytes of input and
+    // output at a time, with a flush after the first half.
+    unsafe fn compress(data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut strm = stream();
+        assert_eq!(BZ2_bzCompressInit(&mut strm, 1, 0, 30), BZ_OK);
+        let mut out = vec![0u8; data.len() * 2 + 1000];
+        let (mut in_pos, mut out_pos) = (0, 0);
+        let half = data.len() / 2;
+
+        let mut step = |strm: &mut bz_stream, in_pos: &mut usize, out_pos: &mut usize, end: usize, action| {
+            // a flush or finish is repeated with all that is left
+            let avail_in = if action == BZ_RUN { (end - *in_pos).min(chunk) } else { end - *in_pos };
+            strm.next_in = data[*in_pos..].as_ptr() as *mut c_char;
+            strm.avail_in = avail_in as c_uint;
+            strm.next_out = out[*out_pos..].as_mut_ptr() as *mut c_char;
+            strm.avail_out = chunk as c_uint;
+            let ret = BZ2_bzCompress(strm, action);
+            *in_pos += avail_in - strm.avail_in as usize;
+            *out_pos += chunk - strm.avail_out as usize;
+            ret
+        };
+
+        while in_pos < half {
+            assert_eq!(step(&mut strm, &mut in_pos, &mut out_pos, half, BZ_RUN), BZ_RUN_OK);
+        }
+        loop {
+            match step(&mut strm, &mut in_pos, &mut out_pos, half, BZ_FLUSH) {
+                BZ_FLUSH_OK => {}
+                BZ_RUN_OK => break,
+                ret => panic!("flush returned {}", ret),
+            }
+        }
+        let flushed = out_pos;
+
+        loop {
+            match step(&mut strm, &mut in_pos, &mut out_pos, data.len(), BZ_FINISH) {
+                BZ_FINISH_OK => {}
+                BZ_STREAM_END => break,
+                ret => panic!("finish returned {}", ret),
+            }
+        }
+        assert_eq!(in_pos, data.len());
+
+        // everything before the flush could be decoded from what was output
+        let mut out_before = vec![];
+        let _ = BzReader::new(&out[..flushed]).read_to_end(&mut out_before);
+        assert_eq!(out_before, &data[..half]);
+
+        assert_eq!(BZ2_bzCompress(&mut strm, BZ_FINISH), BZ_SEQUENCE_ERROR);
+        assert_eq!(strm.total_in_lo32 as usize, data.len());
+        assert_eq!(strm.total_out_lo32 as usize, out_pos);
+        assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        assert!(strm.state.is_null());
+        out.truncate(out_pos);
+        out
+    }
+
+    // Decompresses `data` through a `bz_stream`, `chunk` bytes at a time,
+    // returning the output, the return code that ended it and the input
+    // left over.
+    unsafe fn decompress(data: &[u8], chunk: usize) -> (Vec<u8>, c_int, usize) {
+        let mut strm = stream();
+        assert_eq!(BZ2_bzDecompressInit(&mut strm, 0, 0), BZ_OK);
+        let mut out = vec![];
+        let mut buf = vec![0u8; chunk];
+        let mut in_pos = 0;
+        let ret = loop {
+            let avail_in = (data.len() - in_pos).min(chunk);
+            strm.next_in = data[in_pos..].as_ptr() as *mut c_char;
+            strm.avail_in = avail_in as c_uint;
+            strm.next_out = buf.as_mut_ptr() as *mut c_char;
+            strm.avail_out = chunk as c_uint;
+            let ret = BZ2_bzDecompress(&mut strm);
+            in_pos += avail_in - strm.avail_in as usize;
+            let produced = chunk - strm.avail_out as usize;
+            out.extend_from_slice(&buf[..produced]);
+            if ret != BZ_OK || (avail_in == 0 && produced == 0) {
+                break ret;
+            }
+        };
+        if ret == BZ_STREAM_END {
+            assert_eq!(BZ2_bzDecompress(&mut strm), BZ_SEQUENCE_ERROR);
+        }
+        assert_eq!(BZ2_bzDecompressEnd(&mut strm), BZ_OK);
+        (out, ret, data.len() - in_pos)
+    }
+
+    #[test]
+    fn streams_round_trip() {
+        let data = sample(300_000);
+        for &chunk in &[1, 4096] {
+            let input = if chunk == 1 { &data[..3000] } else { &data[..] };
+            let compressed = unsafe { compress(input, chunk) };
+            assert_eq!(decode(&compressed), input);
+
+            let mut concatenated = compressed.clone();
+            concatenated.extend_from_slice(b"next");
+            let (out, ret, left) = unsafe { decompress(&concatenated, chunk) };
+            assert_eq!(ret, BZ_STREAM_END);
+            assert_eq!(out, input);
+            // like libbz2, the first stream is all that is decoded
+            assert_eq!(left, 4);
+        }
+    }
+
+    #[test]
+    fn reports_errors_as_libbz2_does() {
+        let mut writer = BzWriter::new(vec![], 9);
+        writer.write_all(&sample(10_000)).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        unsafe {
+            assert_eq!(decompress(b"BZh0", 16).1, BZ_DATA_ERROR_MAGIC);
+            assert_eq!(decompress(b"PK\x03\x04", 16).1, BZ_DATA_ERROR_MAGIC);
+            let mut damaged = compressed.clone();
+            damaged[100] ^= 0x10;
+            assert_eq!(decompress(&damaged, 4096).1, BZ_DATA_ERROR);
+            // running out of input is not an error to a stream
+            assert_eq!(decompress(&compressed[..100], 4096).1, BZ_OK);
+
+            let mut strm = stream();
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 10, 0, 0), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompressInit(ptr::null_mut(), 9, 0, 0), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzDecompressInit(&mut strm, 0, 2), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_RUN), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzDecompress(&mut strm), BZ_PARAM_ERROR);
+
+            // a finish must be repeated with the same input
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 9, 0, 0), BZ_OK);
+            let mut out = [0u8; 16];
+            let input = b"some input";
+            strm.next_in = input.as_ptr() as *mut c_char;
+            strm.avail_in = input.len() as c_uint;
+            strm.next_out = out.as_mut_ptr() as *mut c_char;
+            strm.avail_out = 4;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FINISH), BZ_FINISH_OK);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_RUN), BZ_SEQUENCE_ERROR);
+            strm.avail_in += 1;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FINISH), BZ_SEQUENCE_ERROR);
+            // a moved stream is not recognised
+            let mut moved = stream();
+            moved.state = strm.state;
+            assert_eq!(BZ2_bzCompressEnd(&mut moved), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        }
+    }
+
+    #[test]
+    fn buffers_round_trip() {
+        let data = sample(100_000);
+        let mut source = data.clone();
+        let mut compressed = vec![0u8; data.len() + 1000];
+        let mut len = compressed.len() as c_uint;
+        unsafe {
+            let ret = BZ2_bzBuffToBuffCompress(compressed.as_mut_ptr() as *mut c_char, &mut len,
+                                               source.as_mut_ptr() as *mut c_char, source.len() as c_uint, 9, 0, 0);
+            assert_eq!(ret, BZ_OK);
+            compressed.truncate(len as usize);
+            assert_eq!(decode(&compressed), data);
+
+            let mut small = 10;
+            let ret = BZ2_bzBuffToBuffCompress(compressed.as_mut_ptr() as *mut c_char, &mut small,
+                                               source.as_mut_ptr() as *mut c_char, source.len() as c_uint, 9, 0, 0);
+            assert_eq!(ret, BZ_OUTBUFF_FULL);
+
+            let mut out = vec![0u8; data.len()];
+            let mut len = out.len() as c_uint;
+            let ret = BZ2_bzBuffToBuffDecompress(out.as_mut_ptr() as *mut c_char, &mut len,
+                                                 compressed.as_mut_ptr() as *mut c_char, compressed.len() as c_uint,
+                                                 0, 0);
+            assert_eq!(ret, BZ_OK);
+            assert_eq!(len as usize, data.len());
+            assert_eq!(out, data);
+
+            let mut len = out.len() as c_uint - 1;
+            let ret = BZ2_bzBuffToBuffDecompress(out.as_mut_ptr() as *mut c_char, &mut len,
+                                                 compressed.as_mut_ptr() as *mut c_char, compressed.len() as c_uint,
+                                                 0, 0);
+            assert_eq!(ret, BZ_OUTBUFF_FULL);
+            let mut len = out.len() as c_uint;
+            let ret = BZ2_bzBuffToBuffDecompress(out.as_mut_ptr() as *mut c_char, &mut len,
+                                                 compressed.as_mut_ptr() as *mut c_char, 100, 0, 0);
+            assert_eq!(ret, BZ_UNEXPECTED_EOF);
+        }
+    }
+
+    #[test]
+    fn header_is_current() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/bzlib.h"));
+        assert!(generated == include_str!("../include/bzlib.h"),
+                "include/bzlib.h is out of date: regenerate it with \
+                 `cbindgen --config cbindgen.toml --output include/bzlib.h`");
+    }
+}
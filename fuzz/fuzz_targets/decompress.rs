@@ -0,0 +1,36 @@
+//! Decodes arbitrary input, which must never panic, once from a single
+//! buffer and once a byte at a time, which must agree.
+//!
+//!     cargo +nightly fuzz run decompress
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bzip2_rs;
+
+use std::io::{BufReader, Read};
+
+use bzip2_rs::{bufread, scan_blocks, Limits};
+
+fuzz_target!(|data: &[u8]| {
+    // small inputs can still expand enormously
+    let limits = Limits {
+        max_output: Some(64 << 20),
+        ..Limits::default()
+    };
+
+    let mut whole = Vec::new();
+    let whole_result = bufread::BzReader::new(data).limits(limits).read_to_end(&mut whole);
+
+    let mut bytewise = Vec::new();
+    let input = BufReader::with_capacity(1, data);
+    let bytewise_result = bufread::BzReader::new(input).limits(limits).read_to_end(&mut bytewise);
+
+    match (whole_result, bytewise_result) {
+        (Ok(_), Ok(_)) => assert_eq!(whole, bytewise),
+        (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string()),
+        (a, b) => panic!("decoding all at once gave {:?}, a byte at a time {:?}", a, b),
+    }
+
+    for _ in scan_blocks(data) {}
+});
@@ -0,0 +1,39 @@
+//! Compresses arbitrary input, with the block size level taken from the
+//! first byte and a flushed block boundary from the second, and checks that
+//! it decompresses to the same data.
+//!
+//!     cargo +nightly fuzz run round_trip
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bzip2_rs;
+
+use std::io::{Read, Write};
+
+use bzip2_rs::{BzReader, BzWriter, ParBzWriter};
+
+fuzz_target!(|input: &[u8]| {
+    if input.len() < 2 {
+        return;
+    }
+    let level = input[0] as u32 % 9 + 1;
+    let data = &input[2..];
+    let split = (input[1] as usize).min(data.len());
+
+    let mut writer = BzWriter::new(Vec::new(), level);
+    writer.write_all(&data[..split]).unwrap();
+    writer.flush_block().unwrap();
+    writer.write_all(&data[split..]).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    let mut out = Vec::new();
+    BzReader::new(&compressed[..]).read_to_end(&mut out).unwrap();
+    assert_eq!(out, data);
+
+    let mut serial = BzWriter::new(Vec::new(), level);
+    serial.write_all(data).unwrap();
+    let mut parallel = ParBzWriter::new(Vec::new(), level, 2);
+    parallel.write_all(data).unwrap();
+    assert_eq!(parallel.finish().unwrap(), serial.finish().unwrap());
+});
@@ -0,0 +1,555 @@
+//! `AsyncRead` and `AsyncWrite` adapters, for tokio with the `tokio` feature
+//! and for futures-io with the `futures-io` feature.
+//!
+//! `BzReader` and `BzWriter` work like their blocking counterparts in `read`
+//! and `write`, and implement the traits of whichever of the two their inner
+//! stream implements. They are driven by `Decompress` and `Compress`, which
+//! keep their place anywhere within a block, so an inner stream that returns
+//! `Pending` only ever means the same call is made again once it is woken.
+//!
+//! The inner stream must be `Unpin`; one that is not can be pinned in a
+//! `Box` first.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+#[cfg(feature = "futures-io")]
+use futures_io;
+#[cfg(feature = "tokio")]
+use tokio::io as tokio_io;
+
+use compress::{Action, Compress};
+use decompress::{Decompress, Limits, Status};
+use index::Index;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Decompresses bzip2 data read from `R`, an `AsyncRead`.
+///
+/// Concatenated streams are decoded one after another unless
+/// `multi_stream(false)` is set.
+pub struct BzReader<R> {
+    inner: R,
+    decompress: Decompress,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl<R> BzReader<R> {
+    pub fn new(inner: R) -> Self {
+        BzReader {
+            inner,
+            decompress: Decompress::new(),
+            buf: vec![0; BUF_SIZE],
+            pos: 0,
+            end: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Sets whether to go on decoding streams that follow the first one.
+    /// When this is off, reading stops at the end of the first stream and
+    /// `unused` holds the input that was read past it.
+    pub fn multi_stream(mut self, multi_stream: bool) -> Self {
+        self.decompress.set_multi_stream(multi_stream);
+        self
+    }
+
+    /// Sets the limits past which reading fails rather than produce more
+    /// output.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.decompress.set_limits(limits);
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the inner reader. Any input that was read from it but not
+    /// decoded, as returned by `unused`, is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Input that was read from the inner reader but not consumed by the
+    /// decoder.
+    pub fn unused(&self) -> &[u8] {
+        &self.buf[self.pos..self.end]
+    }
+
+    /// The number of compressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.decompress.total_in()
+    }
+
+    /// The number of decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.decompress.total_out()
+    }
+
+    // Decodes into `out` from the buffered input, refilling the buffer with
+    // `fill` once it has all been consumed. Output that can be produced from
+    // input already read never waits on the inner reader.
+    fn poll_decode<F>(&mut self, out: &mut [u8], mut fill: F) -> Poll<io::Result<usize>>
+        where F: FnMut(&mut R, &mut [u8]) -> Poll<io::Result<usize>>
+    {
+        if self.done || out.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            let (consumed, produced, status) = self.decompress.decompress(&self.buf[self.pos..self.end], out)?;
+            self.pos += consumed;
+            let drained = self.pos == self.end;
+
+            if status == Status::StreamEnd && ((self.eof && drained) || !self.decompress.multi_stream()) {
+                self.done = true;
+                return Poll::Ready(Ok(produced));
+            }
+            if produced > 0 {
+                return Poll::Ready(Ok(produced));
+            }
+            if drained {
+                if self.eof {
+                    return Poll::Ready(Err(self.decompress.eof_error().into()));
+                }
+                let n = ready!(fill(&mut self.inner, &mut self.buf))?;
+                self.pos = 0;
+                self.end = n;
+                self.eof = n == 0;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: tokio_io::AsyncRead + Unpin> tokio_io::AsyncRead for BzReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, out: &mut tokio_io::ReadBuf) -> Poll<io::Result<()>> {
+        let n = ready!(self.get_mut().poll_decode(out.initialize_unfilled(), |inner, buf| {
+            let mut buf = tokio_io::ReadBuf::new(buf);
+            ready!(Pin::new(inner).poll_read(cx, &mut buf))?;
+            Poll::Ready(Ok(buf.filled().len()))
+        }))?;
+        out.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<R: futures_io::AsyncRead + Unpin> futures_io::AsyncRead for BzReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, out: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_decode(out, |inner, buf| Pin::new(inner).poll_read(cx, buf))
+    }
+}
+
+/// Compresses everything written to it into a bzip2 stream written to `W`,
+/// an `AsyncWrite`.
+///
+/// The stream is ended by shutting the writer down, with `poll_shutdown` or
+/// `poll_close`. Unlike `write::BzWriter`, dropping the writer cannot end
+/// it, as there would be no way to wait for the inner writer.
+pub struct BzWriter<W> {
+    inner: W,
+    compress: Compress,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<W> BzWriter<W> {
+    /// Creates a writer using blocks of `level` * 100k bytes, for `level` in
+    /// `1..=9`.
+    pub fn new(inner: W, level: u32) -> Self {
+        BzWriter {
+            inner,
+            compress: Compress::new(level),
+            buf: Vec::with_capacity(BUF_SIZE),
+            done: false,
+        }
+    }
+
+    /// Records the position of every block in `index`, which is off by
+    /// default. It can only be changed before anything is written.
+    pub fn record_index(mut self, record_index: bool) -> Self {
+        self.compress.set_record_index(record_index);
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the inner writer, which has only been sent the whole stream
+    /// if the writer was shut down.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// The number of uncompressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.compress.total_in()
+    }
+
+    /// The number of compressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.compress.total_out()
+    }
+
+    /// The positions of the blocks compressed so far, complete once the
+    /// stream is finished, if `record_index` was set.
+    pub fn index(&self) -> &Index {
+        self.compress.index()
+    }
+
+    fn poll_encode<F>(&mut self, data: &[u8], mut write: F) -> Poll<io::Result<usize>>
+        where F: FnMut(&mut W, &[u8]) -> Poll<io::Result<usize>>
+    {
+        if self.done {
+            return Poll::Ready(Err(io::Error::other("write after the stream was finished")));
+        }
+        loop {
+            ready!(self.poll_dump(&mut write))?;
+            let (consumed, _) = self.compress_into_buf(data, Action::Run);
+            if consumed > 0 || data.is_empty() {
+                return Poll::Ready(Ok(consumed));
+            }
+        }
+    }
+
+    // Ends the stream and writes out everything still buffered.
+    fn poll_finish<F>(&mut self, mut write: F) -> Poll<io::Result<()>>
+        where F: FnMut(&mut W, &[u8]) -> Poll<io::Result<usize>>
+    {
+        loop {
+            ready!(self.poll_dump(&mut write))?;
+            if self.done {
+                return Poll::Ready(Ok(()));
+            }
+            self.done = self.compress_into_buf(&[], Action::Finish).1 == Status::StreamEnd;
+        }
+    }
+
+    fn compress_into_buf(&mut self, data: &[u8], action: Action) -> (usize, Status) {
+        debug_assert!(self.buf.is_empty());
+        self.buf.resize(BUF_SIZE, 0);
+        let (consumed, produced, status) = self.compress.compress(data, &mut self.buf, action);
+        self.buf.truncate(produced);
+        (consumed, status)
+    }
+
+    fn poll_dump<F>(&mut self, write: &mut F) -> Poll<io::Result<()>>
+        where F: FnMut(&mut W, &[u8]) -> Poll<io::Result<usize>>
+    {
+        while !self.buf.is_empty() {
+            let n = ready!(write(&mut self.inner, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<W: tokio_io::AsyncWrite + Unpin> tokio_io::AsyncWrite for BzWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_encode(data, |inner, buf| Pin::new(inner).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_dump(&mut |inner: &mut W, buf: &[u8]| Pin::new(inner).poll_write(cx, buf)))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_finish(|inner, buf| Pin::new(inner).poll_write(cx, buf)))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<W: futures_io::AsyncWrite + Unpin> futures_io::AsyncWrite for BzWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_encode(data, |inner, buf| Pin::new(inner).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_dump(&mut |inner: &mut W, buf: &[u8]| Pin::new(inner).poll_write(cx, buf)))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_finish(|inner, buf| Pin::new(inner).poll_write(cx, buf)))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::task::Waker;
+
+    use bzip2::Compression;
+    use bzip2::read::BzDecoder;
+    use bzip2::write::BzEncoder;
+    #[cfg(feature = "tokio")]
+    use tokio::io::{duplex, AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::*;
+    #[cfg(feature = "tokio")]
+    use Error;
+
+    fn reference(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::Fastest);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn reference_decode(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        BzDecoder::new(compressed).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 24680u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 4 {
+                0 => (x >> 8) as u8,
+                1 => b'a' + (x >> 24) as u8 % 8,
+                _ => (i / 300) as u8,
+            }
+        }).collect()
+    }
+
+    // Writes `data` to `writer` and shuts it down, while reading `reader`
+    // to the end, polling each in turn as a task woken by the other would
+    // be. Returns what was read, and how often each of them was pending.
+    #[cfg(feature = "tokio")]
+    fn pump<W, R>(data: &[u8], writer: &mut W, reader: &mut R) -> io::Result<(Vec<u8>, usize, usize)>
+        where W: AsyncWrite + Unpin, R: AsyncRead + Unpin
+    {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut input = data;
+        let mut shut = false;
+        let mut out = vec![];
+        let mut buf = [0; 1000];
+        let (mut write_pending, mut read_pending) = (0, 0);
+        loop {
+            if !input.is_empty() {
+                match Pin::new(&mut *writer).poll_write(&mut cx, &input[..input.len().min(5000)]) {
+                    Poll::Ready(n) => input = &input[n?..],
+                    Poll::Pending => write_pending += 1,
+                }
+            } else if !shut {
+                match Pin::new(&mut *writer).poll_shutdown(&mut cx) {
+                    Poll::Ready(res) => {
+                        res?;
+                        shut = true;
+                    }
+                    Poll::Pending => write_pending += 1,
+                }
+            }
+
+            let mut buf = ReadBuf::new(&mut buf);
+            match Pin::new(&mut *reader).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(res) => {
+                    res?;
+                    if buf.filled().is_empty() {
+                        return Ok((out, write_pending, read_pending));
+                    }
+                    out.extend_from_slice(buf.filled());
+                }
+                Poll::Pending => read_pending += 1,
+            }
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn round_trips_through_a_duplex() {
+        let data = sample(250_000);
+        let (client, server) = duplex(100);
+        let mut writer = BzWriter::new(client, 1).record_index(true);
+        let mut reader = BzReader::new(server);
+        let (out, write_pending, read_pending) = pump(&data, &mut writer, &mut reader).unwrap();
+        assert_eq!(out, data);
+        assert!(write_pending > 0 && read_pending > 0);
+        assert_eq!(writer.index().entries().len(), 3);
+        assert_eq!(reader.total_in(), writer.total_out());
+        assert_eq!(reader.total_out(), data.len() as u64);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn encodes_for_the_reference_decoder() {
+        let data = sample(150_000);
+        let (client, mut server) = duplex(64);
+        let mut writer = BzWriter::new(client, 1);
+        let (compressed, write_pending, _) = pump(&data, &mut writer, &mut server).unwrap();
+        assert!(write_pending > 0);
+        assert_eq!(reference_decode(&compressed), data);
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(match Pin::new(&mut writer).poll_write(&mut cx, b"more") {
+            Poll::Ready(res) => res.is_err(),
+            Poll::Pending => false,
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn decodes_reference_streams_a_few_bytes_at_a_time() {
+        let first = sample(120_000);
+        let mut compressed = reference(&first);
+        compressed.extend(reference(b"and a second stream"));
+
+        let (mut client, server) = duplex(7);
+        let mut reader = BzReader::new(server);
+        let (out, _, read_pending) = pump(&compressed, &mut client, &mut reader).unwrap();
+        assert!(read_pending > 0);
+        assert_eq!(&out[..first.len()], &first[..]);
+        assert_eq!(&out[first.len()..], b"and a second stream");
+
+        // or just the first
+        let (mut client, server) = duplex(7);
+        let mut reader = BzReader::new(server).multi_stream(false);
+        let (out, _, _) = pump(&compressed, &mut client, &mut reader).unwrap();
+        assert_eq!(out, first);
+        assert!(reader.unused().len() < 7);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn truncated_input_is_an_error() {
+        let compressed = reference(&sample(50_000));
+        let (mut client, server) = duplex(1000);
+        let mut reader = BzReader::new(server);
+        let err = pump(&compressed[..compressed.len() / 2], &mut client, &mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(matches!(*err.into_inner().unwrap().downcast::<Error>().unwrap(), Error::UnexpectedEof { .. }));
+    }
+
+    // An in-memory stream that is pending on every other call, waking its
+    // task straight away, and moves at most three bytes at a time.
+    #[cfg(feature = "futures-io")]
+    #[derive(Default)]
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        ready: bool,
+        closed: bool,
+    }
+
+    #[cfg(feature = "futures-io")]
+    impl Trickle {
+        fn poll<T, F: FnOnce(&mut Self) -> T>(&mut self, cx: &mut Context, f: F) -> Poll<T> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(f(self))
+        }
+    }
+
+    #[cfg(feature = "futures-io")]
+    impl futures_io::AsyncRead for Trickle {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll(cx, |this| {
+                let n = buf.len().min(this.data.len() - this.pos).min(3);
+                buf[..n].copy_from_slice(&this.data[this.pos..this.pos + n]);
+                this.pos += n;
+                Ok(n)
+            })
+        }
+    }
+
+    #[cfg(feature = "futures-io")]
+    impl futures_io::AsyncWrite for Trickle {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll(cx, |this| {
+                let n = buf.len().min(3);
+                this.data.extend_from_slice(&buf[..n]);
+                Ok(n)
+            })
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            self.get_mut().poll(cx, |this| {
+                this.closed = true;
+                Ok(())
+            })
+        }
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn round_trips_through_futures_io() {
+        use futures_io::{AsyncRead, AsyncWrite};
+
+        let data = sample(120_000);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut writer = BzWriter::new(Trickle::default(), 1);
+        let mut input = &data[..];
+        let mut pending = 0;
+        while !input.is_empty() {
+            match Pin::new(&mut writer).poll_write(&mut cx, &input[..input.len().min(5000)]) {
+                Poll::Ready(n) => input = &input[n.unwrap()..],
+                Poll::Pending => pending += 1,
+            }
+        }
+        loop {
+            match Pin::new(&mut writer).poll_close(&mut cx) {
+                Poll::Ready(res) => break res.unwrap(),
+                Poll::Pending => pending += 1,
+            }
+        }
+        assert!(pending > 0);
+        let sink = writer.into_inner();
+        assert!(sink.closed);
+        assert_eq!(reference_decode(&sink.data), data);
+
+        // followed by a stream from the reference encoder
+        let mut compressed = sink.data;
+        compressed.extend(reference(b"and a second stream"));
+        let mut reader = BzReader::new(Trickle { data: compressed, ..Trickle::default() });
+        let mut out = vec![];
+        let mut buf = [0; 1000];
+        let mut pending = 0;
+        loop {
+            match Pin::new(&mut reader).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(n) => out.extend_from_slice(&buf[..n.unwrap()]),
+                Poll::Pending => pending += 1,
+            }
+        }
+        assert!(pending > 0);
+        assert_eq!(&out[..data.len()], &data[..]);
+        assert_eq!(&out[data.len()..], b"and a second stream");
+    }
+}
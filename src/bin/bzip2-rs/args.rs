@@ -0,0 +1,194 @@
+//! Command line parsing, following `bzip2`: options from the `BZIP2` and
+//! `BZIP` environment variables come before the command line's own, short
+//! flags may be combined (`-dc9`), options and files may come in any order,
+//! and `--` makes everything after it a file.
+
+use std::ffi::OsString;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Compress,
+    Decompress,
+    Test,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub mode: Mode,
+    pub level: u32,
+    pub stdout: bool,
+    pub keep: bool,
+    pub force: bool,
+    pub quiet: bool,
+    pub verbosity: u32,
+    /// Accepted for compatibility. Decoding always uses the same, small
+    /// amount of memory.
+    pub small: bool,
+    /// Report on each file tested as a line of JSON on standard output.
+    pub json: bool,
+    pub files: Vec<OsString>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+impl Options {
+    /// The defaults when invoked as `program`: `bunzip2` decompresses and
+    /// `bzcat` decompresses to standard output.
+    fn for_program(program: &str) -> Options {
+        let program = program.to_lowercase();
+        let cat = program.contains("z2cat") || program.contains("zcat");
+        let decompress = cat || program.contains("unzip");
+        Options {
+            mode: if decompress { Mode::Decompress } else { Mode::Compress },
+            level: 9,
+            stdout: cat,
+            keep: false,
+            force: false,
+            quiet: false,
+            verbosity: 0,
+            small: false,
+            json: false,
+            files: Vec::new(),
+        }
+    }
+}
+
+/// Parses `args`, which must not include the program name, for a program
+/// invoked as `program`.
+pub fn parse<I: IntoIterator<Item = OsString>>(program: &str, args: I) -> Result<Command, String> {
+    let mut options = Options::for_program(program);
+    let mut files_only = false;
+    let mut help = false;
+    let mut version = false;
+
+    for arg in args {
+        let flag = match arg.to_str() {
+            // like `bzip2`, a lone `-` is an empty set of flags
+            Some(flag) if !files_only && flag.starts_with('-') => flag.to_owned(),
+            _ => {
+                options.files.push(arg);
+                continue;
+            }
+        };
+
+        if flag == "--" {
+            files_only = true;
+            continue;
+        }
+        if flag.starts_with("--") {
+            match &flag[..] {
+                "--stdout" => options.stdout = true,
+                "--decompress" => options.mode = Mode::Decompress,
+                "--compress" => options.mode = Mode::Compress,
+                "--force" => options.force = true,
+                "--test" => options.mode = Mode::Test,
+                "--keep" => options.keep = true,
+                "--small" => options.small = true,
+                "--quiet" => options.quiet = true,
+                "--version" | "--license" => version = true,
+                "--verbose" => options.verbosity += 1,
+                "--help" => help = true,
+                "--fast" => options.level = 1,
+                "--best" => options.level = 9,
+                "--json" => options.json = true,
+                _ => return Err(format!("Bad flag `{}'", flag)),
+            }
+            continue;
+        }
+
+        for c in flag[1..].chars() {
+            match c {
+                'c' => options.stdout = true,
+                'd' => options.mode = Mode::Decompress,
+                'z' => options.mode = Mode::Compress,
+                'f' => options.force = true,
+                't' => options.mode = Mode::Test,
+                'k' => options.keep = true,
+                's' => options.small = true,
+                'q' => options.quiet = true,
+                'v' => options.verbosity += 1,
+                'L' | 'V' => version = true,
+                'h' => help = true,
+                '1'..='9' => options.level = c as u32 - '0' as u32,
+                _ => return Err(format!("Bad flag `{}'", flag)),
+            }
+        }
+    }
+
+    Ok(if help {
+        Command::Help
+    } else if version {
+        Command::Version
+    } else {
+        Command::Run(options)
+    })
+}
+
+/// Splits the value of an options environment variable into arguments.
+pub fn from_env(value: Option<OsString>) -> Vec<OsString> {
+    match value.as_ref().and_then(|value| value.to_str()) {
+        Some(value) => value.split_whitespace().map(OsString::from).collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &str, args: &[&str]) -> Options {
+        match parse(program, args.iter().map(OsString::from)).unwrap() {
+            Command::Run(options) => options,
+            command => panic!("expected options, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn flags_combine_and_mix_with_files() {
+        let options = run("bzip2-rs", &["-dc4", "a", "--keep", "-v", "b", "-v"]);
+        assert_eq!(options.mode, Mode::Decompress);
+        assert_eq!(options.level, 4);
+        assert!(options.stdout && options.keep && !options.force);
+        assert_eq!(options.verbosity, 2);
+        assert_eq!(options.files, vec![OsString::from("a"), OsString::from("b")]);
+
+        let options = run("bzip2-rs", &["--best", "-3", "--fast", "-", "--", "-z", "--help"]);
+        assert_eq!(options.level, 1);
+        assert_eq!(options.files, vec![OsString::from("-z"), OsString::from("--help")]);
+        assert!(!options.json);
+
+        let options = run("bzip2-rs", &["-t", "--json", "a.bz2"]);
+        assert_eq!(options.mode, Mode::Test);
+        assert!(options.json);
+
+        // later flags win, so command line flags override the environment
+        let mut args = from_env(Some(OsString::from(" -1  -q ")));
+        args.push(OsString::from("-9"));
+        let options = run("bzip2-rs", &args.iter().map(|a| a.to_str().unwrap()).collect::<Vec<_>>());
+        assert_eq!(options.level, 9);
+        assert!(options.quiet);
+    }
+
+    #[test]
+    fn program_name_sets_the_mode() {
+        assert_eq!(run("bzip2-rs", &[]).mode, Mode::Compress);
+        assert_eq!(run("bunzip2", &[]).mode, Mode::Decompress);
+        let options = run("bzcat", &[]);
+        assert_eq!(options.mode, Mode::Decompress);
+        assert!(options.stdout);
+        assert_eq!(run("bunzip2", &["-z"]).mode, Mode::Compress);
+    }
+
+    #[test]
+    fn help_version_and_bad_flags() {
+        assert_eq!(parse("bzip2-rs", vec![OsString::from("-dh")]), Ok(Command::Help));
+        assert_eq!(parse("bzip2-rs", vec![OsString::from("--license")]), Ok(Command::Version));
+        assert!(parse("bzip2-rs", vec![OsString::from("-x")]).is_err());
+        assert!(parse("bzip2-rs", vec![OsString::from("--frobnicate")]).is_err());
+    }
+}
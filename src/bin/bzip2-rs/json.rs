@@ -0,0 +1,134 @@
+//! The JSON written by `--json` and `inspect`: one object per file, on a
+//! line of its own.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+#[cfg(feature = "serde")]
+use bzip2_rs::Inspection;
+use bzip2_rs::{Error, Report};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// `report` on the file `name` as a single line of JSON.
+pub fn report(name: &str, report: &Report) -> String {
+    let mut out = String::new();
+    out.push_str("{\"file\":");
+    string(&mut out, name);
+    let _ = write!(out, ",\"ok\":{},\"total_in\":{},\"total_out\":{},\"error\":",
+                   report.is_ok(), report.total_in, report.total_out);
+    error(&mut out, report.error.as_ref());
+
+    out.push_str(",\"streams\":[");
+    for (i, stream) in report.streams.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"bits\":{},\"level\":{},\"blocks\":{},\"stored_crc\":{},\"computed_crc\":{}}}",
+                       range(&stream.bits), stream.level, range(&stream.blocks),
+                       stream.stored_crc, stream.computed_crc);
+    }
+
+    out.push_str("],\"blocks\":[");
+    for (i, block) in report.blocks.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"index\":{},\"bits\":{},\"len\":{},\"stored_crc\":{},\"computed_crc\":{},\
+                             \"tables\":{},\"randomised\":{}}}",
+                       block.index, range(&block.bits), block.len, block.stored_crc, block.computed_crc,
+                       block.tables, block.randomised);
+    }
+    out.push_str("]}");
+    out
+}
+
+/// `inspection` of the file `name` as a single line of JSON: the
+/// `Inspection` as it serialises, with a `file` field first.
+#[cfg(feature = "serde")]
+pub fn inspection(name: &str, inspection: &Inspection) -> String {
+    #[derive(Serialize)]
+    struct File<'a> {
+        file: &'a str,
+        #[serde(flatten)]
+        inspection: &'a Inspection,
+    }
+
+    serde_json::to_string(&File { file: name, inspection }).expect("an Inspection always serialises")
+}
+
+fn error(out: &mut String, error: Option<&Error>) {
+    match error {
+        Some(err) => {
+            out.push_str("{\"message\":");
+            string(out, &err.to_string());
+            let _ = write!(out, ",\"block\":{},\"bit_offset\":{}}}", err.block(), err.bit_offset());
+        }
+        None => out.push_str("null"),
+    }
+}
+
+fn range(range: &Range<u64>) -> String {
+    format!("[{},{}]", range.start, range.end)
+}
+
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "serde")]
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn escapes_and_shapes() {
+        let mut out = String::new();
+        string(&mut out, "a \"b\"\\\n\u{1}é");
+        assert_eq!(out, "\"a \\\"b\\\"\\\\\\n\\u0001é\"");
+
+        let empty = Report::default();
+        assert_eq!(report("x", &empty),
+                   "{\"file\":\"x\",\"ok\":true,\"total_in\":0,\"total_out\":0,\"error\":null,\
+                    \"streams\":[],\"blocks\":[]}");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn inspection_serialises_like_reports() {
+        let input = &b"BZh91AY&SY"[..];
+        let json = inspection("x", &bzip2_rs::inspect(input).unwrap());
+        assert_eq!(json,
+                   "{\"file\":\"x\",\"streams\":[{\"bits\":[0,32],\"level\":9,\"crc\":null,\"blocks\":[]}],\
+                    \"error\":{\"message\":\"unexpected end of input in block 0 at bit offset 80\",\
+                    \"block\":0,\"bit_offset\":80}}");
+
+        // errors and bit ranges are written the same way by `-t --json`
+        let inspected: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let verified: serde_json::Value = serde_json::from_str(&report("x", &bzip2_rs::verify(input).unwrap())).unwrap();
+        assert_eq!(inspected["error"], verified["error"]);
+
+        let mut writer = bzip2_rs::BzWriter::new(vec![], 9);
+        writer.write_all(b"hello").unwrap();
+        let input = writer.finish().unwrap();
+        let inspected: serde_json::Value = serde_json::from_str(&inspection("x", &bzip2_rs::inspect(&input[..]).unwrap())).unwrap();
+        let verified: serde_json::Value = serde_json::from_str(&report("x", &bzip2_rs::verify(&input[..]).unwrap())).unwrap();
+        assert_eq!(inspected["streams"][0]["bits"], verified["streams"][0]["bits"]);
+    }
+}
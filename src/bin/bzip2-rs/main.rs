@@ -0,0 +1,560 @@
+//! A replacement for the `bzip2` command line tool, taking the same flags and
+//! treating files the same way.
+
+extern crate bzip2_rs;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+
+mod args;
+mod json;
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, FileTimes, Metadata, OpenOptions};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use bzip2_rs::verify::BlockReport;
+use bzip2_rs::{verify, BzReader, BzWriter, Error, Report};
+
+use args::{Command, Mode, Options};
+
+// exit codes, as `bzip2` uses them
+const ENVIRONMENT: i32 = 1;
+const CORRUPT: i32 = 2;
+
+// compressed extensions and what they stand for once decompressed
+const EXTENSIONS: &[(&str, &str)] = &[("bz2", ""), ("bz", ""), ("tbz2", "tar"), ("tbz", "tar")];
+
+fn main() {
+    let mut args = env::args_os();
+    let program = args.next()
+        .and_then(|arg| Path::new(&arg).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "bzip2-rs".to_owned());
+
+    let args: Vec<OsString> = args.collect();
+    if args.first().is_some_and(|arg| arg == "inspect") {
+        process::exit(inspect(&program, &args[1..]));
+    }
+
+    let mut all = args::from_env(env::var_os("BZIP2"));
+    all.extend(args::from_env(env::var_os("BZIP")));
+    all.extend(args);
+
+    let options = match args::parse(&program, all) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            usage(&program);
+            return;
+        }
+        Ok(Command::Version) => {
+            eprintln!("bzip2-rs, a block-sorting file compressor.  Version {}.", env!("CARGO_PKG_VERSION"));
+            eprintln!();
+            eprintln!("   Licensed under either of the Apache License, Version 2.0 or the MIT");
+            eprintln!("   license, at your option.");
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}: {}", program, message);
+            usage(&program);
+            process::exit(ENVIRONMENT);
+        }
+    };
+
+    let mut cli = Cli::new(program, options);
+    cli.run();
+    process::exit(cli.exit);
+}
+
+fn usage(program: &str) {
+    eprint!("\
+bzip2-rs, a block-sorting file compressor.  Version {version}.
+
+   usage: {program} [flags and input files in any order]
+          {program} inspect [files]
+
+   -h --help           print this message
+   -d --decompress     force decompression
+   -z --compress       force compression
+   -k --keep           keep (don't delete) input files
+   -f --force          overwrite existing output files
+   -t --test           test compressed file integrity
+   -c --stdout         output to standard out
+   -q --quiet          suppress noncritical error messages
+   -v --verbose        be verbose (a 2nd -v gives more)
+   -L --license        display software version & license
+   -V --version        display software version & license
+   -s --small          accepted for compatibility
+   -1 .. -9            set block size to 100k .. 900k
+   --fast              alias for -1
+   --best              alias for -9
+   --json              with -t, report on each file as JSON on standard out
+
+   If invoked as `bunzip2', the default action is to decompress,
+   and as `bzcat', to decompress to standard output.
+
+   If no file names are given, {program} compresses or decompresses
+   from standard input to standard output.  You can combine
+   short flags, so `-v -4' means the same as -v4 or -4v, &c.
+   Options in the BZIP2 and BZIP environment variables come first.
+
+   `{program} inspect' writes the header and the Huffman tables, symbol
+   frequencies and so on of every block as a line of JSON per file.  It
+   needs {program} built with the serde feature.
+
+", version = env!("CARGO_PKG_VERSION"), program = program);
+}
+
+// Writes the internals of each of `files`, or of standard input, as JSON,
+// returning the exit code.
+#[cfg(feature = "serde")]
+fn inspect(program: &str, files: &[OsString]) -> i32 {
+    let mut exit = 0;
+    let mut one = |name: &str, inspection: io::Result<bzip2_rs::Inspection>| match inspection {
+        Ok(inspection) => {
+            println!("{}", json::inspection(name, &inspection));
+            if let Some(ref err) = inspection.error {
+                eprintln!("{}: {}: {}", program, name, err);
+                exit = exit.max(CORRUPT);
+            }
+        }
+        Err(err) => {
+            eprintln!("{}: {}: {}", program, name, err);
+            exit = exit.max(ENVIRONMENT);
+        }
+    };
+
+    if files.is_empty() {
+        let stdin = io::stdin();
+        one("(stdin)", bzip2_rs::inspect(stdin.lock()));
+    }
+    for file in files {
+        let name = Path::new(file).display().to_string();
+        one(&name, File::open(file).and_then(bzip2_rs::inspect));
+    }
+    exit
+}
+
+#[cfg(not(feature = "serde"))]
+fn inspect(program: &str, _files: &[OsString]) -> i32 {
+    eprintln!("{}: inspect needs {} built with the serde feature", program, program);
+    ENVIRONMENT
+}
+
+struct Cli {
+    program: String,
+    options: Options,
+    exit: i32,
+    // the length of the longest file name, to line up verbose output
+    longest: usize,
+}
+
+// What was read and written for one input.
+struct Totals {
+    input: u64,
+    output: u64,
+    trailing_garbage: bool,
+}
+
+impl Cli {
+    fn new(program: String, options: Options) -> Self {
+        let longest = options.files.iter().map(|file| file.to_string_lossy().len()).max().unwrap_or(7);
+        Cli {
+            program,
+            options,
+            exit: 0,
+            longest,
+        }
+    }
+
+    fn run(&mut self) {
+        let compress = self.options.mode == Mode::Compress;
+        let to_stdout = compress && (self.options.stdout || self.options.files.is_empty());
+        if to_stdout && io::stdout().is_terminal() {
+            self.fail(ENVIRONMENT, "I won't write compressed data to a terminal.".to_owned());
+            eprintln!("{}: For help, type: `{} --help'.", self.program, self.program);
+            return;
+        }
+        if self.options.files.is_empty() {
+            if !compress && io::stdin().is_terminal() {
+                self.fail(ENVIRONMENT, "I won't read compressed data from a terminal.".to_owned());
+                eprintln!("{}: For help, type: `{} --help'.", self.program, self.program);
+                return;
+            }
+            self.stdin();
+            return;
+        }
+
+        let files: Vec<OsString> = self.options.files.clone();
+        for file in &files {
+            self.file(Path::new(file));
+        }
+    }
+
+    fn fail(&mut self, code: i32, message: String) {
+        eprintln!("{}: {}", self.program, message);
+        self.exit = self.exit.max(code);
+    }
+
+    fn warn(&self, message: String) {
+        if !self.options.quiet {
+            eprintln!("{}: {}", self.program, message);
+        }
+    }
+
+    fn stdin(&mut self) {
+        let stdin = io::stdin();
+        if self.options.mode == Mode::Test {
+            return self.test("(stdin)", stdin.lock());
+        }
+        let stdout = io::stdout();
+        match self.transform(stdin.lock(), stdout.lock()).map(|(totals, _)| totals) {
+            Ok(totals) => self.done("(stdin)", &totals),
+            Err(err) => self.fail(error_code(&err), format!("(stdin): {}", err)),
+        }
+    }
+
+    fn file(&mut self, input: &Path) {
+        let name = input.display().to_string();
+        let metadata = match fs::metadata(input) {
+            Ok(metadata) => metadata,
+            Err(err) => return self.fail(ENVIRONMENT, format!("Can't open input file {}: {}.", name, err)),
+        };
+        if metadata.is_dir() {
+            return self.fail(ENVIRONMENT, format!("Input file {} is a directory.", name));
+        }
+
+        let output = if self.options.mode == Mode::Test || self.options.stdout {
+            None
+        } else {
+            match self.output_name(input) {
+                Some(output) => Some(output),
+                None => return,
+            }
+        };
+
+        if let Some(ref output) = output {
+            if !self.options.force {
+                let regular = fs::symlink_metadata(input).map(|metadata| metadata.is_file()).unwrap_or(false);
+                if !regular {
+                    return self.fail(ENVIRONMENT, format!("Input file {} is not a normal file.", name));
+                }
+                let links = other_links(&metadata);
+                if links > 0 {
+                    return self.fail(ENVIRONMENT, format!("Input file {} has {} other link{}.",
+                                                          name, links, if links == 1 { "" } else { "s" }));
+                }
+            }
+            if fs::symlink_metadata(output).is_ok() {
+                if !self.options.force {
+                    return self.fail(ENVIRONMENT, format!("Output file {} already exists.", output.display()));
+                }
+                if let Err(err) = fs::remove_file(output) {
+                    return self.fail(ENVIRONMENT, format!("Can't remove {}: {}.", output.display(), err));
+                }
+            }
+        }
+
+        let reader = match File::open(input) {
+            Ok(file) => BufReader::new(file),
+            Err(err) => return self.fail(ENVIRONMENT, format!("Can't open input file {}: {}.", name, err)),
+        };
+
+        let output = match output {
+            Some(output) => output,
+            None if self.options.mode == Mode::Test => return self.test(&name, reader),
+            None => {
+                let stdout = io::stdout();
+                return match self.transform(reader, stdout.lock()).map(|(totals, _)| totals) {
+                    Ok(totals) => self.done(&name, &totals),
+                    Err(err) => self.fail(error_code(&err), format!("{}: {}", name, err)),
+                };
+            }
+        };
+
+        let file = match create(&output) {
+            Ok(file) => file,
+            Err(err) => {
+                return self.fail(ENVIRONMENT, format!("Can't create output file {}: {}.", output.display(), err))
+            }
+        };
+        let result = self.transform(reader, BufWriter::new(file))
+            .and_then(|(totals, writer)| Ok((totals, writer.into_inner()?)));
+        let (totals, file) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                let _ = fs::remove_file(&output);
+                return self.fail(error_code(&err), format!("{}: {}", name, err));
+            }
+        };
+
+        if let Err(err) = copy_metadata(&metadata, &file) {
+            return self.fail(ENVIRONMENT, format!("Can't set attributes of {}: {}.", output.display(), err));
+        }
+        drop(file);
+        self.done(&name, &totals);
+
+        if !self.options.keep {
+            if let Err(err) = fs::remove_file(input) {
+                self.fail(ENVIRONMENT, format!("Can't remove input file {}: {}.", name, err));
+            }
+        }
+    }
+
+    // The file to write `input` to, or `None` if it should be skipped.
+    fn output_name(&mut self, input: &Path) -> Option<PathBuf> {
+        let extension = input.extension().and_then(|extension| extension.to_str());
+        let known = EXTENSIONS.iter().find(|&&(compressed, _)| extension == Some(compressed));
+        let mut output = input.as_os_str().to_owned();
+
+        if self.options.mode == Mode::Compress {
+            if let Some(&(compressed, _)) = known {
+                self.fail(ENVIRONMENT, format!("Input file {} already has .{} suffix.", input.display(), compressed));
+                return None;
+            }
+            output.push(".bz2");
+            return Some(output.into());
+        }
+
+        match known {
+            Some(&(_, decompressed)) => Some(input.with_extension(decompressed)),
+            None => {
+                output.push(".out");
+                let output = PathBuf::from(output);
+                self.warn(format!("Can't guess original name for {} -- using {}", input.display(), output.display()));
+                Some(output)
+            }
+        }
+    }
+
+    fn transform<R: Read, W: Write>(&self, input: R, output: W) -> io::Result<(Totals, W)> {
+        if self.options.mode == Mode::Compress {
+            let mut input = input;
+            let mut writer = BzWriter::new(output, self.options.level);
+            io::copy(&mut input, &mut writer)?;
+            writer.try_finish()?;
+            let totals = Totals {
+                input: writer.total_in(),
+                output: writer.total_out(),
+                trailing_garbage: false,
+            };
+            let mut output = writer.finish()?;
+            output.flush()?;
+            return Ok((totals, output));
+        }
+
+        let mut reader = BzReader::new(input);
+        let mut output = output;
+        let trailing_garbage = match io::copy(&mut reader, &mut output) {
+            Ok(_) => false,
+            Err(ref err) if is_trailing_garbage(err) => true,
+            Err(err) => return Err(err),
+        };
+        output.flush()?;
+        let totals = Totals {
+            input: reader.total_in(),
+            output: reader.total_out(),
+            trailing_garbage,
+        };
+        Ok((totals, output))
+    }
+
+    // Checks `input` without writing what it decodes to, reporting on each
+    // block with -vv and on the whole with --json.
+    fn test<R: Read>(&mut self, name: &str, input: R) {
+        let report = match verify(input) {
+            Ok(report) => report,
+            Err(err) => return self.fail(ENVIRONMENT, format!("{}: {}", name, err)),
+        };
+        if self.options.json {
+            println!("{}", json::report(name, &report));
+        }
+        if self.options.verbosity >= 2 {
+            eprint!("{}", details(name, &report));
+        }
+
+        let totals = Totals {
+            input: report.total_in,
+            output: report.total_out,
+            trailing_garbage: matches!(report.error, Some(Error::TrailingGarbage { .. })),
+        };
+        match report.error {
+            Some(ref err) if !totals.trailing_garbage => self.fail(CORRUPT, format!("{}: {}", name, err)),
+            _ => self.done(name, &totals),
+        }
+    }
+
+    fn done(&self, name: &str, totals: &Totals) {
+        if totals.trailing_garbage {
+            self.warn(format!("{}: trailing garbage after EOF ignored", name));
+        }
+        if self.options.verbosity == 0 {
+            return;
+        }
+        let result = match self.options.mode {
+            Mode::Compress => ratios(totals.input, totals.output),
+            Mode::Decompress => "done".to_owned(),
+            Mode::Test => "ok".to_owned(),
+        };
+        let pad = self.longest.saturating_sub(name.len());
+        eprintln!("  {}: {:pad$}{}", name, "", result, pad = pad);
+    }
+}
+
+fn ratios(input: u64, output: u64) -> String {
+    if input == 0 {
+        return "no data compressed.".to_owned();
+    }
+    let (input_f, output_f) = (input as f64, output as f64);
+    format!("{:6.3}:1, {:6.3} bits/byte, {:5.2}% saved, {} in, {} out.",
+            input_f / output_f, 8.0 * output_f / input_f, 100.0 * (1.0 - output_f / input_f), input, output)
+}
+
+// What `bzip2 -tvv` shows: each block's stored and computed CRCs, and each
+// stream's combined ones.
+fn details(name: &str, report: &Report) -> String {
+    let mut out = format!("  {}:\n", name);
+    let mut blocks = report.blocks.iter().peekable();
+    let block_line = |block: &BlockReport| {
+        format!("    [{}: bits {}-{}, {} tables{}, {} bytes {{0x{:08x}, 0x{:08x}}}]\n",
+                block.index + 1, block.bits.start, block.bits.end, block.tables,
+                if block.randomised { ", randomised" } else { "" }, block.len,
+                block.stored_crc, block.computed_crc)
+    };
+    for stream in &report.streams {
+        while let Some(block) = blocks.next_if(|block| block.index < stream.blocks.end) {
+            out.push_str(&block_line(block));
+        }
+        out.push_str(&format!("    combined CRCs: stored = 0x{:08x}, computed = 0x{:08x}\n",
+                              stream.stored_crc, stream.computed_crc));
+    }
+    for block in blocks {
+        out.push_str(&block_line(block));
+    }
+    out
+}
+
+fn decode_error(err: &io::Error) -> Option<&Error> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<Error>())
+}
+
+fn is_trailing_garbage(err: &io::Error) -> bool {
+    matches!(decode_error(err), Some(&Error::TrailingGarbage { .. }))
+}
+
+fn error_code(err: &io::Error) -> i32 {
+    if decode_error(err).is_some() { CORRUPT } else { ENVIRONMENT }
+}
+
+#[cfg(unix)]
+fn create(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // readable only by us until the input's permissions are copied over
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(unix)]
+fn other_links(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink().saturating_sub(1)
+}
+
+#[cfg(not(unix))]
+fn other_links(_: &Metadata) -> u64 {
+    0
+}
+
+// Gives the output the input's times, owner and permissions, as far as we
+// are allowed to.
+fn copy_metadata(from: &Metadata, to: &File) -> io::Result<()> {
+    let mut times = FileTimes::new();
+    if let Ok(accessed) = from.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    if let Ok(modified) = from.modified() {
+        times = times.set_modified(modified);
+    }
+    to.set_times(times)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{fchown, MetadataExt};
+        // only root can give files away, so failing is expected
+        let _ = fchown(to, Some(from.uid()), Some(from.gid()));
+    }
+    to.set_permissions(from.permissions())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(mode: Mode) -> Cli {
+        let mut options = match args::parse("bzip2-rs", vec![]).unwrap() {
+            Command::Run(options) => options,
+            _ => unreachable!(),
+        };
+        options.mode = mode;
+        options.quiet = true;
+        Cli::new("bzip2-rs".to_owned(), options)
+    }
+
+    #[test]
+    fn output_names() {
+        let mut compress = cli(Mode::Compress);
+        assert_eq!(compress.output_name(Path::new("dir/foo")), Some(PathBuf::from("dir/foo.bz2")));
+        assert_eq!(compress.output_name(Path::new("foo.tar")), Some(PathBuf::from("foo.tar.bz2")));
+        assert_eq!(compress.output_name(Path::new("foo.bz2")), None);
+        assert_eq!(compress.output_name(Path::new("foo.tbz")), None);
+        assert_eq!(compress.exit, ENVIRONMENT);
+
+        let mut decompress = cli(Mode::Decompress);
+        assert_eq!(decompress.output_name(Path::new("dir/foo.bz2")), Some(PathBuf::from("dir/foo")));
+        assert_eq!(decompress.output_name(Path::new("foo.bz")), Some(PathBuf::from("foo")));
+        assert_eq!(decompress.output_name(Path::new("foo.tbz2")), Some(PathBuf::from("foo.tar")));
+        assert_eq!(decompress.output_name(Path::new("foo.tbz")), Some(PathBuf::from("foo.tar")));
+        assert_eq!(decompress.output_name(Path::new("foo")), Some(PathBuf::from("foo.out")));
+        assert_eq!(decompress.output_name(Path::new(".bz2")), Some(PathBuf::from(".bz2.out")));
+        assert_eq!(decompress.exit, 0);
+    }
+
+    #[test]
+    fn round_trips() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7 % 13) as u8 + b'a').collect();
+        let (totals, compressed) = cli(Mode::Compress).transform(&data[..], vec![]).unwrap();
+        assert_eq!(totals.input, data.len() as u64);
+        assert_eq!(totals.output, compressed.len() as u64);
+
+        let mut garbage = compressed.clone();
+        garbage.extend_from_slice(b"junk");
+        let (totals, out) = cli(Mode::Decompress).transform(&garbage[..], vec![]).unwrap();
+        assert_eq!(out, data);
+        assert!(totals.trailing_garbage);
+
+        let err = cli(Mode::Test).transform(&compressed[..compressed.len() - 1], io::sink()).err().unwrap();
+        assert_eq!(error_code(&err), CORRUPT);
+        let report = verify(&garbage[..]).unwrap();
+        assert_eq!(details("f", &report), format!("  f:\n    [1: bits 32-{}, {} tables, 20000 bytes {{0x{:08x}, 0x{:08x}}}]\n    \
+                                                   combined CRCs: stored = 0x{:08x}, computed = 0x{:08x}\n",
+                                                  report.blocks[0].bits.end, report.blocks[0].tables,
+                                                  report.blocks[0].stored_crc, report.blocks[0].stored_crc,
+                                                  report.streams[0].stored_crc, report.streams[0].stored_crc));
+        let mut test = cli(Mode::Test);
+        test.test("f", &compressed[..compressed.len() - 1]);
+        assert_eq!(test.exit, CORRUPT);
+        let mut test = cli(Mode::Test);
+        test.test("f", &garbage[..]);
+        assert_eq!(test.exit, 0);
+
+        assert_eq!(ratios(0, 14), "no data compressed.");
+        assert_eq!(ratios(800, 200), " 4.000:1,  2.000 bits/byte, 75.00% saved, 800 in, 200 out.");
+    }
+}
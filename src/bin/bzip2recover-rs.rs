@@ -0,0 +1,139 @@
+//! Salvages the intact blocks of a damaged `.bz2` file, like `bzip2recover`.
+//!
+//! Each block found is written to a stream of its own, `rec00001file.bz2`
+//! and so on, next to the damaged file, and blocks that fail to decode are
+//! reported. With `-c`, the data of the intact blocks is written to standard
+//! output instead.
+
+extern crate bzip2_rs;
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use bzip2_rs::Recover;
+
+// exit codes
+const ENVIRONMENT: i32 = 1;
+const DAMAGED: i32 = 2;
+
+fn main() {
+    let mut concat = false;
+    let mut quiet = false;
+    let mut files = Vec::new();
+    for arg in env::args_os().skip(1) {
+        match arg.to_str() {
+            Some("-c") | Some("--stdout") => concat = true,
+            Some("-q") | Some("--quiet") => quiet = true,
+            Some("-h") | Some("--help") => {
+                usage();
+                return;
+            }
+            Some(flag) if flag.starts_with('-') => {
+                eprintln!("bzip2recover-rs: bad flag `{}'", flag);
+                usage();
+                process::exit(ENVIRONMENT);
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.len() != 1 {
+        usage();
+        process::exit(ENVIRONMENT);
+    }
+
+    match recover(Path::new(&files[0]), concat, quiet) {
+        Ok(true) => {}
+        Ok(false) => process::exit(DAMAGED),
+        Err(err) => {
+            eprintln!("bzip2recover-rs: {}: {}", files[0].to_string_lossy(), err);
+            process::exit(ENVIRONMENT);
+        }
+    }
+}
+
+fn usage() {
+    eprint!("\
+usage: bzip2recover-rs [-c] [-q] damaged_file_name
+
+   Writes each block of the file as a stream of its own, rec00001file.bz2
+   and so on, in the file's directory, and reports the damaged ones.
+
+   -c --stdout         write the data of the intact blocks to standard out
+   -q --quiet          only report damaged blocks
+   -h --help           print this message
+
+   Exits with status 2 if any block is damaged.
+");
+}
+
+// Recovers the blocks of `input`, returning whether all of them were intact.
+fn recover(input: &Path, concat: bool, quiet: bool) -> io::Result<bool> {
+    let file = BufReader::new(File::open(input)?);
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let (mut total, mut damaged) = (0, 0);
+
+    for block in Recover::new(file) {
+        let block = block?;
+        total += 1;
+        let number = block.index + 1;
+        match block.result {
+            Ok(ref data) => {
+                if !quiet {
+                    eprintln!("   block {} runs from {} to {}", number, block.bits.start, block.bits.end);
+                }
+                if concat {
+                    stdout.write_all(data)?;
+                }
+            }
+            Err(ref err) => {
+                damaged += 1;
+                eprintln!("   block {} runs from {} to {} -- damaged: {}",
+                          number, block.bits.start, block.bits.end, err);
+            }
+        }
+        if !concat {
+            let output = output_name(input, number);
+            File::create(&output)?.write_all(&block.to_stream())?;
+            if !quiet {
+                eprintln!("   writing block {} to `{}' ...", number, output.display());
+            }
+        }
+    }
+    stdout.flush()?;
+
+    if !quiet || damaged > 0 {
+        eprintln!("bzip2recover-rs: {} of {} blocks intact", total - damaged, total);
+    }
+    Ok(damaged == 0)
+}
+
+// The file block `number` is written to: `rec00001` and the input's name,
+// with a `.bz2` extension added if it lacks one.
+fn output_name(input: &Path, number: u64) -> PathBuf {
+    let mut name = OsString::from(format!("rec{:05}", number));
+    name.push(input.file_name().unwrap_or_default());
+    let mut output = input.with_file_name(name);
+    if output.extension().is_none_or(|extension| extension != "bz2") {
+        let mut with_extension = output.into_os_string();
+        with_extension.push(".bz2");
+        output = with_extension.into();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_names() {
+        assert_eq!(output_name(Path::new("dir/file.bz2"), 1), PathBuf::from("dir/rec00001file.bz2"));
+        assert_eq!(output_name(Path::new("file.tbz"), 42), PathBuf::from("rec00042file.tbz.bz2"));
+        assert_eq!(output_name(Path::new("file"), 123_456), PathBuf::from("rec123456file.bz2"));
+    }
+}
@@ -0,0 +1,199 @@
+// MSB-first bit I/O. The reader is fed from byte slices as bits are needed,
+// so a decoder can suspend whenever the input runs dry and pick up where it
+// left off once more arrives.
+
+use alloc::vec::Vec;
+
+pub struct BitReader {
+    acc: u64,
+    count: u32,
+    bytes: u64,
+}
+
+impl BitReader {
+    pub fn new() -> Self {
+        BitReader {
+            acc: 0,
+            count: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Creates a reader for input that starts `bytes` bytes into the data,
+    /// so that positions count from the start of the data.
+    #[cfg(feature = "std")]
+    pub fn starting_at(bytes: u64) -> Self {
+        BitReader {
+            acc: 0,
+            count: 0,
+            bytes,
+        }
+    }
+
+    /// The number of bits consumed so far.
+    pub fn position(&self) -> u64 {
+        self.bytes * 8 - self.count as u64
+    }
+
+    /// The number of bits buffered but not yet consumed.
+    pub fn available(&self) -> u32 {
+        self.count
+    }
+
+    /// Pulls bytes from `input` until at least `n` bits (at most 56) are
+    /// buffered. Returns whether that succeeded.
+    #[inline]
+    pub fn fill(&mut self, input: &mut &[u8], n: u32) -> bool {
+        debug_assert!(n <= 56);
+        while self.count < n {
+            match input.split_first() {
+                Some((&byte, rest)) => {
+                    self.acc = (self.acc << 8) | byte as u64;
+                    self.count += 8;
+                    self.bytes += 1;
+                    *input = rest;
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Returns the next `n` buffered bits without consuming them.
+    #[inline]
+    pub fn peek(&self, n: u32) -> u32 {
+        debug_assert!(n <= self.count && n <= 32);
+        ((self.acc >> (self.count - n)) & ((1u64 << n) - 1)) as u32
+    }
+
+    #[inline]
+    pub fn consume(&mut self, n: u32) {
+        debug_assert!(n <= self.count);
+        self.count -= n;
+    }
+
+    #[inline]
+    pub fn read(&mut self, n: u32) -> u32 {
+        let bits = self.peek(n);
+        self.consume(n);
+        bits
+    }
+
+    /// Discards buffered bits up to the next byte boundary.
+    pub fn align(&mut self) {
+        self.count -= self.count % 8;
+    }
+}
+
+pub struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    count: u32,
+    drained: u64,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter {
+            out: Vec::new(),
+            acc: 0,
+            count: 0,
+            drained: 0,
+        }
+    }
+
+    /// Writes the low `n` bits (at most 32) of `bits`.
+    #[inline]
+    pub fn write(&mut self, n: u32, bits: u32) {
+        debug_assert!(n <= 32);
+        self.acc = (self.acc << n) | (bits as u64 & ((1u64 << n) - 1));
+        self.count += n;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.out.push((self.acc >> self.count) as u8);
+        }
+    }
+
+    pub fn write_u48(&mut self, bits: u64) {
+        self.write(16, (bits >> 32) as u32);
+        self.write(32, bits as u32);
+    }
+
+    /// Pads the output with zero bits up to the next byte boundary.
+    pub fn align(&mut self) {
+        if self.count > 0 {
+            let n = 8 - self.count;
+            self.write(n, 0);
+        }
+    }
+
+    /// The number of bits written so far.
+    pub fn position(&self) -> u64 {
+        (self.drained + self.out.len() as u64) * 8 + self.count as u64
+    }
+
+    /// Appends all the bits written to `other`, which need not end on a byte
+    /// boundary.
+    #[cfg(feature = "std")]
+    pub fn append(&mut self, other: &BitWriter) {
+        if self.count == 0 {
+            self.out.extend_from_slice(&other.out);
+        } else {
+            for &byte in &other.out {
+                self.write(8, byte as u32);
+            }
+        }
+        self.write(other.count, other.acc as u32);
+    }
+
+    /// The complete bytes written so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.out
+    }
+
+    /// Removes the first `n` complete bytes, keeping any partial byte.
+    pub fn drain(&mut self, n: usize) {
+        self.out.drain(..n);
+        self.drained += n as u64;
+    }
+
+    /// Discards everything written, keeping the buffer.
+    pub fn clear(&mut self) {
+        self.out.clear();
+        self.acc = 0;
+        self.count = 0;
+        self.drained = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut w = BitWriter::new();
+        w.write(1, 1);
+        w.write(24, 0xabcdef);
+        w.write_u48(0x3141_5926_5359);
+        w.write(3, 5);
+        w.align();
+
+        let data = w.bytes().to_owned();
+        let mut input = &data[..];
+        let mut r = BitReader::new();
+        assert!(r.fill(&mut input, 25));
+        assert_eq!(r.read(1), 1);
+        assert_eq!(r.read(24), 0xabcdef);
+        assert!(r.fill(&mut input, 48));
+        assert_eq!(r.read(16), 0x3141);
+        assert_eq!(r.read(32), 0x5926_5359);
+        assert!(r.fill(&mut input, 3));
+        assert_eq!(r.read(3), 5);
+        assert_eq!(r.position(), 76);
+        r.align();
+        assert_eq!(r.position(), 80);
+        assert!(input.is_empty());
+        assert!(!r.fill(&mut input, 1));
+    }
+}
@@ -0,0 +1,1034 @@
+use alloc::vec::Vec;
+
+use bits::{BitReader, BitWriter};
+use error::{Error, Result};
+use huffman::{self, DecodeTable, Decoded, MAX_ALPHA_SIZE, MAX_CODE_LEN, MAX_ENCODE_LEN};
+#[cfg(feature = "std")]
+use crc::Crc;
+use inspect::BlockInfo;
+use randomise::Randomiser;
+use {bwt_into, mtf, rle, BwtBuffers};
+
+pub const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+pub const EOS_MAGIC: u64 = 0x1772_4538_5090;
+
+// A little more than the most a block can take up compressed.
+#[cfg(feature = "std")]
+pub const MAX_BLOCK_BITS: u64 = 3 << 23;
+
+/// The most a block of size `level` can decode to: every 5 bytes of it can
+/// be a run of 4 with a count of 255 more.
+#[cfg(feature = "std")]
+pub fn max_block_output(level: u32) -> u64 {
+    level as u64 * 100_000 / 5 * 259
+}
+
+const MAX_GROUPS: usize = 6;
+const GROUP_SIZE: usize = 50;
+const MAX_SELECTORS: usize = 2 + 900_000 / GROUP_SIZE;
+const N_ITERS: usize = 4;
+
+const RUNA: u16 = 0;
+const RUNB: u16 = 1;
+
+const LESSER_ICOST: u8 = 0;
+const GREATER_ICOST: u8 = 15;
+
+#[derive(Clone, Copy)]
+enum State {
+    Crc,
+    Randomised,
+    OrigPtr,
+    UsedMap,
+    Bitmap(usize),
+    Groups,
+    Selectors,
+    Selector { i: usize, j: usize },
+    TableStart(usize),
+    Table { t: usize, i: usize, len: u8 },
+    Data,
+    Done,
+}
+
+/// Where a block decoder keeps a word for each byte of the block: the byte
+/// in the low 8 bits, and once the block is complete, the link that undoes
+/// the BWT in the rest, as `BZ2_decompress` does in its fast mode.
+pub trait Workspace: AsMut<[u32]> {
+    /// Makes sure there are at least `len` words, returning whether there
+    /// is room for them.
+    fn grow(&mut self, len: usize) -> bool;
+}
+
+impl Workspace for Vec<u32> {
+    fn grow(&mut self, len: usize) -> bool {
+        if self.len() < len {
+            self.resize(len, 0);
+        }
+        true
+    }
+}
+
+impl Workspace for &mut [u32] {
+    fn grow(&mut self, len: usize) -> bool {
+        self.len() >= len
+    }
+}
+
+/// Decodes one block, from just after its magic up to its end-of-block
+/// symbol. Decoding can be suspended whenever the input runs out and resumed
+/// once more is available.
+///
+/// Nothing is allocated but what `W` allocates to grow.
+pub struct Decoder<W = Vec<u32>> {
+    state: State,
+    index: u64,
+    start: u64,
+    max_len: usize,
+
+    crc: u32,
+    randomised: bool,
+    orig_ptr: u32,
+    used_map: u16,
+    seq_to_unseq: [u8; 256],
+    n_in_use: usize,
+
+    n_groups: usize,
+    n_selectors: usize,
+    selector_mtf: [u8; MAX_GROUPS],
+    selectors: [u8; MAX_SELECTORS],
+    lens: [[u8; MAX_ALPHA_SIZE]; MAX_GROUPS],
+    tables: [DecodeTable; MAX_GROUPS],
+    freq: [u32; MAX_ALPHA_SIZE],
+
+    tt: W,
+    len: usize,
+    mtf: [u8; 256],
+    run: u32,
+    run_weight: u32,
+    group: usize,
+    group_left: usize,
+    table: usize,
+
+    // reading out a finished block: the next link to follow, the bytes left
+    // to follow links for, and those followed but not yet run-length decoded
+    pos: u32,
+    left: usize,
+    randomiser: Randomiser,
+    rle: rle::Decoder,
+    buf: [u8; 1024],
+    buf_pos: usize,
+    buf_len: usize,
+}
+
+impl<W: Workspace> Decoder<W> {
+    pub fn with_workspace(tt: W) -> Self {
+        Decoder {
+            state: State::Done,
+            index: 0,
+            start: 0,
+            max_len: 0,
+            crc: 0,
+            randomised: false,
+            orig_ptr: 0,
+            used_map: 0,
+            seq_to_unseq: [0; 256],
+            n_in_use: 0,
+            n_groups: 0,
+            n_selectors: 0,
+            selector_mtf: [0; MAX_GROUPS],
+            selectors: [0; MAX_SELECTORS],
+            lens: [[0; MAX_ALPHA_SIZE]; MAX_GROUPS],
+            // replaced by each block's own tables before they are used
+            tables: [DecodeTable::new(&[]); MAX_GROUPS],
+            freq: [0; MAX_ALPHA_SIZE],
+            tt,
+            len: 0,
+            mtf: [0; 256],
+            run: 0,
+            run_weight: 0,
+            group: 0,
+            group_left: 0,
+            table: 0,
+            pos: 0,
+            left: 0,
+            randomiser: Randomiser::new(),
+            rle: rle::Decoder::new(),
+            buf: [0; 1024],
+            buf_pos: 0,
+            buf_len: 0,
+        }
+    }
+
+    /// Prepares to decode block number `index`, whose magic starts at bit
+    /// `start`, in a stream with block size `level`.
+    pub fn reset(&mut self, index: u64, start: u64, level: u32) {
+        self.state = State::Crc;
+        self.index = index;
+        self.start = start;
+        self.max_len = level as usize * 100_000;
+    }
+
+    /// The bit offset of the block magic.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The CRC stored in the block header.
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    /// The number of Huffman tables the block declares.
+    pub fn tables(&self) -> usize {
+        self.n_groups
+    }
+
+    /// Whether the block is randomised.
+    pub fn randomised(&self) -> bool {
+        self.randomised
+    }
+
+    /// The internals of a completely decoded block that ends at bit `end`.
+    pub fn inspect(&self, end: u64) -> BlockInfo {
+        debug_assert!(matches!(self.state, State::Done));
+        let alpha_size = self.alpha_size();
+        BlockInfo {
+            index: self.index,
+            bits: self.start..end,
+            crc: self.crc,
+            randomised: self.randomised,
+            orig_ptr: self.orig_ptr,
+            used: self.seq_to_unseq[..self.n_in_use].to_vec(),
+            selectors: self.n_selectors as u32,
+            code_lengths: self.lens[..self.n_groups].iter().map(|lens| lens[..alpha_size].to_vec()).collect(),
+            frequencies: self.freq[..alpha_size].to_vec(),
+        }
+    }
+
+    fn alpha_size(&self) -> usize {
+        self.n_in_use + 2
+    }
+
+    /// Decodes as much of the block as `input` allows, returning whether the
+    /// end-of-block symbol was reached.
+    pub fn decode(&mut self, bits: &mut BitReader, input: &mut &[u8]) -> Result<bool> {
+        macro_rules! need {
+            ($n:expr) => {
+                if !bits.fill(input, $n) {
+                    return Ok(false);
+                }
+            };
+        }
+
+        loop {
+            let pos = bits.position();
+            match self.state {
+                State::Crc => {
+                    need!(32);
+                    self.crc = bits.read(32);
+                    self.state = State::Randomised;
+                }
+                State::Randomised => {
+                    need!(1);
+                    self.randomised = bits.read(1) == 1;
+                    self.state = State::OrigPtr;
+                }
+                State::OrigPtr => {
+                    need!(24);
+                    self.orig_ptr = bits.read(24);
+                    self.state = State::UsedMap;
+                }
+                State::UsedMap => {
+                    need!(16);
+                    self.used_map = bits.read(16) as u16;
+                    self.n_in_use = 0;
+                    self.state = State::Bitmap(0);
+                }
+                State::Bitmap(16) => {
+                    if self.n_in_use == 0 {
+                        return Err(Error::InvalidHuffmanTable {
+                            block: self.index,
+                            bit_offset: pos,
+                        });
+                    }
+                    self.state = State::Groups;
+                }
+                State::Bitmap(i) => {
+                    if self.used_map & (0x8000 >> i) != 0 {
+                        need!(16);
+                        let map = bits.read(16);
+                        for j in 0..16 {
+                            if map & (0x8000 >> j) != 0 {
+                                self.seq_to_unseq[self.n_in_use] = (i * 16 + j) as u8;
+                                self.n_in_use += 1;
+                            }
+                        }
+                    }
+                    self.state = State::Bitmap(i + 1);
+                }
+                State::Groups => {
+                    need!(3);
+                    self.n_groups = bits.read(3) as usize;
+                    if self.n_groups < 2 || self.n_groups > MAX_GROUPS {
+                        return Err(Error::InvalidHuffmanTable {
+                            block: self.index,
+                            bit_offset: pos,
+                        });
+                    }
+                    self.state = State::Selectors;
+                }
+                State::Selectors => {
+                    need!(15);
+                    self.n_selectors = bits.read(15) as usize;
+                    if self.n_selectors == 0 {
+                        return Err(Error::SelectorOutOfRange {
+                            block: self.index,
+                            bit_offset: pos,
+                        });
+                    }
+                    for (t, s) in self.selector_mtf.iter_mut().enumerate() {
+                        *s = t as u8;
+                    }
+                    self.state = State::Selector { i: 0, j: 0 };
+                }
+                State::Selector { i, j } => {
+                    if i == self.n_selectors {
+                        self.state = State::TableStart(0);
+                        continue;
+                    }
+                    need!(1);
+                    if bits.read(1) == 1 {
+                        if j + 1 >= self.n_groups {
+                            return Err(Error::SelectorOutOfRange {
+                                block: self.index,
+                                bit_offset: pos,
+                            });
+                        }
+                        self.state = State::Selector { i, j: j + 1 };
+                        continue;
+                    }
+                    // selectors past the most a block can use are ignored
+                    let sel = self.selector_mtf[j];
+                    self.selector_mtf[..j + 1].rotate_right(1);
+                    if i < MAX_SELECTORS {
+                        self.selectors[i] = sel;
+                    }
+                    self.state = State::Selector { i: i + 1, j: 0 };
+                }
+                State::TableStart(t) => {
+                    if t == self.n_groups {
+                        self.start_data();
+                        continue;
+                    }
+                    need!(5);
+                    let len = bits.read(5) as u8;
+                    self.state = State::Table { t, i: 0, len };
+                }
+                State::Table { t, i, len } => {
+                    if i == self.alpha_size() {
+                        self.state = State::TableStart(t + 1);
+                        continue;
+                    }
+                    if len < 1 || len as u32 > MAX_CODE_LEN {
+                        return Err(Error::InvalidHuffmanTable {
+                            block: self.index,
+                            bit_offset: pos,
+                        });
+                    }
+                    need!(1);
+                    if bits.peek(1) == 0 {
+                        bits.consume(1);
+                        self.lens[t][i] = len;
+                        self.state = State::Table { t, i: i + 1, len };
+                    } else {
+                        need!(2);
+                        let len = if bits.read(2) == 0b10 { len + 1 } else { len - 1 };
+                        self.state = State::Table { t, i, len };
+                    }
+                }
+                State::Data => return self.decode_data(bits, input),
+                State::Done => return Ok(true),
+            }
+        }
+    }
+
+    fn start_data(&mut self) {
+        let alpha_size = self.alpha_size();
+        for t in 0..self.n_groups {
+            self.tables[t] = DecodeTable::new(&self.lens[t][..alpha_size]);
+        }
+        self.freq = [0; MAX_ALPHA_SIZE];
+        self.len = 0;
+        // MTF over the byte values used, so that it yields the bytes
+        // themselves
+        self.mtf = self.seq_to_unseq;
+        self.run = 0;
+        self.run_weight = 0;
+        self.group = 0;
+        self.group_left = 0;
+        self.state = State::Data;
+    }
+
+    fn decode_data(&mut self, bits: &mut BitReader, input: &mut &[u8]) -> Result<bool> {
+        let alpha_size = self.alpha_size();
+        let eob = self.n_in_use as u16 + 1;
+        let selectors = self.n_selectors.min(MAX_SELECTORS);
+
+        loop {
+            let pos = bits.position();
+
+            if self.group_left == 0 {
+                if self.group >= selectors {
+                    return Err(Error::SelectorOutOfRange {
+                        block: self.index,
+                        bit_offset: pos,
+                    });
+                }
+                self.table = self.selectors[self.group] as usize;
+                self.group += 1;
+                self.group_left = GROUP_SIZE;
+            }
+
+            bits.fill(input, MAX_CODE_LEN);
+            let sym = match self.tables[self.table].decode(bits, alpha_size) {
+                Decoded::Symbol(sym) => sym,
+                Decoded::Incomplete => return Ok(false),
+                Decoded::Invalid => {
+                    return Err(Error::InvalidHuffmanTable {
+                        block: self.index,
+                        bit_offset: pos,
+                    })
+                }
+            };
+            self.group_left -= 1;
+            self.freq[sym as usize] += 1;
+
+            if sym == RUNA || sym == RUNB {
+                // no legitimate run is longer than a block
+                if self.run_weight >= 2 * 1024 * 1024 {
+                    return Err(Error::BlockOverflow {
+                        block: self.index,
+                        bit_offset: pos,
+                    });
+                }
+                if self.run_weight == 0 {
+                    self.run_weight = 1;
+                }
+                self.run += (sym as u32 + 1) * self.run_weight;
+                self.run_weight <<= 1;
+                continue;
+            }
+
+            if self.run_weight > 0 {
+                let len = self.len + self.run as usize;
+                if len > self.max_len {
+                    return Err(Error::BlockOverflow {
+                        block: self.index,
+                        bit_offset: pos,
+                    });
+                }
+                if !self.tt.grow(len) {
+                    return Err(self.scratch_error(pos));
+                }
+                let byte = self.mtf[0] as u32;
+                for w in &mut self.tt.as_mut()[self.len..len] {
+                    *w = byte;
+                }
+                self.len = len;
+                self.run = 0;
+                self.run_weight = 0;
+            }
+
+            if sym == eob {
+                self.state = State::Done;
+                return Ok(true);
+            }
+
+            if self.len >= self.max_len {
+                return Err(Error::BlockOverflow {
+                    block: self.index,
+                    bit_offset: pos,
+                });
+            }
+            if !self.tt.grow(self.len + 1) {
+                return Err(self.scratch_error(pos));
+            }
+            self.tt.as_mut()[self.len] = mtf::to_front(&mut self.mtf, sym as usize - 1) as u32;
+            self.len += 1;
+        }
+    }
+
+    fn scratch_error(&self, bit_offset: u64) -> Error {
+        Error::ScratchTooSmall {
+            level: (self.max_len / 100_000) as u32,
+            block: self.index,
+            bit_offset,
+        }
+    }
+
+    /// Links up the bytes of a completely decoded block to undo its BWT,
+    /// ready for its output to be read.
+    pub fn finish(&mut self) -> Result<()> {
+        debug_assert!(matches!(self.state, State::Done));
+
+        let orig_ptr = self.orig_ptr as usize;
+        if orig_ptr >= self.len {
+            return Err(Error::OrigPtrOutOfRange {
+                orig_ptr: self.orig_ptr,
+                block: self.index,
+                bit_offset: self.start + 48 + 32 + 1,
+            });
+        }
+
+        // where each byte value's rows start in the sorted first column
+        let tt = &mut self.tt.as_mut()[..self.len];
+        let mut next = [0u32; 256];
+        for w in tt.iter() {
+            next[*w as u8 as usize] += 1;
+        }
+        let mut sum = 0;
+        for n in next.iter_mut() {
+            sum += *n;
+            *n = sum - *n;
+        }
+        for i in 0..tt.len() {
+            let b = tt[i] as u8 as usize;
+            tt[next[b] as usize] |= (i as u32) << 8;
+            next[b] += 1;
+        }
+
+        self.pos = tt[orig_ptr] >> 8;
+        self.left = self.len;
+        self.randomiser = Randomiser::new();
+        self.rle = rle::Decoder::new();
+        self.buf_pos = 0;
+        self.buf_len = 0;
+        Ok(())
+    }
+
+    /// Writes as much of a finished block's output as fits in `output`,
+    /// undoing any randomisation and the initial run-length encoding.
+    /// Returns the number of bytes written.
+    pub fn read(&mut self, output: &mut [u8]) -> usize {
+        let tt = self.tt.as_mut();
+        let mut written = 0;
+        loop {
+            if self.buf_pos == self.buf_len && self.left > 0 {
+                let n = self.left.min(self.buf.len());
+                for b in &mut self.buf[..n] {
+                    let w = tt[self.pos as usize];
+                    *b = w as u8;
+                    self.pos = w >> 8;
+                }
+                if self.randomised {
+                    self.randomiser.apply(&mut self.buf[..n]);
+                }
+                self.left -= n;
+                self.buf_pos = 0;
+                self.buf_len = n;
+            }
+
+            let (read, n) = self.rle.decode(&self.buf[self.buf_pos..self.buf_len], &mut output[written..]);
+            self.buf_pos += read;
+            written += n;
+            if written == output.len() || self.left == 0 && self.buf_pos == self.buf_len {
+                return written;
+            }
+        }
+    }
+
+    /// Whether all of a finished block's output has been read.
+    pub fn is_drained(&self) -> bool {
+        self.left == 0 && self.buf_pos == self.buf_len && self.rle.is_flushed()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::with_workspace(Vec::new())
+    }
+
+    /// Decodes the whole of block number `index`, whose magic starts at bit
+    /// `start`, in a stream with block size `level`. `data` holds the input
+    /// from byte `base` on. Returns the block's output, checked against its
+    /// CRC, and the bit offset of its end.
+    pub fn decode_at(&mut self, data: &[u8], base: u64, start: u64, index: u64, level: u32) -> Result<(Vec<u8>, u64)> {
+        let eof = Error::UnexpectedEof {
+            block: index,
+            bit_offset: (base + data.len() as u64) * 8,
+        };
+        let mut input = match (start / 8).checked_sub(base).and_then(|at| data.get(at as usize..)) {
+            Some(input) => input,
+            None => return Err(eof),
+        };
+        let mut bits = BitReader::starting_at(start / 8);
+        if !bits.fill(&mut input, 48 + (start % 8) as u32) {
+            return Err(eof);
+        }
+        bits.consume((start % 8) as u32);
+        let magic = (bits.read(16) as u64) << 32 | bits.read(32) as u64;
+        if magic != BLOCK_MAGIC {
+            return Err(Error::BadMagic {
+                block: index,
+                bit_offset: start,
+            });
+        }
+
+        self.reset(index, start, level);
+        if !self.decode(&mut bits, &mut input)? {
+            return Err(eof);
+        }
+        let end = bits.position();
+
+        self.finish()?;
+        let mut output = vec![];
+        while !self.is_drained() {
+            let at = output.len();
+            output.resize(at + self.len, 0);
+            let written = self.read(&mut output[at..]);
+            output.truncate(at + written);
+        }
+        let mut crc = Crc::new();
+        crc.update_slice(&output);
+        let actual = crc.finish();
+        if actual != self.crc {
+            return Err(Error::BlockCrcMismatch {
+                expected: self.crc,
+                actual,
+                block: index,
+                bit_offset: start + 48,
+            });
+        }
+        Ok((output, end))
+    }
+}
+
+/// The buffers blocks are encoded in, kept from one block to the next.
+/// Once they have grown to fit the largest block, encoding allocates
+/// nothing.
+#[derive(Default)]
+pub struct Encoder {
+    bwt: BwtBuffers,
+    mtfv: Vec<u8>,
+    syms: Vec<u16>,
+    selectors: Vec<u8>,
+    selector_codes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder::default()
+    }
+
+    /// Compresses one block of initial run-length encoder output, whose
+    /// input had the CRC `crc`, and appends it to `out`.
+    pub fn encode(&mut self, data: &[u8], crc: u32, out: &mut BitWriter) {
+        self.write_block(data, crc, false, false, out)
+    }
+
+    /// Like `encode`, but pads the block so that it ends on a byte boundary,
+    /// letting everything up to its end be sent without ending the stream.
+    pub fn encode_flushed(&mut self, data: &[u8], crc: u32, out: &mut BitWriter) {
+        self.write_block(data, crc, false, true, out)
+    }
+
+    // Writes a block the way bzip2 0.9.0 did for repetitive data, which
+    // modern encoders never do.
+    #[cfg(test)]
+    fn encode_randomised(&mut self, data: &[u8], crc: u32, out: &mut BitWriter) {
+        let mut data = data.to_owned();
+        Randomiser::new().apply(&mut data);
+        self.write_block(&data, crc, true, false, out)
+    }
+
+    fn write_block(&mut self, data: &[u8], crc: u32, randomised: bool, aligned: bool, out: &mut BitWriter) {
+        debug_assert!(!data.is_empty());
+
+        let orig_ptr = bwt_into(data, &mut self.bwt, &mut self.mtfv);
+
+        let mut in_use = [false; 256];
+        for &b in data {
+            in_use[b as usize] = true;
+        }
+        let mut unseq_to_seq = [0u8; 256];
+        let mut n_in_use = 0;
+        for i in 0..256 {
+            if in_use[i] {
+                unseq_to_seq[i] = n_in_use as u8;
+                n_in_use += 1;
+            }
+        }
+
+        for b in &mut self.mtfv {
+            *b = unseq_to_seq[*b as usize];
+        }
+        mtf::encode_in_place(&mut self.mtfv);
+
+        let alpha_size = n_in_use + 2;
+        let freq = symbols(&self.mtfv, n_in_use, &mut self.syms);
+        let (lens, n_groups) = make_tables(&self.syms, &freq[..alpha_size], &mut self.selectors);
+        let Encoder { ref syms, ref selectors, ref mut selector_codes, .. } = *self;
+        let mut codes = [[0u32; MAX_ALPHA_SIZE]; MAX_GROUPS];
+        for t in 0..n_groups {
+            huffman::assign_codes(&mut codes[t][..alpha_size], &lens[t][..alpha_size]);
+        }
+
+        let mut selector_mtf = [0, 1, 2, 3, 4, 5];
+        selector_codes.clear();
+        selector_codes.extend(selectors.iter().map(|&sel| {
+            let j = selector_mtf.iter().position(|s| *s == sel).unwrap_or(0);
+            selector_mtf[..j + 1].rotate_right(1);
+            j as u8
+        }));
+
+        let mut used_map = 0;
+        for i in 0..16 {
+            if in_use[i * 16..i * 16 + 16].iter().any(|u| *u) {
+                used_map |= 0x8000 >> i;
+            }
+        }
+
+        // A flushed block is padded to end on a byte boundary with fields that
+        // decoders accept and ignore: a code length that goes up and straight
+        // back down costs 4 bits, and selectors past the last group 1 or 2 bits
+        // each. Blocks are at most 18000 groups long, so the one or two extra
+        // selectors keep within the 18002 that decoders allow.
+        let mut pad = 0;
+        if aligned {
+            let mut bits = out.position() + 48 + 32 + 1 + 24 + 16 + 3 + 15;
+            bits += 16 * (used_map as u16).count_ones() as u64;
+            bits += selector_codes.iter().map(|&j| j as u64 + 1).sum::<u64>();
+            for table in &lens[..n_groups] {
+                bits += 5;
+                let mut curr = table[0];
+                for &len in &table[..alpha_size] {
+                    bits += 2 * (len as i32 - curr as i32).unsigned_abs() as u64 + 1;
+                    curr = len;
+                }
+            }
+            for (group, &sel) in syms.chunks(GROUP_SIZE).zip(selectors) {
+                bits += group.iter().map(|&sym| lens[sel as usize][sym as usize] as u64).sum::<u64>();
+            }
+            pad = (8 - bits % 8) % 8;
+        }
+        match pad % 4 {
+            1 => selector_codes.push(0),
+            2 => selector_codes.push(1),
+            3 => selector_codes.extend_from_slice(&[0, 1]),
+            _ => {}
+        }
+
+        out.write_u48(BLOCK_MAGIC);
+        out.write(32, crc);
+        out.write(1, randomised as u32);
+        out.write(24, orig_ptr);
+
+        out.write(16, used_map);
+        for i in 0..16 {
+            if used_map & (0x8000 >> i) != 0 {
+                let mut map = 0;
+                for j in 0..16 {
+                    if in_use[i * 16 + j] {
+                        map |= 0x8000 >> j;
+                    }
+                }
+                out.write(16, map);
+            }
+        }
+
+        out.write(3, n_groups as u32);
+        out.write(15, selector_codes.len() as u32);
+        for &j in selector_codes.iter() {
+            for _ in 0..j {
+                out.write(1, 1);
+            }
+            out.write(1, 0);
+        }
+
+        for (t, table) in lens[..n_groups].iter().enumerate() {
+            let mut curr = table[0];
+            out.write(5, curr as u32);
+            if t == 0 && pad >= 4 {
+                out.write(4, 0b1011);
+            }
+            for &len in &table[..alpha_size] {
+                while curr < len {
+                    out.write(2, 0b10);
+                    curr += 1;
+                }
+                while curr > len {
+                    out.write(2, 0b11);
+                    curr -= 1;
+                }
+                out.write(1, 0);
+            }
+        }
+
+        for (group, &sel) in syms.chunks(GROUP_SIZE).zip(selectors) {
+            let t = sel as usize;
+            for &sym in group {
+                out.write(lens[t][sym as usize] as u32, codes[t][sym as usize]);
+            }
+        }
+        debug_assert!(!aligned || out.position().is_multiple_of(8));
+    }
+}
+
+// Replaces `syms` with the symbols that code `mtfv`, a block's MTF output
+// over `n_in_use` byte values: runs of zeros as RUNA and RUNB digits, every
+// other value one up, and the end-of-block symbol last. Returns how often
+// each occurs.
+fn symbols(mtfv: &[u8], n_in_use: usize, syms: &mut Vec<u16>) -> [u32; MAX_ALPHA_SIZE] {
+    let eob = n_in_use as u16 + 1;
+    let mut freq = [0u32; MAX_ALPHA_SIZE];
+    syms.clear();
+    let mut zeros = 0;
+
+    for &v in mtfv {
+        if v == 0 {
+            zeros += 1;
+            continue;
+        }
+        push_run(syms, &mut freq, zeros);
+        zeros = 0;
+        syms.push(v as u16 + 1);
+        freq[v as usize + 1] += 1;
+    }
+    push_run(syms, &mut freq, zeros);
+    syms.push(eob);
+    freq[eob as usize] += 1;
+    freq
+}
+
+// Emits a run of `zeros` zero MTF values as RUNA/RUNB digits, a bijective
+// base 2 numeral with the least significant digit first.
+fn push_run(syms: &mut Vec<u16>, freq: &mut [u32; MAX_ALPHA_SIZE], zeros: usize) {
+    if zeros == 0 {
+        return;
+    }
+    let mut z = zeros - 1;
+    loop {
+        let sym = if z & 1 == 1 { RUNB } else { RUNA };
+        syms.push(sym);
+        freq[sym as usize] += 1;
+        if z < 2 {
+            break;
+        }
+        z = (z - 2) / 2;
+    }
+}
+
+// Chooses the coding tables and, for every group of 50 symbols, the table to
+// code it with, into `selectors`, refining both over a few passes as
+// `sendMTFValues` does.
+fn make_tables(syms: &[u16], freq: &[u32], selectors: &mut Vec<u8>) -> ([[u8; MAX_ALPHA_SIZE]; MAX_GROUPS], usize) {
+    let alpha_size = freq.len();
+    let n_mtf = syms.len();
+    let n_groups = match n_mtf {
+        0..=199 => 2,
+        200..=599 => 3,
+        600..=1199 => 4,
+        1200..=2399 => 5,
+        _ => 6,
+    };
+
+    let mut lens = [[GREATER_ICOST; MAX_ALPHA_SIZE]; MAX_GROUPS];
+
+    // initial tables each cover a contiguous range of roughly equally
+    // frequent symbols
+    let mut n_part = n_groups;
+    let mut rem_freq = n_mtf as u32;
+    let mut gs = 0;
+    while n_part > 0 {
+        let target = rem_freq / n_part as u32;
+        let mut ge = gs;
+        let mut acc = 0;
+        while acc < target && ge < alpha_size {
+            acc += freq[ge];
+            ge += 1;
+        }
+        if ge > gs + 1 && n_part != n_groups && n_part != 1 && (n_groups - n_part) % 2 == 1 {
+            ge -= 1;
+            acc -= freq[ge];
+        }
+
+        for (v, len) in lens[n_part - 1][..alpha_size].iter_mut().enumerate() {
+            *len = if v >= gs && v < ge { LESSER_ICOST } else { GREATER_ICOST };
+        }
+
+        n_part -= 1;
+        gs = ge;
+        rem_freq -= acc;
+    }
+
+    for _ in 0..N_ITERS {
+        let mut rfreq = [[0u32; MAX_ALPHA_SIZE]; MAX_GROUPS];
+        selectors.clear();
+
+        for group in syms.chunks(GROUP_SIZE) {
+            let mut cost = [0u32; MAX_GROUPS];
+            for &sym in group {
+                for t in 0..n_groups {
+                    cost[t] += lens[t][sym as usize] as u32;
+                }
+            }
+
+            let mut best = 0;
+            for t in 1..n_groups {
+                if cost[t] < cost[best] {
+                    best = t;
+                }
+            }
+            selectors.push(best as u8);
+            for &sym in group {
+                rfreq[best][sym as usize] += 1;
+            }
+        }
+
+        for t in 0..n_groups {
+            huffman::code_lengths(&mut lens[t][..alpha_size], &rfreq[t][..alpha_size], MAX_ENCODE_LEN);
+        }
+    }
+
+    (lens, n_groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2::read::BzDecoder;
+
+    use super::*;
+    use {crc, rle, BzReader};
+
+    // Builds a stream of randomised blocks of `data`, `level` * 100k each.
+    fn randomised_stream(data: &[u8], level: u32) -> Vec<u8> {
+        let mut out = BitWriter::new();
+        out.write(24, 0x42_5a_68);
+        out.write(8, b'0' as u32 + level);
+
+        let mut block = Encoder::new();
+        let mut combined = 0;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let mut encoder = rle::Encoder::new(level as usize * 100_000 - 19);
+            rest = &rest[encoder.encode(rest)..];
+            let block_crc = encoder.block_crc();
+            block.encode_randomised(&encoder.finish(), block_crc, &mut out);
+            combined = crc::combine(combined, block_crc);
+        }
+
+        out.write_u48(EOS_MAGIC);
+        out.write(32, combined);
+        out.align();
+        out.bytes().to_owned()
+    }
+
+    fn check(data: &[u8], level: u32) {
+        let compressed = randomised_stream(data, level);
+
+        let mut reference = vec![];
+        BzDecoder::new(&compressed[..]).read_to_end(&mut reference).unwrap();
+        assert_eq!(reference, data);
+
+        let mut out = vec![];
+        BzReader::new(&compressed[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn randomised_blocks() {
+        check(b"a", 9);
+        check(b"short enough to need no flipped bits at all", 9);
+
+        let text: Vec<u8> = b"randomised blocks date from bzip2 0.9.0; ".iter().cycle().take(5000).cloned().collect();
+        check(&text, 9);
+
+        let mixed: Vec<u8> = (0..250_000u32).map(|i| if i % 7000 < 3000 { 0 } else { (i * 31 / 17) as u8 }).collect();
+        check(&mixed, 1);
+    }
+
+    // Decodes a hand-written block of a stream using the single byte value
+    // 0, with `write` emitting everything from the number of groups on.
+    fn hostile<F: Fn(&mut BitWriter)>(orig_ptr: u32, write: F) -> Error {
+        let mut out = BitWriter::new();
+        out.write(32, 0x425a_6839);
+        out.write_u48(BLOCK_MAGIC);
+        out.write(32, 0);
+        out.write(1, 0);
+        out.write(24, orig_ptr);
+        out.write(16, 0x8000);
+        out.write(16, 0x8000);
+        write(&mut out);
+        out.write(32, 0);
+        out.align();
+        Decoder::new().decode_at(out.bytes(), 0, 32, 0, 9).unwrap_err()
+    }
+
+    // Two groups and one selector, then two tables coding RUNA as 0, RUNB as
+    // 10 and the end of block as 11.
+    fn tables(out: &mut BitWriter) {
+        out.write(3, 2);
+        out.write(15, 1);
+        out.write(1, 0);
+        for _ in 0..2 {
+            out.write(5, 1);
+            out.write(1, 0);
+            out.write(3, 0b100);
+            out.write(1, 0);
+        }
+    }
+
+    #[test]
+    fn hostile_blocks() {
+        macro_rules! assert_err {
+            ($err:expr, $pattern:pat) => {
+                let err = $err;
+                assert!(matches!(err, $pattern), "unexpected error {:?}", err);
+            };
+        }
+
+        assert_err!(hostile(0, |out| out.write(3, 7)), Error::InvalidHuffmanTable { .. });
+        assert_err!(hostile(0, |out| out.write(3, 1)), Error::InvalidHuffmanTable { .. });
+        assert_err!(hostile(0, |out| { out.write(3, 2); out.write(15, 0); }), Error::SelectorOutOfRange { .. });
+        assert_err!(hostile(0, |out| { out.write(3, 2); out.write(15, 1); out.write(2, 0b11); }),
+                    Error::SelectorOutOfRange { .. });
+
+        // code lengths out of 1..=20, directly or through deltas
+        let lengths = |start: u32, deltas: &'static [u32]| move |out: &mut BitWriter| {
+            out.write(3, 2);
+            out.write(15, 1);
+            out.write(1, 0);
+            out.write(5, start);
+            for &delta in deltas {
+                out.write(2, delta);
+            }
+        };
+        assert_err!(hostile(0, lengths(0, &[])), Error::InvalidHuffmanTable { .. });
+        assert_err!(hostile(0, lengths(21, &[])), Error::InvalidHuffmanTable { .. });
+        assert_err!(hostile(0, lengths(20, &[0b10])), Error::InvalidHuffmanTable { .. });
+        assert_err!(hostile(0, lengths(1, &[0b11])), Error::InvalidHuffmanTable { .. });
+
+        // runs longer than any block, while being read and once complete
+        assert_err!(hostile(0, |out| {
+            tables(out);
+            for _ in 0..40 {
+                out.write(2, 0b10);
+            }
+        }), Error::BlockOverflow { .. });
+        assert_err!(hostile(0, |out| {
+            tables(out);
+            for _ in 0..19 {
+                out.write(2, 0b10);
+            }
+            out.write(2, 0b11);
+        }), Error::BlockOverflow { .. });
+
+        assert_err!(hostile(0, |out| { tables(out); out.write(2, 0b11); }), Error::OrigPtrOutOfRange { .. });
+        assert_err!(hostile(1, |out| { tables(out); out.write(3, 0b011); }), Error::OrigPtrOutOfRange { .. });
+        // a well-formed block of one zero byte, with the wrong CRC
+        assert_err!(hostile(0, |out| { tables(out); out.write(3, 0b011); }), Error::BlockCrcMismatch { .. });
+    }
+}
@@ -0,0 +1,141 @@
+use std::io::{self, BufRead, Read};
+
+use decompress::{Decompress, Limits, Status};
+
+/// Decompresses bzip2 data read from a `BufRead`.
+///
+/// Only the bytes that make up the compressed data are consumed from the
+/// inner reader. With `multi_stream(false)`, decoding stops right after the
+/// end-of-stream marker and its padding, leaving the inner reader positioned
+/// at whatever follows, as container formats such as ZIP need.
+pub struct BzReader<R> {
+    inner: R,
+    decompress: Decompress,
+    done: bool,
+}
+
+impl<R: BufRead> BzReader<R> {
+    pub fn new(inner: R) -> Self {
+        BzReader {
+            inner,
+            decompress: Decompress::new(),
+            done: false,
+        }
+    }
+
+    /// Sets whether to go on decoding streams that follow the first one.
+    pub fn multi_stream(mut self, multi_stream: bool) -> Self {
+        self.decompress.set_multi_stream(multi_stream);
+        self
+    }
+
+    /// Sets the limits past which reading fails rather than produce more
+    /// output.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.decompress.set_limits(limits);
+        self
+    }
+}
+
+impl<R> BzReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The number of compressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.decompress.total_in()
+    }
+
+    /// The number of decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.decompress.total_out()
+    }
+}
+
+impl<R: BufRead> Read for BzReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.done || out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let (consumed, produced, status, eof) = {
+                let input = self.inner.fill_buf()?;
+                let (consumed, produced, status) = self.decompress.decompress(input, out)?;
+                (consumed, produced, status, input.is_empty())
+            };
+            self.inner.consume(consumed);
+
+            if status == Status::StreamEnd && (eof || !self.decompress.multi_stream()) {
+                self.done = true;
+                return Ok(produced);
+            }
+            if produced > 0 {
+                return Ok(produced);
+            }
+            if eof {
+                return Err(self.decompress.eof_error().into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::BufReader;
+
+    use bzip2::Compression;
+    use bzip2::write::BzEncoder;
+
+    use super::*;
+
+    fn reference(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::Best);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn stops_after_embedded_stream() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut container = reference(&data);
+        let len = container.len();
+        container.extend(b"PK\x01\x02central directory");
+
+        let mut reader = BzReader::new(&container[..]).multi_stream(false);
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(reader.total_in(), len as u64);
+        assert_eq!(reader.into_inner(), b"PK\x01\x02central directory");
+    }
+
+    #[test]
+    fn reads_through_tiny_buffers() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8 ^ (i / 1000) as u8).collect();
+        let mut container = reference(&data);
+        container.extend(b"tail");
+
+        let mut reader = BzReader::new(BufReader::with_capacity(1, &container[..])).multi_stream(false);
+        let mut out = vec![];
+        let mut byte = [0];
+        while reader.read(&mut byte).unwrap() == 1 {
+            out.push(byte[0]);
+        }
+        assert_eq!(out, data);
+
+        let mut rest = vec![];
+        reader.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"tail");
+    }
+}
@@ -0,0 +1,218 @@
+//! `bzip2::bufread`: encoders and decoders reading from a `BufRead`.
+
+use std::io::{self, BufRead, Read, Write};
+
+use bufread::BzReader;
+use compress::{Action, Compress};
+use decompress::Status;
+
+use super::Compression;
+
+/// Compresses the data read from `R`, reading out the compressed stream.
+pub struct BzEncoder<R> {
+    inner: R,
+    compress: Compress,
+    done: bool,
+}
+
+/// Decompresses the single bzip2 stream read from `R`, leaving whatever
+/// follows it unread.
+pub struct BzDecoder<R> {
+    inner: BzReader<R>,
+}
+
+/// Decompresses every bzip2 stream read from `R`, one after another, as
+/// `pbzip2` and Wikipedia's dumps need.
+pub struct MultiBzDecoder<R> {
+    inner: BzReader<R>,
+}
+
+impl<R: BufRead> BzEncoder<R> {
+    pub fn new(r: R, level: Compression) -> BzEncoder<R> {
+        BzEncoder {
+            inner: r,
+            compress: Compress::new(level.level()),
+            done: false,
+        }
+    }
+}
+
+impl<R> BzEncoder<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The number of compressed bytes read out so far.
+    pub fn total_out(&self) -> u64 {
+        self.compress.total_out()
+    }
+
+    /// The number of uncompressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.compress.total_in()
+    }
+}
+
+impl<R: BufRead> Read for BzEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let (consumed, produced, status) = {
+                let input = self.inner.fill_buf()?;
+                let action = if input.is_empty() { Action::Finish } else { Action::Run };
+                self.compress.compress(input, buf, action)
+            };
+            self.inner.consume(consumed);
+
+            self.done = status == Status::StreamEnd;
+            // nothing is produced until a block fills up or the input ends
+            if produced > 0 || self.done {
+                return Ok(produced);
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for BzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl<R: BufRead> BzDecoder<R> {
+    pub fn new(r: R) -> BzDecoder<R> {
+        BzDecoder {
+            inner: BzReader::new(r).multi_stream(false),
+        }
+    }
+}
+
+impl<R> BzDecoder<R> {
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+
+    /// The number of compressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// The number of decompressed bytes read out so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+impl<R: BufRead> Read for BzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<W: Write> Write for BzDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl<R: BufRead> MultiBzDecoder<R> {
+    pub fn new(r: R) -> MultiBzDecoder<R> {
+        MultiBzDecoder {
+            inner: BzReader::new(r),
+        }
+    }
+}
+
+impl<R> MultiBzDecoder<R> {
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: BufRead> Read for MultiBzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: BufRead + Write> Write for MultiBzDecoder<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2;
+
+    use super::*;
+
+    #[test]
+    fn decoders_stop_at_the_end_of_the_stream_or_input() {
+        let mut input = vec![];
+        let mut ends = vec![];
+        for part in &[&b"first "[..], b"second"] {
+            let mut encoder = BzEncoder::new(*part, Compression::best());
+            encoder.read_to_end(&mut input).unwrap();
+            assert_eq!(encoder.total_in(), part.len() as u64);
+            ends.push(input.len());
+        }
+        let first = ends[0];
+
+        let mut decoder = BzDecoder::new(&input[..]);
+        let mut out = String::new();
+        decoder.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "first ");
+        assert_eq!(decoder.total_in(), first as u64);
+        assert_eq!(decoder.into_inner(), &input[first..]);
+
+        let mut out = String::new();
+        MultiBzDecoder::new(&input[..]).read_to_string(&mut out).unwrap();
+        assert_eq!(out, "first second");
+
+        // the same stream as the `bzip2` crate decodes
+        let mut out = String::new();
+        bzip2::bufread::BzDecoder::new(&input[..first]).read_to_string(&mut out).unwrap();
+        assert_eq!(out, "first ");
+    }
+}
@@ -0,0 +1,360 @@
+//! The API of the `bzip2` crate, implemented with this one.
+//!
+//! The types here have the names and signatures of those in `bzip2` 0.4, so
+//! that code using it can move over by changing `bzip2::` to
+//! `bzip2_rs::compat::` in its `use`s. Where they differ:
+//!
+//! - `work_factor` is checked, then ignored, as is `small`.
+//! - The `read`, `bufread` and `write` adapters fail with this crate's
+//!   `Error`, which says which block was bad and where, rather than
+//!   `compat::Error`.
+//! - `Compress` does not insist that a flush or finish is repeated with the
+//!   same input, only with the same action.
+//! - A `write::BzDecoder` finished before the end of its stream fails
+//!   rather than wait for input forever.
+
+pub mod bufread;
+pub mod read;
+pub mod write;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use compress;
+use decompress;
+use error::Error as DecodeError;
+
+pub use compress::Action;
+
+/// A block size level, from `1` for 100k blocks to `9` for 900k.
+#[derive(Copy, Clone, Debug)]
+pub struct Compression(u32);
+
+impl Compression {
+    /// Creates a level from its number. Only `1..=9` can be compressed with.
+    pub fn new(level: u32) -> Compression {
+        Compression(level)
+    }
+
+    /// Level 0, which the `bzip2` crate has but cannot compress with either.
+    pub fn none() -> Compression {
+        Compression(0)
+    }
+
+    /// The smallest blocks, which are the quickest to sort.
+    pub fn fast() -> Compression {
+        Compression(1)
+    }
+
+    /// The largest blocks, which compress best.
+    pub fn best() -> Compression {
+        Compression(9)
+    }
+
+    pub fn level(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression(6)
+    }
+}
+
+/// The outcome of a call to `Compress::compress` or `Decompress::decompress`,
+/// named as libbz2 names its return codes.
+#[derive(PartialEq, Eq, Copy, Debug, Clone)]
+pub enum Status {
+    /// Decoding went fine, with more to come.
+    Ok,
+    /// A flush is under way, and needs calling again to complete.
+    FlushOk,
+    /// Input was taken, or a flush completed.
+    RunOk,
+    /// The stream is being finished, and needs calling again to complete.
+    FinishOk,
+    /// The end of the stream has been reached.
+    StreamEnd,
+    /// Never returned here: libbz2 reports running out of memory with it.
+    MemNeeded,
+}
+
+/// The errors `Compress` and `Decompress` report, as libbz2 reports them.
+#[derive(PartialEq, Eq, Copy, Debug, Clone)]
+pub enum Error {
+    /// The call does not follow on from the ones before it, such as a `Run`
+    /// during a flush, or any call once the stream has ended.
+    Sequence,
+    /// The data being decoded is not valid bzip2.
+    Data,
+    /// The data being decoded does not start with a bzip2 stream header.
+    DataMagic,
+    /// The parameters are invalid.
+    Param,
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Sequence => "bzip2: sequence of operations invalid",
+            Error::Data => "bzip2: invalid data",
+            Error::DataMagic => "bzip2: bz2 header missing",
+            Error::Param => "bzip2: invalid parameters",
+        })
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::other(err)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Error {
+        match err {
+            DecodeError::BadMagic { bit_offset: 0, .. } | DecodeError::UnsupportedBlockSize { .. } => {
+                Error::DataMagic
+            }
+            _ => Error::Data,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Running,
+    Flushing,
+    Finishing,
+    Idle,
+}
+
+/// The `bzip2` crate's raw encoder, with libbz2's rules on which action may
+/// follow which.
+pub struct Compress {
+    inner: compress::Compress,
+    mode: Mode,
+}
+
+impl Compress {
+    /// Creates an encoder at `lvl`, which must be `1..=9`. `work_factor`
+    /// must be `0..=250`.
+    pub fn new(lvl: Compression, work_factor: u32) -> Compress {
+        assert!(work_factor <= 250, "work factor must be 0..=250, got {}", work_factor);
+        Compress {
+            inner: compress::Compress::new(lvl.level()),
+            mode: Mode::Running,
+        }
+    }
+
+    /// Encodes from `input` into `output`.
+    ///
+    /// Once a `Flush` or `Finish` has been asked for, the same action must
+    /// be repeated until it completes: a flush with `RunOk`, a finish with
+    /// `StreamEnd`. Anything else is a `Sequence` error, as is any call
+    /// after the end of the stream.
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8], action: Action) -> Result<Status, Error> {
+        if input.is_empty() && action == Action::Run {
+            return Ok(Status::RunOk);
+        }
+        match (self.mode, action) {
+            (Mode::Running, Action::Flush) => self.mode = Mode::Flushing,
+            (Mode::Running, Action::Finish) => self.mode = Mode::Finishing,
+            (Mode::Running, Action::Run) | (Mode::Flushing, Action::Flush) | (Mode::Finishing, Action::Finish) => {}
+            _ => return Err(Error::Sequence),
+        }
+
+        let (_, _, status) = self.inner.compress(input, output, action);
+        Ok(match action {
+            Action::Run => Status::RunOk,
+            Action::Flush if status == decompress::Status::Flushed => {
+                self.mode = Mode::Running;
+                Status::RunOk
+            }
+            Action::Flush => Status::FlushOk,
+            Action::Finish if status == decompress::Status::StreamEnd => {
+                self.mode = Mode::Idle;
+                Status::StreamEnd
+            }
+            Action::Finish => Status::FinishOk,
+        })
+    }
+
+    /// Like `compress`, encoding into the spare capacity of `output`, which
+    /// is never grown.
+    pub fn compress_vec(&mut self, input: &[u8], output: &mut Vec<u8>, action: Action) -> Result<Status, Error> {
+        let len = output.len();
+        output.resize(output.capacity(), 0);
+        let before = self.total_out();
+        let status = self.compress(input, &mut output[len..], action);
+        output.truncate(len + (self.total_out() - before) as usize);
+        status
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+/// The `bzip2` crate's raw decoder, which decodes a single stream.
+pub struct Decompress {
+    inner: decompress::Decompress,
+    done: bool,
+}
+
+impl Decompress {
+    /// Creates a decoder. `small` asks libbz2 for its slower decoder that
+    /// needs less memory, and makes no difference here.
+    pub fn new(small: bool) -> Decompress {
+        let _ = small;
+        let mut inner = decompress::Decompress::new();
+        inner.set_multi_stream(false);
+        Decompress { inner, done: false }
+    }
+
+    /// Decodes from `input` into `output`, returning `StreamEnd` once the
+    /// end of the stream is reached and `Ok` until then. Calls after that
+    /// fail with `Sequence`.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<Status, Error> {
+        if self.done {
+            return Err(Error::Sequence);
+        }
+        match self.inner.decompress(input, output)? {
+            (_, _, decompress::Status::StreamEnd) => {
+                self.done = true;
+                Ok(Status::StreamEnd)
+            }
+            _ => Ok(Status::Ok),
+        }
+    }
+
+    /// Like `decompress`, decoding into the spare capacity of `output`,
+    /// which is never grown.
+    pub fn decompress_vec(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<Status, Error> {
+        let len = output.len();
+        output.resize(output.capacity(), 0);
+        let before = self.total_out();
+        let status = self.decompress(input, &mut output[len..]);
+        output.truncate(len + (self.total_out() - before) as usize);
+        status
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2;
+
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 4321u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            if (x >> 16).is_multiple_of(3) { (x >> 8) as u8 } else { (i / 300) as u8 }
+        }).collect()
+    }
+
+    #[test]
+    fn compress_follows_libbz2s_sequence() {
+        let data = sample(250_000);
+        let mut compress = Compress::new(Compression::fast(), 30);
+        let mut out = Vec::with_capacity(1000);
+
+        let mut input = &data[..100_000];
+        while !input.is_empty() {
+            out.reserve_exact(1000);
+            let before = compress.total_in();
+            assert_eq!(compress.compress_vec(input, &mut out, Action::Run), Ok(Status::RunOk));
+            input = &input[(compress.total_in() - before) as usize..];
+        }
+        assert_eq!(compress.compress(&[], &mut [], Action::Run), Ok(Status::RunOk));
+
+        // a flush needs repeating until it completes with RunOk
+        let mut flushes = 0;
+        loop {
+            out.reserve_exact(1000);
+            match compress.compress_vec(&[], &mut out, Action::Flush).unwrap() {
+                Status::FlushOk => flushes += 1,
+                Status::RunOk => break,
+                status => panic!("unexpected {:?}", status),
+            }
+            assert_eq!(compress.compress(&data, &mut [], Action::Run), Err(Error::Sequence));
+        }
+        assert!(flushes > 0);
+
+        // everything up to the flush can be decoded straight away
+        let mut flushed = vec![0; 100_000];
+        let mut decompress = decompress::Decompress::new();
+        let (_, produced, _) = decompress.decompress(&out, &mut flushed).unwrap();
+        assert_eq!(produced, 100_000);
+        assert_eq!(flushed, &data[..100_000]);
+
+        let mut input = &data[100_000..];
+        loop {
+            out.reserve_exact(1000);
+            let before = compress.total_in();
+            let status = compress.compress_vec(input, &mut out, Action::Finish).unwrap();
+            input = &input[(compress.total_in() - before) as usize..];
+            if status == Status::StreamEnd {
+                break;
+            }
+            assert_eq!(status, Status::FinishOk);
+            assert_eq!(compress.compress(&[], &mut [], Action::Flush), Err(Error::Sequence));
+        }
+        assert!(input.is_empty());
+        assert_eq!(compress.compress(&[], &mut [0; 10], Action::Finish), Err(Error::Sequence));
+        assert_eq!(compress.total_out(), out.len() as u64);
+
+        let mut decoded = vec![];
+        bzip2::read::BzDecoder::new(&out[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn decompress_reports_as_libbz2_does() {
+        let data = sample(50_000);
+        let mut compressed = vec![];
+        bzip2::write::BzEncoder::new(&mut compressed, bzip2::Compression::Best).write_all(&data).unwrap();
+        compressed.extend(b"after");
+
+        let mut decompress = Decompress::new(false);
+        let mut out = Vec::with_capacity(1000);
+        let mut input = &compressed[..];
+        loop {
+            let before = decompress.total_in();
+            let status = decompress.decompress_vec(input, &mut out).unwrap();
+            input = &input[(decompress.total_in() - before) as usize..];
+            if status == Status::StreamEnd {
+                break;
+            }
+            assert_eq!(status, Status::Ok);
+            out.reserve_exact(1000);
+        }
+        assert_eq!(out, data);
+        assert_eq!(input, b"after");
+        assert_eq!(decompress.decompress(input, &mut [0; 10]), Err(Error::Sequence));
+
+        let mut decompress = Decompress::new(true);
+        assert_eq!(decompress.decompress(b"PK\x03\x04", &mut [0; 10]), Err(Error::DataMagic));
+        let mut decompress = Decompress::new(false);
+        assert_eq!(decompress.decompress(b"BZh91AY&SX", &mut [0; 10]), Err(Error::Data));
+    }
+}
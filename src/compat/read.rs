@@ -0,0 +1,198 @@
+//! `bzip2::read`: encoders and decoders reading from a `Read`.
+
+use std::io::{self, BufReader, Read, Write};
+
+use read::BzReader;
+
+use super::{bufread, Compression};
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Compresses the data read from `R`, reading out the compressed stream.
+pub struct BzEncoder<R> {
+    inner: bufread::BzEncoder<BufReader<R>>,
+}
+
+/// Decompresses the single bzip2 stream read from `R`.
+///
+/// Input is read ahead into a buffer, so whatever follows the stream may
+/// have been read from `R` too.
+pub struct BzDecoder<R> {
+    inner: BzReader<R>,
+}
+
+/// Decompresses every bzip2 stream read from `R`, one after another.
+pub struct MultiBzDecoder<R> {
+    inner: BzReader<R>,
+}
+
+impl<R: Read> BzEncoder<R> {
+    pub fn new(r: R, level: Compression) -> BzEncoder<R> {
+        BzEncoder {
+            inner: bufread::BzEncoder::new(BufReader::with_capacity(BUF_SIZE, r), level),
+        }
+    }
+}
+
+impl<R> BzEncoder<R> {
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref().get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut().get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner().into_inner()
+    }
+
+    /// The number of compressed bytes read out so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
+    /// The number of uncompressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+}
+
+impl<R: Read> Read for BzEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<W: Write + Read> Write for BzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl<R: Read> BzDecoder<R> {
+    pub fn new(r: R) -> BzDecoder<R> {
+        BzDecoder {
+            inner: BzReader::new(r).multi_stream(false),
+        }
+    }
+}
+
+impl<R> BzDecoder<R> {
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+
+    /// The number of decompressed bytes read out so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
+    /// The number of compressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+}
+
+impl<R: Read> Read for BzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<W: Write + Read> Write for BzDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl<R: Read> MultiBzDecoder<R> {
+    pub fn new(r: R) -> MultiBzDecoder<R> {
+        MultiBzDecoder {
+            inner: BzReader::new(r),
+        }
+    }
+}
+
+impl<R> MultiBzDecoder<R> {
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: Read> Read for MultiBzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read + Write> Write for MultiBzDecoder<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2;
+    use proptest::string::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn round_trips_with_bzip2(ref data in bytes_regex(".*").unwrap(), level in 1..10u32) {
+            let mut compressed = vec![];
+            let mut encoder = BzEncoder::new(&data[..], Compression::new(level));
+            encoder.read_to_end(&mut compressed).unwrap();
+            prop_assert_eq!(encoder.total_in(), data.len() as u64);
+            prop_assert_eq!(encoder.total_out(), compressed.len() as u64);
+
+            let mut decoded = vec![];
+            bzip2::read::BzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+            prop_assert_eq!(&decoded, data);
+
+            let mut reference = vec![];
+            bzip2::read::BzEncoder::new(&data[..], bzip2::Compression::Fastest).read_to_end(&mut reference).unwrap();
+            reference.extend(&compressed);
+            let mut decoded = vec![];
+            MultiBzDecoder::new(&reference[..]).read_to_end(&mut decoded).unwrap();
+            prop_assert_eq!(&decoded[..data.len()], &data[..]);
+            prop_assert_eq!(&decoded[data.len()..], &data[..]);
+
+            let mut decoded = vec![];
+            BzDecoder::new(&reference[..]).read_to_end(&mut decoded).unwrap();
+            prop_assert_eq!(&decoded, data);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! `bzip2::write`: encoders and decoders writing to a `Write`.
+
+use std::io::{self, Read, Write};
+
+use decompress::{Decompress, Status};
+use write::BzWriter;
+
+use super::Compression;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Compresses everything written to it into a bzip2 stream written to `W`.
+pub struct BzEncoder<W: Write> {
+    inner: BzWriter<W>,
+    done: bool,
+}
+
+/// Decompresses the bzip2 stream written to it, writing the data to `W`.
+pub struct BzDecoder<W: Write> {
+    inner: Option<W>,
+    decompress: Decompress,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<W: Write> BzEncoder<W> {
+    pub fn new(obj: W, level: Compression) -> BzEncoder<W> {
+        BzEncoder {
+            inner: BzWriter::new(obj, level.level()),
+            done: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut()
+    }
+
+    /// Ends the stream and writes out everything still buffered, without
+    /// giving up the inner writer.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.inner.try_finish()?;
+        self.done = true;
+        Ok(())
+    }
+
+    /// Ends the stream and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.inner.finish()
+    }
+
+    /// The number of compressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
+    /// The number of uncompressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+}
+
+impl<W: Write> Write for BzEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner.write(data)
+    }
+
+    /// Ends the current block, as the `bzip2` crate does, so that everything
+    /// written so far can be decoded from the output.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.done {
+            return self.inner.flush();
+        }
+        self.inner.flush_block()
+    }
+}
+
+impl<W: Read + Write> Read for BzEncoder<W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_mut().read(buf)
+    }
+}
+
+impl<W: Write> BzDecoder<W> {
+    pub fn new(obj: W) -> BzDecoder<W> {
+        let mut decompress = Decompress::new();
+        decompress.set_multi_stream(false);
+        BzDecoder {
+            inner: Some(obj),
+            decompress,
+            buf: Vec::with_capacity(BUF_SIZE),
+            done: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Decodes and writes out whatever is left of the stream, failing if
+    /// not all of it has been written in.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        while !self.done {
+            self.decode(&[])?;
+            if !self.done && self.buf.is_empty() {
+                return Err(self.decompress.eof_error().into());
+            }
+        }
+        self.dump()
+    }
+
+    /// Finishes decoding and returns the inner writer.
+    pub fn finish(&mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    /// The number of decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.decompress.total_out()
+    }
+
+    /// The number of compressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.decompress.total_in()
+    }
+
+    fn dump(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            let n = self.inner.as_mut().unwrap().write(&self.buf)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        loop {
+            self.dump()?;
+            self.buf.resize(BUF_SIZE, 0);
+            let result = self.decompress.decompress(data, &mut self.buf);
+            let (consumed, produced, status) = result.inspect_err(|_| self.buf.clear())?;
+            self.buf.truncate(produced);
+
+            self.done = status == Status::StreamEnd;
+            if consumed > 0 || data.is_empty() || self.done {
+                return Ok(consumed);
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for BzDecoder<W> {
+    /// Decodes from `data`, taking none of it once the end of the stream has
+    /// been reached.
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.decode(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.dump()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Read + Write> Read for BzDecoder<W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_mut().read(buf)
+    }
+}
+
+impl<W: Write> Drop for BzDecoder<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2;
+
+    use super::*;
+    use Error;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 2468u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            if (x >> 16).is_multiple_of(4) { (x >> 8) as u8 } else { (i / 500) as u8 }
+        }).collect()
+    }
+
+    #[test]
+    fn encoder_flushes_blocks() {
+        let data = sample(120_000);
+        let mut encoder = BzEncoder::new(vec![], Compression::fast());
+        encoder.write_all(&data[..70_000]).unwrap();
+        encoder.flush().unwrap();
+
+        let mut flushed = vec![0; 70_000];
+        let (_, produced, _) = Decompress::new().decompress(encoder.get_ref(), &mut flushed).unwrap();
+        assert_eq!(produced, 70_000);
+        assert_eq!(flushed, &data[..70_000]);
+
+        encoder.write_all(&data[70_000..]).unwrap();
+        encoder.try_finish().unwrap();
+        encoder.flush().unwrap();
+        assert_eq!(encoder.total_in(), data.len() as u64);
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoded = vec![];
+        bzip2::read::BzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn decoder_takes_one_stream_in_pieces() {
+        let data = sample(300_000);
+        let mut compressed = vec![];
+        bzip2::write::BzEncoder::new(&mut compressed, bzip2::Compression::Fastest).write_all(&data).unwrap();
+        let len = compressed.len();
+        compressed.extend(b"trailer");
+
+        let mut decoder = BzDecoder::new(vec![]);
+        let mut taken = 0;
+        for mut chunk in compressed.chunks(777) {
+            while !chunk.is_empty() {
+                let n = decoder.write(chunk).unwrap();
+                if n == 0 {
+                    break;
+                }
+                taken += n;
+                chunk = &chunk[n..];
+            }
+        }
+        assert_eq!(taken, len);
+        assert_eq!(decoder.write(b"more").unwrap(), 0);
+        assert_eq!(decoder.total_in(), len as u64);
+        assert_eq!(decoder.finish().unwrap(), data);
+
+        let mut decoder = BzDecoder::new(vec![]);
+        decoder.write_all(&compressed[..len - 20]).unwrap();
+        let err = decoder.try_finish().unwrap_err();
+        assert!(matches!(*err.into_inner().unwrap().downcast::<Error>().unwrap(), Error::UnexpectedEof { .. }));
+    }
+}
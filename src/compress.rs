@@ -0,0 +1,330 @@
+use bits::BitWriter;
+use block::{self, EOS_MAGIC};
+use crc;
+use decompress::Status;
+use index::{Entry, Index};
+use rle;
+
+/// What `Compress::compress` should do once it has taken all of its input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Keep accumulating input into the current block.
+    Run,
+    /// End the current block, padded to end on a byte boundary, so that
+    /// everything passed in so far can be decoded from the output.
+    Flush,
+    /// End the stream.
+    Finish,
+}
+
+/// Incremental bzip2 stream encoder, the counterpart of `Decompress`.
+///
+/// The caller passes in whatever input and output space it has and is told
+/// how much of each was used, so the encoder never blocks. The working
+/// space of a block is allocated as the first block is encoded and kept for
+/// every block after it, including those of later streams started with
+/// `reset`, so memory is never allocated just because of a call. The one
+/// exception is `index`, which grows by an entry per block when it is
+/// recorded.
+pub struct Compress {
+    level: u32,
+    rle: rle::Encoder,
+    block: block::Encoder,
+    out: BitWriter,
+    out_pos: usize,
+    combined: u32,
+    record_index: bool,
+    index: Index,
+    // uncompressed bytes taken into the current block
+    block_in: u64,
+    started: bool,
+    finished: bool,
+    total_in: u64,
+    total_out: u64,
+}
+
+impl Compress {
+    /// Creates an encoder using blocks of `level` * 100k bytes, for `level`
+    /// in `1..=9`.
+    pub fn new(level: u32) -> Self {
+        assert!((1..=9).contains(&level), "block size level must be 1..=9, got {}", level);
+        Compress {
+            level,
+            rle: rle::Encoder::new(block_size(level)),
+            block: block::Encoder::new(),
+            out: BitWriter::new(),
+            out_pos: 0,
+            combined: 0,
+            record_index: false,
+            index: Index::new(),
+            block_in: 0,
+            started: false,
+            finished: false,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    /// Discards the stream being encoded, and any of it not yet output, and
+    /// starts a new one at the same level, keeping the working space.
+    pub fn reset(&mut self) {
+        self.rle.reset();
+        self.out.clear();
+        self.out_pos = 0;
+        self.combined = 0;
+        self.index.clear();
+        self.block_in = 0;
+        self.started = false;
+        self.finished = false;
+        self.total_in = 0;
+        self.total_out = 0;
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Sets whether to record the position of every block in `index`, which
+    /// is off by default. It can only be changed before the stream takes any
+    /// input.
+    pub fn set_record_index(&mut self, record_index: bool) {
+        assert!(self.total_in == 0, "the index can only be switched before the stream takes input");
+        self.record_index = record_index;
+    }
+
+    pub fn record_index(&self) -> bool {
+        self.record_index
+    }
+
+    /// The positions of the blocks written so far, if they are being
+    /// recorded, and empty otherwise.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Encodes from `input` into `output`, returning the number of bytes
+    /// consumed and produced. `action` takes effect once all of `input` has
+    /// been consumed.
+    ///
+    /// The status is `Flushed` once a flush is complete, `StreamEnd` once the
+    /// stream has been finished and all of it produced, and `Ok` otherwise:
+    /// in particular, an unfinished flush or finish needs the call repeated
+    /// with more output space.
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8], action: Action) -> (usize, usize, Status) {
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        let status = loop {
+            let pending = &self.out.bytes()[self.out_pos..];
+            let n = pending.len().min(output.len() - produced);
+            output[produced..produced + n].copy_from_slice(&pending[..n]);
+            produced += n;
+            self.out_pos += n;
+            if self.out_pos < self.out.bytes().len() {
+                break Status::Ok;
+            }
+            self.out.drain(self.out_pos);
+            self.out_pos = 0;
+
+            if self.finished {
+                break Status::StreamEnd;
+            }
+
+            if !self.started {
+                self.out.write(24, 0x42_5a_68);
+                self.out.write(8, b'0' as u32 + self.level);
+                self.started = true;
+                continue;
+            }
+
+            if consumed < input.len() {
+                let n = self.rle.encode(&input[consumed..]);
+                consumed += n;
+                self.block_in += n as u64;
+                if consumed < input.len() {
+                    // the block is full
+                    self.end_block(false);
+                    continue;
+                }
+            }
+
+            match action {
+                Action::Run => break Status::Ok,
+                Action::Flush => {
+                    if self.rle.is_empty() {
+                        // a full block is only ended once there is input
+                        // for the next one, so nothing is left unaligned
+                        debug_assert!(self.out.position().is_multiple_of(8));
+                        break Status::Flushed;
+                    }
+                    self.end_block(true);
+                }
+                Action::Finish => {
+                    self.end_block(false);
+                    self.out.write_u48(EOS_MAGIC);
+                    self.out.write(32, self.combined);
+                    self.out.align();
+                    self.finished = true;
+                }
+            }
+        };
+
+        self.total_in += consumed as u64;
+        self.total_out += produced as u64;
+        (consumed, produced, status)
+    }
+
+    // Ends the current block, if it has any data. A flushed block ends on a
+    // byte boundary so that all of it can be output straight away.
+    fn end_block(&mut self, flush: bool) {
+        if self.rle.is_empty() {
+            return;
+        }
+        let crc = self.rle.block_crc();
+        let start = self.out.position();
+        let data = self.rle.finish_block();
+        if flush {
+            self.block.encode_flushed(data, crc, &mut self.out);
+        } else {
+            self.block.encode(data, crc, &mut self.out);
+        }
+        self.rle.reset();
+        self.combined = crc::combine(self.combined, crc);
+
+        if self.record_index {
+            let offset = self.index.len();
+            self.index.push(Entry {
+                bits: start..self.out.position(),
+                bytes: offset..offset + self.block_in,
+                level: self.level,
+                crc,
+            });
+        }
+        self.block_in = 0;
+    }
+}
+
+// Leaves room for the few bytes the initial run-length encoder can emit past
+// its limit, so that a block never exceeds `level` * 100k.
+pub fn block_size(level: u32) -> usize {
+    level as usize * 100_000 - 19
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2::read::BzDecoder;
+
+    use super::*;
+    use decompress::Decompress;
+
+    // Drives `compress` with input and output slices of at most `step` bytes.
+    fn compress(compress: &mut Compress, mut input: &[u8], action: Action, step: usize, out: &mut Vec<u8>) -> Status {
+        let mut buf = vec![0; step];
+        loop {
+            let chunk = &input[..input.len().min(step)];
+            let (consumed, produced, status) = compress.compress(chunk, &mut buf, action);
+            out.extend_from_slice(&buf[..produced]);
+            input = &input[consumed..];
+            if input.is_empty() && (status != Status::Ok || action == Action::Run) {
+                return status;
+            }
+        }
+    }
+
+    fn decompress(mut input: &[u8], step: usize) -> Vec<u8> {
+        let mut decompress = Decompress::new();
+        let mut buf = vec![0; step];
+        let mut out = vec![];
+        loop {
+            let chunk = &input[..input.len().min(step)];
+            let (consumed, produced, status) = decompress.decompress(chunk, &mut buf).unwrap();
+            out.extend_from_slice(&buf[..produced]);
+            input = &input[consumed..];
+            if status == Status::StreamEnd && input.is_empty() {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i * i / 1000) as u8).collect();
+        let mut encoder = Compress::new(1);
+        let mut compressed = vec![];
+        assert_eq!(compress(&mut encoder, &data, Action::Run, 1, &mut compressed), Status::Ok);
+        assert_eq!(compress(&mut encoder, &[], Action::Finish, 1, &mut compressed), Status::StreamEnd);
+        assert_eq!(encoder.total_in(), data.len() as u64);
+        assert_eq!(encoder.total_out(), compressed.len() as u64);
+        assert!(encoder.index().is_empty());
+
+        let mut reference = vec![];
+        BzDecoder::new(&compressed[..]).read_to_end(&mut reference).unwrap();
+        assert_eq!(reference, data);
+        assert_eq!(decompress(&compressed, 1), data);
+
+        // nothing more happens once the stream is finished
+        assert_eq!(encoder.compress(b"more", &mut [0; 10], Action::Run), (0, 0, Status::StreamEnd));
+    }
+
+    #[test]
+    fn flush_ends_the_block() {
+        let mut encoder = Compress::new(9);
+        let mut compressed = vec![];
+        assert_eq!(compress(&mut encoder, b"first part, ", Action::Flush, 3, &mut compressed), Status::Flushed);
+        let flushed = compressed.len();
+        assert!(flushed > 4);
+
+        // flushing again with nothing new is a no-op
+        assert_eq!(compress(&mut encoder, b"", Action::Flush, 3, &mut compressed), Status::Flushed);
+        assert_eq!(compressed.len(), flushed);
+
+        compress(&mut encoder, b"second part", Action::Run, 5, &mut compressed);
+        assert_eq!(compress(&mut encoder, b"", Action::Finish, 5, &mut compressed), Status::StreamEnd);
+
+        let mut reference = vec![];
+        BzDecoder::new(&compressed[..]).read_to_end(&mut reference).unwrap();
+        assert_eq!(reference, b"first part, second part");
+        assert_eq!(decompress(&compressed, 7), b"first part, second part");
+    }
+
+    #[test]
+    fn reset_starts_a_new_stream() {
+        // blocks of different sizes, so that anything left over from a
+        // larger one would show in a smaller one
+        let data: Vec<u8> = (0..250_000u32).map(|i| (i.wrapping_mul(i) >> 13) as u8).collect();
+        let mut encoder = Compress::new(1);
+        encoder.set_record_index(true);
+        let mut first = vec![];
+        compress(&mut encoder, &data, Action::Run, 4096, &mut first);
+        assert_eq!(compress(&mut encoder, &[], Action::Finish, 4096, &mut first), Status::StreamEnd);
+        let index = encoder.index().clone();
+        assert_eq!(decompress(&first, 4096), data);
+
+        // abandoned part way through a block
+        encoder.reset();
+        let mut abandoned = vec![];
+        compress(&mut encoder, &data[..150_000], Action::Run, 4096, &mut abandoned);
+        assert!(encoder.total_in() > 0);
+
+        encoder.reset();
+        assert_eq!((encoder.total_in(), encoder.total_out()), (0, 0));
+        assert!(encoder.index().is_empty());
+        let mut second = vec![];
+        compress(&mut encoder, &data, Action::Run, 4096, &mut second);
+        assert_eq!(compress(&mut encoder, &[], Action::Finish, 4096, &mut second), Status::StreamEnd);
+        assert_eq!(second, first);
+        assert_eq!(encoder.index(), &index);
+
+        encoder.reset();
+        let mut third = vec![];
+        compress(&mut encoder, b"short", Action::Run, 3, &mut third);
+        assert_eq!(compress(&mut encoder, &[], Action::Finish, 3, &mut third), Status::StreamEnd);
+        assert_eq!(decompress(&third, 3), b"short");
+    }
+}
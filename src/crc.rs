@@ -0,0 +1,67 @@
+// The CRC-32 used by bzip2: polynomial 0x04c11db7, fed MSB first, with
+// inverted initial value and output.
+
+static TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = (i as u32) << 24;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 0x8000_0000 != 0 { (c << 1) ^ 0x04c1_1db7 } else { c << 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Crc(u32);
+
+impl Crc {
+    pub fn new() -> Self {
+        Crc(0xffff_ffff)
+    }
+
+    #[inline]
+    pub fn update(&mut self, byte: u8) {
+        self.0 = (self.0 << 8) ^ TABLE[((self.0 >> 24) as u8 ^ byte) as usize];
+    }
+
+    pub fn update_run(&mut self, byte: u8, len: usize) {
+        for _ in 0..len {
+            self.update(byte);
+        }
+    }
+
+    pub fn update_slice(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.update(byte);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Folds a block CRC into the combined CRC of its stream.
+pub fn combine(combined: u32, block: u32) -> u32 {
+    combined.rotate_left(1) ^ block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        let mut crc = Crc::new();
+        crc.update_slice(b"123456789");
+        assert_eq!(crc.finish(), 0xfc89_1918);
+    }
+}
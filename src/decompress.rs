@@ -0,0 +1,576 @@
+use alloc::vec::Vec;
+
+use bits::BitReader;
+use block::{self, Workspace, BLOCK_MAGIC, EOS_MAGIC};
+use crc::{self, Crc};
+use error::{Error, Result};
+use inspect::StreamInfo;
+use verify::{BlockReport, Report, StreamReport};
+
+/// The outcome of a call to `Compress::compress` or
+/// `Decompress::decompress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// More input or more output space is needed to make progress.
+    Ok,
+    /// A flush is complete: all input passed in so far has been compressed
+    /// and produced.
+    Flushed,
+    /// The end of a stream was reached and all of its output produced.
+    StreamEnd,
+}
+
+/// Bounds on what a decoder will produce, to guard against decompression
+/// bombs. Each limit is off when `None`, as all are by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The most bytes of output to produce in total.
+    pub max_output: Option<u64>,
+    /// The most bytes of output to produce per byte of input consumed.
+    pub max_ratio: Option<u64>,
+    /// The most streams to decode.
+    pub max_streams: Option<u64>,
+    /// The most blocks to decode, counted across all streams.
+    pub max_blocks: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Header,
+    Magic,
+    Block,
+    Output,
+    StreamCrc,
+    Done,
+}
+
+/// Incremental bzip2 stream decoder: input is pushed in, output is pulled
+/// out, and neither side ever has to be complete.
+///
+/// By default, input that continues past the end of a stream is decoded as
+/// a further stream, as `bzip2` does for concatenated files.
+///
+/// The decoder never blocks, and memory is only allocated to set up the
+/// working space of each block, never just because of a call.
+pub struct Decompress<W = Vec<u32>> {
+    bits: BitReader,
+    state: State,
+    multi_stream: bool,
+    limits: Limits,
+    // an error hit after producing some output, reported on the next call
+    error: Option<Error>,
+    // what has been checked so far, if asked for
+    report: Option<Report>,
+    // the internals of every block parsed, if asked for instead of output
+    inspection: Option<Vec<StreamInfo>>,
+    level: u32,
+    streams: u64,
+    blocks: u64,
+    stream_start: u64,
+    stream_blocks: u64,
+    block: block::Decoder<W>,
+    block_end: u64,
+    block_out: u64,
+    crc: Crc,
+    combined: u32,
+    total_in: u64,
+    total_out: u64,
+}
+
+/// The number of words of scratch space `decompress_into` needs to decode
+/// streams with block size `level`.
+pub fn scratch_len(level: u32) -> usize {
+    level as usize * 100_000
+}
+
+/// The working space of `decompress_into`: the words lent to it, which hold
+/// a block as it is decoded, and the state of a decoder, with its Huffman
+/// and MTF tables.
+pub struct Scratch<'a> {
+    decompress: Decompress<&'a mut [u32]>,
+}
+
+impl<'a> Scratch<'a> {
+    /// Lends `tt` to decode with. Streams with block size `level` need
+    /// `scratch_len(level)` words of it.
+    pub fn new(tt: &'a mut [u32]) -> Self {
+        Scratch {
+            decompress: Decompress::with_workspace(tt),
+        }
+    }
+}
+
+/// Decodes all of `input`, which may hold concatenated streams, into
+/// `output`, returning the number of bytes written.
+///
+/// Nothing is allocated: `output` and `scratch` are all the memory used.
+/// Fails with `Error::OutputLimitExceeded` if `output` is too small, and with
+/// `Error::ScratchTooSmall` if a block is too large for `scratch`.
+pub fn decompress_into(input: &[u8], output: &mut [u8], scratch: &mut Scratch) -> Result<usize> {
+    let decompress = &mut scratch.decompress;
+    decompress.restart();
+
+    let mut input = input;
+    let mut produced = 0;
+    loop {
+        let (consumed, written, status) = decompress.decompress(input, &mut output[produced..])?;
+        input = &input[consumed..];
+        produced += written;
+        if status == Status::StreamEnd {
+            return Ok(produced);
+        }
+        if consumed == 0 && written == 0 {
+            if let State::Output = decompress.state {
+                return Err(Error::OutputLimitExceeded {
+                    limit: output.len() as u64,
+                    block: decompress.blocks,
+                    bit_offset: decompress.bits.position(),
+                });
+            }
+            return Err(decompress.eof_error());
+        }
+    }
+}
+
+impl Default for Decompress {
+    fn default() -> Self {
+        Decompress::new()
+    }
+}
+
+impl Decompress {
+    pub fn new() -> Self {
+        Decompress::with_workspace(Vec::new())
+    }
+}
+
+impl<W: Workspace> Decompress<W> {
+    fn with_workspace(tt: W) -> Self {
+        Decompress {
+            bits: BitReader::new(),
+            state: State::Header,
+            multi_stream: true,
+            limits: Limits::default(),
+            error: None,
+            report: None,
+            inspection: None,
+            level: 0,
+            streams: 0,
+            blocks: 0,
+            stream_start: 0,
+            stream_blocks: 0,
+            block: block::Decoder::with_workspace(tt),
+            block_end: 0,
+            block_out: 0,
+            crc: Crc::new(),
+            combined: 0,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    // Starts over on new input, keeping the settings and the block decoder.
+    fn restart(&mut self) {
+        self.bits = BitReader::new();
+        self.state = State::Header;
+        self.error = None;
+        self.streams = 0;
+        self.blocks = 0;
+        self.total_in = 0;
+        self.total_out = 0;
+    }
+
+    /// Sets whether to go on decoding streams that follow the first one.
+    pub fn set_multi_stream(&mut self, multi_stream: bool) {
+        self.multi_stream = multi_stream;
+    }
+
+    pub fn multi_stream(&self) -> bool {
+        self.multi_stream
+    }
+
+    /// Sets the limits beyond which decoding fails with an error rather than
+    /// produce more output.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    // Starts keeping a report of every block and stream checked.
+    #[cfg(feature = "std")]
+    pub(crate) fn start_report(&mut self) {
+        self.report = Some(Report::default());
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn take_report(&mut self) -> Option<Report> {
+        self.report.take()
+    }
+
+    // Stops decoding blocks once they are parsed, keeping their internals
+    // instead. No output is produced and CRCs go unchecked.
+    #[cfg(feature = "std")]
+    pub(crate) fn start_inspection(&mut self) {
+        self.inspection = Some(Vec::new());
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn take_inspection(&mut self) -> Option<Vec<StreamInfo>> {
+        self.inspection.take()
+    }
+
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// The error to report when the input ends before the stream does.
+    pub fn eof_error(&self) -> Error {
+        match self.state {
+            State::Header if self.streams > 0 => Error::TrailingGarbage {
+                block: self.blocks,
+                bit_offset: self.bits.position(),
+            },
+            _ => Error::UnexpectedEof {
+                block: self.blocks,
+                bit_offset: self.total_in * 8,
+            },
+        }
+    }
+
+    /// Decodes from `input` into `output`, returning the number of bytes
+    /// consumed and produced.
+    ///
+    /// Output that comes before an error is returned first, and the error
+    /// from the next call on.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize, Status)> {
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+        let mut rest = input;
+        let mut produced = 0;
+        let mut status = self.run(&mut rest, output, &mut produced);
+
+        let consumed = input.len() - rest.len();
+        self.total_in += consumed as u64;
+        self.total_out += produced as u64;
+
+        if let Err(ref error) = status {
+            // hand over the output that came before the error first
+            if produced > 0 {
+                self.error = Some(error.clone());
+                status = Ok(Status::Ok);
+            }
+        }
+        status.map(|status| (consumed, produced, status))
+    }
+
+    fn run(&mut self, input: &mut &[u8], output: &mut [u8], produced: &mut usize) -> Result<Status> {
+        loop {
+            let pos = self.bits.position();
+            match self.state {
+                State::Header => {
+                    if !self.bits.fill(input, 32) {
+                        return Ok(Status::Ok);
+                    }
+                    if self.bits.read(24) != 0x42_5a_68 {
+                        if self.streams > 0 {
+                            return Err(Error::TrailingGarbage {
+                                block: self.blocks,
+                                bit_offset: pos,
+                            });
+                        }
+                        return Err(Error::BadMagic {
+                            block: self.blocks,
+                            bit_offset: pos,
+                        });
+                    }
+                    let level = self.bits.read(8) as u8;
+                    if !(b'1'..=b'9').contains(&level) {
+                        return Err(Error::UnsupportedBlockSize {
+                            level,
+                            block: self.blocks,
+                            bit_offset: pos + 24,
+                        });
+                    }
+                    if let Some(limit) = self.limits.max_streams {
+                        if self.streams >= limit {
+                            return Err(Error::StreamLimitExceeded {
+                                limit,
+                                block: self.blocks,
+                                bit_offset: pos,
+                            });
+                        }
+                    }
+                    self.level = (level - b'0') as u32;
+                    self.combined = 0;
+                    self.stream_start = pos;
+                    self.stream_blocks = self.blocks;
+                    if let Some(ref mut streams) = self.inspection {
+                        streams.push(StreamInfo {
+                            bits: pos..pos + 32,
+                            level: self.level,
+                            crc: None,
+                            blocks: Vec::new(),
+                        });
+                    }
+                    self.state = State::Magic;
+                }
+                State::Magic => {
+                    if !self.bits.fill(input, 48) {
+                        return Ok(Status::Ok);
+                    }
+                    let magic = (self.bits.read(16) as u64) << 32 | self.bits.read(32) as u64;
+                    if magic == BLOCK_MAGIC {
+                        if let Some(limit) = self.limits.max_blocks {
+                            if self.blocks >= limit {
+                                return Err(Error::BlockLimitExceeded {
+                                    limit,
+                                    block: self.blocks,
+                                    bit_offset: pos,
+                                });
+                            }
+                        }
+                        self.block.reset(self.blocks, pos, self.level);
+                        self.state = State::Block;
+                    } else if magic == EOS_MAGIC {
+                        self.state = State::StreamCrc;
+                    } else {
+                        return Err(Error::BadMagic {
+                            block: self.blocks,
+                            bit_offset: pos,
+                        });
+                    }
+                }
+                State::Block => {
+                    if !self.block.decode(&mut self.bits, input)? {
+                        return Ok(Status::Ok);
+                    }
+                    self.block_end = self.bits.position();
+                    if let Some(stream) = self.inspection.as_mut().and_then(|streams| streams.last_mut()) {
+                        stream.bits.end = self.block_end;
+                        stream.blocks.push(self.block.inspect(self.block_end));
+                        self.blocks += 1;
+                        self.state = State::Magic;
+                        continue;
+                    }
+                    self.block_out = 0;
+                    self.block.finish()?;
+                    self.crc = Crc::new();
+                    self.state = State::Output;
+                }
+                State::Output => {
+                    let (headroom, limit_error) = self.headroom(*produced);
+                    let end = output.len().min(produced.saturating_add(headroom));
+                    let out = &mut output[*produced..end];
+                    let written = self.block.read(out);
+                    self.crc.update_slice(&out[..written]);
+                    self.block_out += written as u64;
+                    *produced += written;
+
+                    if !self.block.is_drained() {
+                        if written == out.len() && end < output.len() {
+                            // stopped by a limit rather than by the space left
+                            if let Some(error) = limit_error {
+                                return Err(error);
+                            }
+                        }
+                        return Ok(Status::Ok);
+                    }
+
+                    let actual = self.crc.finish();
+                    if let Some(ref mut report) = self.report {
+                        report.blocks.push(BlockReport {
+                            index: self.blocks,
+                            bits: self.block.start()..self.block_end,
+                            len: self.block_out,
+                            stored_crc: self.block.crc(),
+                            computed_crc: actual,
+                            tables: self.block.tables() as u32,
+                            randomised: self.block.randomised(),
+                        });
+                    }
+                    if actual != self.block.crc() {
+                        return Err(Error::BlockCrcMismatch {
+                            expected: self.block.crc(),
+                            actual,
+                            block: self.blocks,
+                            bit_offset: self.block.start() + 48,
+                        });
+                    }
+                    self.combined = crc::combine(self.combined, actual);
+                    self.blocks += 1;
+                    self.state = State::Magic;
+                }
+                State::StreamCrc => {
+                    if !self.bits.fill(input, 32) {
+                        return Ok(Status::Ok);
+                    }
+                    let expected = self.bits.read(32);
+                    if let Some(stream) = self.inspection.as_mut().and_then(|streams| streams.last_mut()) {
+                        stream.bits.end = (pos + 32).div_ceil(8) * 8;
+                        stream.crc = Some(expected);
+                        self.bits.align();
+                        self.streams += 1;
+                        self.state = State::Done;
+                        continue;
+                    }
+                    if let Some(ref mut report) = self.report {
+                        report.streams.push(StreamReport {
+                            bits: self.stream_start..(pos + 32).div_ceil(8) * 8,
+                            level: self.level,
+                            blocks: self.stream_blocks..self.blocks,
+                            stored_crc: expected,
+                            computed_crc: self.combined,
+                        });
+                    }
+                    if expected != self.combined {
+                        return Err(Error::StreamCrcMismatch {
+                            expected,
+                            actual: self.combined,
+                            block: self.blocks,
+                            bit_offset: pos,
+                        });
+                    }
+                    self.bits.align();
+                    self.streams += 1;
+                    self.state = State::Done;
+                }
+                State::Done => {
+                    if !self.multi_stream || input.is_empty() {
+                        return Ok(Status::StreamEnd);
+                    }
+                    self.state = State::Header;
+                }
+            }
+        }
+    }
+
+    // How many more bytes the limits allow on top of `produced` in this
+    // call, with the error to fail with if more than that are needed.
+    fn headroom(&self, produced: usize) -> (usize, Option<Error>) {
+        let out = self.total_out + produced as u64;
+        let bit_offset = self.bits.position();
+        let mut headroom = u64::MAX;
+        let mut error = None;
+        if let Some(limit) = self.limits.max_output {
+            headroom = limit.saturating_sub(out);
+            error = Some(Error::OutputLimitExceeded { limit, block: self.blocks, bit_offset });
+        }
+        if let Some(limit) = self.limits.max_ratio {
+            let ratio_headroom = limit.saturating_mul(bit_offset.div_ceil(8)).saturating_sub(out);
+            if ratio_headroom < headroom {
+                headroom = ratio_headroom;
+                error = Some(Error::RatioLimitExceeded { limit, block: self.blocks, bit_offset });
+            }
+        }
+        (headroom.min(usize::MAX as u64) as usize, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2;
+    use proptest::string::bytes_regex;
+
+    use super::*;
+    use BzWriter;
+
+    fn compressed(data: &[u8], level: u32) -> Vec<u8> {
+        let mut writer = BzWriter::new(vec![], level);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    // Decodes `input` in small steps until an error, returning the output
+    // produced before it.
+    fn decode_err(input: &[u8]) -> (Vec<u8>, Error) {
+        let mut decompress = Decompress::new();
+        let mut rest = input;
+        let mut out = vec![];
+        let mut buf = [0; 1000];
+        loop {
+            match decompress.decompress(rest, &mut buf) {
+                Ok((consumed, produced, _)) => {
+                    assert!(consumed > 0 || produced > 0, "stuck without an error");
+                    rest = &rest[consumed..];
+                    out.extend_from_slice(&buf[..produced]);
+                }
+                Err(error) => {
+                    // the error sticks
+                    assert_eq!(decompress.decompress(rest, &mut buf), Err(error.clone()));
+                    return (out, error);
+                }
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decompresses_into_a_buffer(ref data in bytes_regex(".*").unwrap(), level in 1..10u32) {
+            let mut input = compressed(data, level);
+            bzip2::read::BzEncoder::new(&data[..], bzip2::Compression::Fastest).read_to_end(&mut input).unwrap();
+
+            let mut tt = vec![0; scratch_len(level)];
+            let mut scratch = Scratch::new(&mut tt);
+            let mut output = vec![0; data.len() * 2];
+            prop_assert_eq!(decompress_into(&input, &mut output, &mut scratch), Ok(data.len() * 2));
+            prop_assert_eq!(&output[..data.len()], &data[..]);
+            prop_assert_eq!(&output[data.len()..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn reports_what_does_not_fit() {
+        let data: Vec<u8> = (0..450_000u32).map(|i| ((i / 3) ^ (i / 1000)) as u8).collect();
+        let input = compressed(&data, 2);
+        let mut tt = vec![0; scratch_len(2)];
+        let mut scratch = Scratch::new(&mut tt);
+
+        let mut output = vec![0; data.len() + 1];
+        assert_eq!(decompress_into(&input, &mut output, &mut scratch), Ok(data.len()));
+        assert_eq!(&output[..data.len()], &data[..]);
+
+        // the same scratch is reused for every call
+        let err = decompress_into(&input, &mut output[..data.len() - 1], &mut scratch).unwrap_err();
+        assert!(matches!(err, Error::OutputLimitExceeded { limit, block: 2, .. } if limit == data.len() as u64 - 1));
+        let err = decompress_into(&input[..input.len() - 1], &mut output, &mut scratch).unwrap_err();
+        assert!(matches!(err, Error::UnexpectedEof { block: 3, .. }));
+
+        let mut tt = vec![0; scratch_len(1)];
+        let err = decompress_into(&input, &mut output, &mut Scratch::new(&mut tt)).unwrap_err();
+        assert!(matches!(err, Error::ScratchTooSmall { level: 2, block: 0, .. }));
+        // a stream whose blocks are small enough fits
+        let input = compressed(&data[..1000], 9);
+        assert_eq!(decompress_into(&input, &mut output, &mut Scratch::new(&mut tt)), Ok(1000));
+    }
+
+    #[test]
+    fn returns_output_before_a_crc_mismatch() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let mut input = compressed(&data, 1);
+        // the stored CRC of the first block follows the block magic
+        input[10] ^= 1;
+        let (out, error) = decode_err(&input);
+        assert!(matches!(error, Error::BlockCrcMismatch { .. }));
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn returns_output_before_trailing_garbage() {
+        let mut input = compressed(b"hello", 1);
+        let len = input.len() as u64;
+        input.extend_from_slice(b"garbage");
+        let (out, error) = decode_err(&input);
+        assert_eq!(error, Error::TrailingGarbage { block: 1, bit_offset: len * 8 });
+        assert_eq!(out, b"hello");
+    }
+}
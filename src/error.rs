@@ -0,0 +1,161 @@
+use core::fmt;
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::io;
+
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// An error encountered while decoding a bzip2 stream.
+///
+/// Every variant records `block`, the index of the block being decoded
+/// (counted from zero across the whole input), and `bit_offset`, the offset
+/// in bits from the start of the input of the field found to be invalid.
+///
+/// With the `serde` feature it serialises as its message, `block` and
+/// `bit_offset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The input does not start with `BZh`, or a block starts with neither
+    /// the block magic nor the end-of-stream magic.
+    BadMagic { block: u64, bit_offset: u64 },
+    /// The block size digit of the stream header is not `1..=9`.
+    UnsupportedBlockSize { level: u8, block: u64, bit_offset: u64 },
+    /// The CRC of a decoded block does not match the one stored before it.
+    BlockCrcMismatch { expected: u32, actual: u32, block: u64, bit_offset: u64 },
+    /// The combined CRC of a stream does not match the one stored after it.
+    StreamCrcMismatch { expected: u32, actual: u32, block: u64, bit_offset: u64 },
+    /// A block declares an impossible set of Huffman tables, or its data
+    /// contains a code that none of them can decode.
+    InvalidHuffmanTable { block: u64, bit_offset: u64 },
+    /// A block refers to a Huffman table or selector that does not exist.
+    SelectorOutOfRange { block: u64, bit_offset: u64 },
+    /// The BWT origin pointer does not point into the block.
+    OrigPtrOutOfRange { orig_ptr: u32, block: u64, bit_offset: u64 },
+    /// A block decodes to more data than the stream's block size allows.
+    BlockOverflow { block: u64, bit_offset: u64 },
+    /// The input ended in the middle of a stream.
+    UnexpectedEof { block: u64, bit_offset: u64 },
+    /// Data that is not a bzip2 stream follows the end of the input.
+    TrailingGarbage { block: u64, bit_offset: u64 },
+    /// The output would grow past `Limits::max_output`.
+    OutputLimitExceeded { limit: u64, block: u64, bit_offset: u64 },
+    /// The output would grow past `Limits::max_ratio` times the input
+    /// consumed.
+    RatioLimitExceeded { limit: u64, block: u64, bit_offset: u64 },
+    /// The input holds more streams than `Limits::max_streams`.
+    StreamLimitExceeded { limit: u64, block: u64, bit_offset: u64 },
+    /// The input holds more blocks than `Limits::max_blocks`.
+    BlockLimitExceeded { limit: u64, block: u64, bit_offset: u64 },
+    /// A block is too large for the scratch space lent to `decompress_into`,
+    /// which needs `scratch_len(level)` words for the stream's block size.
+    ScratchTooSmall { level: u32, block: u64, bit_offset: u64 },
+}
+
+impl Error {
+    /// The index of the block being decoded when the error occurred.
+    pub fn block(&self) -> u64 {
+        match *self {
+            Error::BadMagic { block, .. } |
+            Error::UnsupportedBlockSize { block, .. } |
+            Error::BlockCrcMismatch { block, .. } |
+            Error::StreamCrcMismatch { block, .. } |
+            Error::InvalidHuffmanTable { block, .. } |
+            Error::SelectorOutOfRange { block, .. } |
+            Error::OrigPtrOutOfRange { block, .. } |
+            Error::BlockOverflow { block, .. } |
+            Error::UnexpectedEof { block, .. } |
+            Error::TrailingGarbage { block, .. } |
+            Error::OutputLimitExceeded { block, .. } |
+            Error::RatioLimitExceeded { block, .. } |
+            Error::StreamLimitExceeded { block, .. } |
+            Error::BlockLimitExceeded { block, .. } |
+            Error::ScratchTooSmall { block, .. } => block,
+        }
+    }
+
+    /// The offset in bits from the start of the input of the invalid field.
+    pub fn bit_offset(&self) -> u64 {
+        match *self {
+            Error::BadMagic { bit_offset, .. } |
+            Error::UnsupportedBlockSize { bit_offset, .. } |
+            Error::BlockCrcMismatch { bit_offset, .. } |
+            Error::StreamCrcMismatch { bit_offset, .. } |
+            Error::InvalidHuffmanTable { bit_offset, .. } |
+            Error::SelectorOutOfRange { bit_offset, .. } |
+            Error::OrigPtrOutOfRange { bit_offset, .. } |
+            Error::BlockOverflow { bit_offset, .. } |
+            Error::UnexpectedEof { bit_offset, .. } |
+            Error::TrailingGarbage { bit_offset, .. } |
+            Error::OutputLimitExceeded { bit_offset, .. } |
+            Error::RatioLimitExceeded { bit_offset, .. } |
+            Error::StreamLimitExceeded { bit_offset, .. } |
+            Error::BlockLimitExceeded { bit_offset, .. } |
+            Error::ScratchTooSmall { bit_offset, .. } => bit_offset,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BadMagic { .. } => write!(f, "bad magic number")?,
+            Error::UnsupportedBlockSize { level, .. } => {
+                write!(f, "unsupported block size {:?}", level as char)?
+            }
+            Error::BlockCrcMismatch { expected, actual, .. } => {
+                write!(f, "block CRC mismatch (stored {:08x}, computed {:08x})", expected, actual)?
+            }
+            Error::StreamCrcMismatch { expected, actual, .. } => {
+                write!(f, "stream CRC mismatch (stored {:08x}, computed {:08x})", expected, actual)?
+            }
+            Error::InvalidHuffmanTable { .. } => write!(f, "invalid Huffman table")?,
+            Error::SelectorOutOfRange { .. } => write!(f, "selector out of range")?,
+            Error::OrigPtrOutOfRange { orig_ptr, .. } => {
+                write!(f, "origPtr {} out of range", orig_ptr)?
+            }
+            Error::BlockOverflow { .. } => write!(f, "block exceeds the declared block size")?,
+            Error::UnexpectedEof { .. } => write!(f, "unexpected end of input")?,
+            Error::TrailingGarbage { .. } => write!(f, "trailing garbage after end of stream")?,
+            Error::OutputLimitExceeded { limit, .. } => {
+                write!(f, "output limit of {} bytes exceeded", limit)?
+            }
+            Error::RatioLimitExceeded { limit, .. } => {
+                write!(f, "expansion ratio limit of {} exceeded", limit)?
+            }
+            Error::StreamLimitExceeded { limit, .. } => write!(f, "limit of {} streams exceeded", limit)?,
+            Error::BlockLimitExceeded { limit, .. } => write!(f, "limit of {} blocks exceeded", limit)?,
+            Error::ScratchTooSmall { level, .. } => {
+                write!(f, "scratch space too small for block size {}", level)?
+            }
+        }
+        write!(f, " in block {} at bit offset {}", self.block(), self.bit_offset())
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {}
+
+#[cfg(feature = "serde")]
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error> {
+        let mut error = serializer.serialize_struct("Error", 3)?;
+        error.serialize_field("message", &format_args!("{}", self))?;
+        error.serialize_field("block", &self.block())?;
+        error.serialize_field("bit_offset", &self.bit_offset())?;
+        error.end()
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::UnexpectedEof { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
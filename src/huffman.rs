@@ -0,0 +1,274 @@
+use bits::BitReader;
+
+/// The longest code a decoder must accept. Encoders since bzip2 1.0.3 never
+/// produce codes longer than `MAX_ENCODE_LEN`.
+pub const MAX_CODE_LEN: u32 = 20;
+pub const MAX_ENCODE_LEN: u32 = 17;
+/// The most symbols a block's alphabet can have: RUNA and RUNB, 255 MTF
+/// values and the end-of-block symbol.
+pub const MAX_ALPHA_SIZE: usize = 258;
+
+/// Computes length-limited Huffman code lengths for `freq` into `lens`,
+/// exactly as `BZ2_hbMakeCodeLengths` does. At most `MAX_ALPHA_SIZE`
+/// symbols are supported.
+pub fn code_lengths(lens: &mut [u8], freq: &[u32], max_len: u32) {
+    let n = freq.len();
+    debug_assert_eq!(lens.len(), n);
+    assert!(n <= MAX_ALPHA_SIZE);
+
+    // nodes and heap entries are 1-based, index 0 is a sentinel
+    let mut heap = [0usize; MAX_ALPHA_SIZE + 2];
+    let mut weight = [0u32; MAX_ALPHA_SIZE * 2];
+    let mut parent = [0isize; MAX_ALPHA_SIZE * 2];
+
+    for i in 0..n {
+        weight[i + 1] = freq[i].max(1) << 8;
+    }
+
+    fn add_weights(a: u32, b: u32) -> u32 {
+        ((a & 0xffff_ff00) + (b & 0xffff_ff00)) | (1 + (a & 0xff).max(b & 0xff))
+    }
+
+    loop {
+        let mut n_nodes = n;
+        let mut n_heap = 0;
+
+        heap[0] = 0;
+        weight[0] = 0;
+        parent[0] = -2;
+
+        let up_heap = |heap: &mut [usize], weight: &[u32], mut z: usize| {
+            let tmp = heap[z];
+            while weight[tmp] < weight[heap[z >> 1]] {
+                heap[z] = heap[z >> 1];
+                z >>= 1;
+            }
+            heap[z] = tmp;
+        };
+
+        let down_heap = |heap: &mut [usize], weight: &[u32], n_heap: usize| {
+            let mut z = 1;
+            let tmp = heap[z];
+            loop {
+                let mut y = z << 1;
+                if y > n_heap {
+                    break;
+                }
+                if y < n_heap && weight[heap[y + 1]] < weight[heap[y]] {
+                    y += 1;
+                }
+                if weight[tmp] < weight[heap[y]] {
+                    break;
+                }
+                heap[z] = heap[y];
+                z = y;
+            }
+            heap[z] = tmp;
+        };
+
+        for p in &mut parent[1..n + 1] {
+            *p = -1;
+        }
+        for i in 1..n + 1 {
+            n_heap += 1;
+            heap[n_heap] = i;
+            up_heap(&mut heap, &weight, n_heap);
+        }
+
+        while n_heap > 1 {
+            let n1 = heap[1];
+            heap[1] = heap[n_heap];
+            n_heap -= 1;
+            down_heap(&mut heap, &weight, n_heap);
+            let n2 = heap[1];
+            heap[1] = heap[n_heap];
+            n_heap -= 1;
+            down_heap(&mut heap, &weight, n_heap);
+
+            n_nodes += 1;
+            parent[n1] = n_nodes as isize;
+            parent[n2] = n_nodes as isize;
+            weight[n_nodes] = add_weights(weight[n1], weight[n2]);
+            parent[n_nodes] = -1;
+            n_heap += 1;
+            heap[n_heap] = n_nodes;
+            up_heap(&mut heap, &weight, n_heap);
+        }
+
+        let mut too_long = false;
+        for i in 1..n + 1 {
+            let mut len = 0;
+            let mut k = i;
+            while parent[k] >= 0 {
+                k = parent[k] as usize;
+                len += 1;
+            }
+            lens[i - 1] = len as u8;
+            too_long |= len > max_len;
+        }
+
+        if !too_long {
+            break;
+        }
+
+        for w in &mut weight[1..n + 1] {
+            *w = (1 + (*w >> 8) / 2) << 8;
+        }
+    }
+}
+
+/// Assigns canonical codes to `lens`, shortest codes first and ties broken
+/// by symbol order.
+pub fn assign_codes(codes: &mut [u32], lens: &[u8]) {
+    let min_len = lens.iter().cloned().min().unwrap_or(0) as u32;
+    let max_len = lens.iter().cloned().max().unwrap_or(0) as u32;
+
+    let mut code = 0;
+    for len in min_len..max_len + 1 {
+        for (c, l) in codes.iter_mut().zip(lens) {
+            if *l as u32 == len {
+                *c = code;
+                code += 1;
+            }
+        }
+        code <<= 1;
+    }
+}
+
+pub enum Decoded {
+    Symbol(u16),
+    /// Not enough bits are buffered to decode the next symbol.
+    Incomplete,
+    /// The buffered bits do not form a code in this table.
+    Invalid,
+}
+
+/// Canonical Huffman decoding table, the `limit`/`base`/`perm` scheme of
+/// `BZ2_hbCreateDecodeTables`.
+#[derive(Clone, Copy)]
+pub struct DecodeTable {
+    limit: [i32; MAX_CODE_LEN as usize + 2],
+    base: [i32; MAX_CODE_LEN as usize + 2],
+    perm: [u16; 258],
+    min_len: u32,
+    max_len: u32,
+}
+
+impl DecodeTable {
+    /// Builds a table from code lengths, which must all be in
+    /// `1..=MAX_CODE_LEN`.
+    pub fn new(lens: &[u8]) -> Self {
+        let mut table = DecodeTable {
+            limit: [0; MAX_CODE_LEN as usize + 2],
+            base: [0; MAX_CODE_LEN as usize + 2],
+            perm: [0; 258],
+            min_len: lens.iter().cloned().min().unwrap_or(1) as u32,
+            max_len: lens.iter().cloned().max().unwrap_or(1) as u32,
+        };
+
+        let (min_len, max_len) = (table.min_len as usize, table.max_len as usize);
+
+        let mut pp = 0;
+        for len in min_len..max_len + 1 {
+            for (sym, l) in lens.iter().enumerate() {
+                if *l as usize == len {
+                    table.perm[pp] = sym as u16;
+                    pp += 1;
+                }
+            }
+        }
+
+        for l in lens {
+            table.base[*l as usize + 1] += 1;
+        }
+        for i in 1..table.base.len() {
+            table.base[i] += table.base[i - 1];
+        }
+
+        let mut vec = 0;
+        for i in min_len..max_len + 1 {
+            vec += table.base[i + 1] - table.base[i];
+            table.limit[i] = vec - 1;
+            vec <<= 1;
+        }
+        for i in min_len + 1..max_len + 1 {
+            table.base[i] = ((table.limit[i - 1] + 1) << 1) - table.base[i];
+        }
+
+        table
+    }
+
+    /// Decodes one symbol of an alphabet of `alpha_size` symbols, consuming
+    /// its bits only if it could be decoded.
+    #[inline]
+    pub fn decode(&self, bits: &mut BitReader, alpha_size: usize) -> Decoded {
+        let avail = bits.available();
+        let mut len = self.min_len;
+        loop {
+            if len > avail {
+                return Decoded::Incomplete;
+            }
+            let code = bits.peek(len) as i32;
+            if code <= self.limit[len as usize] {
+                let idx = code - self.base[len as usize];
+                if idx < 0 || idx as usize >= alpha_size {
+                    return Decoded::Invalid;
+                }
+                bits.consume(len);
+                return Decoded::Symbol(self.perm[idx as usize]);
+            }
+            len += 1;
+            if len > self.max_len {
+                return Decoded::Invalid;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bits::BitWriter;
+
+    #[test]
+    fn lengths_are_limited() {
+        // Fibonacci frequencies produce the deepest possible trees
+        let mut freq = vec![1u32, 1];
+        while freq.len() < 30 {
+            let n = freq.len();
+            let next = freq[n - 1] + freq[n - 2];
+            freq.push(next);
+        }
+        let mut lens = vec![0; freq.len()];
+        code_lengths(&mut lens, &freq, MAX_ENCODE_LEN);
+        assert!(lens.iter().all(|l| *l >= 1 && *l as u32 <= MAX_ENCODE_LEN));
+    }
+
+    #[test]
+    fn round_trip() {
+        let freq = [10u32, 1, 0, 7, 3, 3, 50, 2];
+        let mut lens = [0; 8];
+        code_lengths(&mut lens, &freq, MAX_ENCODE_LEN);
+        let mut codes = [0; 8];
+        assign_codes(&mut codes, &lens);
+
+        let symbols = [6u16, 0, 3, 7, 2, 1, 6, 6, 4, 5];
+        let mut w = BitWriter::new();
+        for s in &symbols {
+            w.write(lens[*s as usize] as u32, codes[*s as usize]);
+        }
+        w.align();
+
+        let table = DecodeTable::new(&lens);
+        let data = w.bytes().to_owned();
+        let mut input = &data[..];
+        let mut bits = BitReader::new();
+        for s in &symbols {
+            bits.fill(&mut input, MAX_CODE_LEN);
+            match table.decode(&mut bits, lens.len()) {
+                Decoded::Symbol(sym) => assert_eq!(sym, *s),
+                _ => panic!("failed to decode {}", s),
+            }
+        }
+    }
+}
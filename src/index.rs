@@ -0,0 +1,430 @@
+//! Where each block lies in the compressed and uncompressed data.
+//!
+//! Blocks are independent, so with their offsets known any part of the
+//! uncompressed data can be reached by decoding just the blocks that hold
+//! it. An `Index` comes either from the writer that produced the data, when
+//! it was asked to record one, or from decoding the data once with
+//! `Index::scan`.
+//!
+//! An index can be saved next to the data it describes, conventionally with
+//! a `.bz2idx` extension, so that it only has to be built once. The format
+//! is, with all integers big-endian:
+//!
+//! ```text
+//! magic         "BZ2IDX"
+//! version       u8, currently 1
+//! reserved      u8, zero
+//! archive_len   u64, the length in bytes of the compressed data
+//! count         u64, the number of entries
+//! entries       count times:
+//!     bit_start     u64
+//!     bit_end       u64
+//!     byte_end      u64 (each block starts where the last one ended)
+//!     level         u8
+//!     crc           u32
+//! checksum      u32, the CRC of everything before it
+//! ```
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// The position of one block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The bits of the compressed data the block occupies, from the start of
+    /// its magic to the end of its last symbol.
+    pub bits: Range<u64>,
+    /// The bytes of uncompressed data the block decodes to.
+    pub bytes: Range<u64>,
+    /// The block size level of the stream the block belongs to.
+    pub level: u32,
+    /// The CRC of the block's uncompressed data.
+    pub crc: u32,
+}
+
+/// The positions of all the blocks of some compressed data, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Index {
+    entries: Vec<Entry>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Index::default()
+    }
+
+    /// Adds the next block, which must follow on from the last one.
+    pub fn push(&mut self, entry: Entry) {
+        if let Some(last) = self.entries.last() {
+            assert!(entry.bits.start >= last.bits.end && entry.bytes.start == last.bytes.end,
+                    "index entries must follow on from each other");
+        }
+        self.entries.push(entry);
+    }
+
+    /// Removes every entry, keeping the space they took.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The length of the uncompressed data.
+    pub fn len(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.bytes.end)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the position in `entries` of the block holding uncompressed
+    /// byte `offset`.
+    pub fn find(&self, offset: u64) -> Option<usize> {
+        if offset >= self.len() {
+            return None;
+        }
+        Some(self.entries.partition_point(|entry| entry.bytes.end <= offset))
+    }
+}
+
+// Building an index from the data, and saving and loading it.
+#[cfg(feature = "std")]
+mod saved {
+    use std::error;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    use block::{self, MAX_BLOCK_BITS};
+    use crc::Crc;
+    use {parallel, seekable};
+
+    use super::{Entry, Index};
+
+    const MAGIC: &[u8; 6] = b"BZ2IDX";
+    const VERSION: u8 = 1;
+    pub(super) const HEADER_LEN: usize = 24;
+    pub(super) const ENTRY_LEN: usize = 29;
+
+    // The number of blocks decoded to check that an index still matches.
+    const SAMPLES: usize = 3;
+
+    impl Entry {
+        // Whether the entry is no larger than a block can be, so that loading
+        // it cannot take more memory than any block would.
+        pub(crate) fn fits_a_block(&self) -> bool {
+            self.bits.end.saturating_sub(self.bits.start) <= MAX_BLOCK_BITS &&
+            self.bytes.end.saturating_sub(self.bytes.start) <= block::max_block_output(self.level)
+        }
+    }
+
+    impl Index {
+        /// Builds an index by decoding all of `data`, which may hold
+        /// concatenated streams, on `threads` worker threads.
+        pub fn scan(data: &[u8], threads: usize) -> io::Result<Index> {
+            let mut index = Index::new();
+            parallel::decode_blocks(data, threads, |entry, _| {
+                index.push(entry);
+                Ok(())
+            })?;
+            Ok(index)
+        }
+
+        /// Saves the index for compressed data `archive_len` bytes long.
+        pub fn write_to<W: Write>(&self, mut out: W, archive_len: u64) -> io::Result<()> {
+            let mut buf = Vec::with_capacity(HEADER_LEN + self.entries.len() * ENTRY_LEN + 4);
+            buf.extend_from_slice(MAGIC);
+            buf.extend_from_slice(&[VERSION, 0]);
+            buf.extend_from_slice(&archive_len.to_be_bytes());
+            buf.extend_from_slice(&(self.entries.len() as u64).to_be_bytes());
+            for entry in &self.entries {
+                buf.extend_from_slice(&entry.bits.start.to_be_bytes());
+                buf.extend_from_slice(&entry.bits.end.to_be_bytes());
+                buf.extend_from_slice(&entry.bytes.end.to_be_bytes());
+                buf.push(entry.level as u8);
+                buf.extend_from_slice(&entry.crc.to_be_bytes());
+            }
+            let checksum = checksum(&buf);
+            buf.extend_from_slice(&checksum.to_be_bytes());
+            out.write_all(&buf)
+        }
+
+        /// Loads a saved index, returning it with the length of the compressed
+        /// data it was built for. Nothing is checked against the data itself.
+        pub fn read_from<R: Read>(mut input: R) -> io::Result<(Index, u64)> {
+            let mut header = [0; HEADER_LEN];
+            input.read_exact(&mut header)?;
+            if &header[..6] != MAGIC {
+                return Err(invalid("not a bzip2 index"));
+            }
+            if header[6] != VERSION {
+                return Err(invalid(format!("unsupported bzip2 index version {}", header[6])));
+            }
+            if header[7] != 0 {
+                return Err(invalid("invalid bzip2 index header"));
+            }
+            let archive_len = u64_at(&header, 8);
+            let count = u64_at(&header, 16);
+
+            let len = count.checked_mul(ENTRY_LEN as u64)
+                .and_then(|n| n.checked_add(HEADER_LEN as u64))
+                .ok_or_else(|| invalid("invalid bzip2 index entry count"))?;
+            let archive_bits = archive_len.checked_mul(8)
+                .ok_or_else(|| invalid("invalid bzip2 index archive length"))?;
+
+            let mut data = header.to_vec();
+            // a corrupt count must not make us allocate wildly up front
+            input.by_ref().take(len - HEADER_LEN as u64).read_to_end(&mut data)?;
+            if data.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let mut stored = [0; 4];
+            input.read_exact(&mut stored)?;
+            if u32::from_be_bytes(stored) != checksum(&data) {
+                return Err(invalid("bzip2 index checksum mismatch"));
+            }
+
+            let mut index = Index::new();
+            for raw in data[HEADER_LEN..].chunks(ENTRY_LEN) {
+                let entry = Entry {
+                    bits: u64_at(raw, 0)..u64_at(raw, 8),
+                    bytes: index.len()..u64_at(raw, 16),
+                    level: raw[24] as u32,
+                    crc: u32::from_be_bytes([raw[25], raw[26], raw[27], raw[28]]),
+                };
+                let follows = index.entries.last().is_none_or(|last| entry.bits.start >= last.bits.end);
+                if !follows || entry.bits.start >= entry.bits.end || entry.bits.end > archive_bits ||
+                   entry.bytes.start >= entry.bytes.end || !(1..=9).contains(&entry.level) ||
+                   !entry.fits_a_block() {
+                    return Err(invalid("invalid bzip2 index entry"));
+                }
+                index.push(entry);
+            }
+            Ok((index, archive_len))
+        }
+
+        /// Loads a saved index and checks it against `archive`, the compressed
+        /// data it describes.
+        pub fn load<R: Read, A: Read + Seek>(input: R, archive: &mut A) -> io::Result<Index> {
+            let (index, archive_len) = Index::read_from(input)?;
+            index.validate(archive, archive_len)?;
+            Ok(index)
+        }
+
+        /// Checks that `archive` is `archive_len` bytes long and that a few of
+        /// the blocks are where the index says and hold what it says, to catch
+        /// an index that has gone stale.
+        pub fn validate<A: Read + Seek>(&self, archive: &mut A, archive_len: u64) -> io::Result<()> {
+            let actual_len = archive.seek(SeekFrom::End(0))?;
+            if actual_len != archive_len {
+                return Err(invalid(format!("bzip2 index is for a {} byte archive, not {} bytes",
+                                           archive_len, actual_len)));
+            }
+
+            let n = self.entries.len();
+            let mut samples: Vec<usize> = (0..SAMPLES).map(|i| i * n.saturating_sub(1) / (SAMPLES - 1)).collect();
+            samples.dedup();
+            let mut decoder = block::Decoder::new();
+            let mut buf = Vec::new();
+            for i in samples.into_iter().filter(|&i| i < n) {
+                seekable::decode_entry(archive, self, i, &mut decoder, &mut buf)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at..at + 8]);
+        u64::from_be_bytes(bytes)
+    }
+
+    fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update_slice(data);
+        crc.finish()
+    }
+
+    fn invalid<E: Into<Box<dyn error::Error + Send + Sync>>>(error: E) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use super::saved::{ENTRY_LEN, HEADER_LEN};
+    use block;
+    use crc::Crc;
+    use {BzWriter, ParBzWriter};
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 777u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 3 {
+                0 => (x >> 8) as u8,
+                _ => (i / 500) as u8,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn writers_and_scan_agree() {
+        let data = sample(450_000);
+
+        let mut writer = BzWriter::new(vec![], 1).record_index(true);
+        writer.write_all(&data).unwrap();
+        writer.try_finish().unwrap();
+        let index = writer.index().clone();
+        let compressed = writer.finish().unwrap();
+
+        assert!(index.entries().len() > 2);
+        assert_eq!(index.entries()[0].bits.start, 32);
+        assert_eq!(index.len(), data.len() as u64);
+        assert_eq!(Index::scan(&compressed, 3).unwrap(), index);
+
+        let mut writer = ParBzWriter::new(vec![], 1, 3).record_index(true);
+        writer.write_all(&data).unwrap();
+        writer.try_finish().unwrap();
+        assert_eq!(writer.index(), &index);
+    }
+
+    fn indexed(data: &[u8]) -> (Vec<u8>, Index) {
+        let mut writer = BzWriter::new(vec![], 1).record_index(true);
+        writer.write_all(data).unwrap();
+        writer.try_finish().unwrap();
+        let index = writer.index().clone();
+        (writer.finish().unwrap(), index)
+    }
+
+    fn saved_index(data: &[u8]) -> Vec<u8> {
+        let (compressed, index) = indexed(data);
+        let mut saved = vec![];
+        index.write_to(&mut saved, compressed.len() as u64).unwrap();
+        saved
+    }
+
+    // Replaces the checksum of a saved index edited in place.
+    fn reseal(saved: &mut [u8]) {
+        let end = saved.len() - 4;
+        let mut crc = Crc::new();
+        crc.update_slice(&saved[..end]);
+        let checksum = crc.finish();
+        saved[end..].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let (compressed, index) = indexed(&sample(450_000));
+        let mut saved = vec![];
+        index.write_to(&mut saved, compressed.len() as u64).unwrap();
+        assert_eq!(saved.len(), HEADER_LEN + index.entries().len() * ENTRY_LEN + 4);
+
+        let loaded = Index::load(&saved[..], &mut Cursor::new(&compressed)).unwrap();
+        assert_eq!(loaded, index);
+
+        let mut empty = vec![];
+        Index::new().write_to(&mut empty, 14).unwrap();
+        let loaded = Index::load(&empty[..], &mut Cursor::new(&indexed(b"").0)).unwrap();
+        assert!(loaded.is_empty());
+    }
+
+    #[test]
+    fn rejects_damaged_or_stale_indexes() {
+        let data = sample(350_000);
+        let (compressed, index) = indexed(&data);
+        let mut saved = vec![];
+        index.write_to(&mut saved, compressed.len() as u64).unwrap();
+
+        for i in 0..saved.len() {
+            let mut damaged = saved.clone();
+            damaged[i] ^= 4;
+            assert!(Index::read_from(&damaged[..]).is_err());
+        }
+        assert!(Index::read_from(&saved[..saved.len() - 1]).is_err());
+
+        let mut longer = compressed.clone();
+        longer.push(0);
+        assert!(Index::load(&saved[..], &mut Cursor::new(&longer)).is_err());
+
+        // same length, different contents
+        let mut changed = data.clone();
+        changed[data.len() - 1] ^= 1;
+        let (other, _) = indexed(&changed);
+        let mut other = other;
+        other.resize(compressed.len(), 0);
+        assert!(Index::load(&saved[..], &mut Cursor::new(&other)).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_counts_and_lengths() {
+        // a header claiming more entries than fit in a u64 of bytes
+        let mut header = b"BZ2IDX\x01\0".to_vec();
+        header.extend_from_slice(&100u64.to_be_bytes());
+        header.extend_from_slice(&u64::MAX.to_be_bytes());
+        let err = Index::read_from(&header[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // an archive too long to count in bits, with a valid checksum
+        let mut saved = saved_index(b"some data");
+        saved[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        reseal(&mut saved);
+        let err = Index::read_from(&saved[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_a_reserved_byte_in_use() {
+        let mut saved = saved_index(b"some data");
+        assert!(Index::read_from(&saved[..]).is_ok());
+        saved[7] = 1;
+        reseal(&mut saved);
+        let err = Index::read_from(&saved[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_entries_larger_than_a_block() {
+        let saved = saved_index(b"some data");
+        assert!(Index::read_from(&saved[..]).is_ok());
+
+        // more output than a level 1 block can decode to
+        let mut larger = saved.clone();
+        larger[HEADER_LEN + 16..HEADER_LEN + 24].copy_from_slice(&(block::max_block_output(1) + 1).to_be_bytes());
+        reseal(&mut larger);
+        let err = Index::read_from(&larger[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // more bits than any block takes, in an archive long enough for them
+        let mut longer = saved.clone();
+        longer[8..16].copy_from_slice(&(1u64 << 40).to_be_bytes());
+        longer[HEADER_LEN + 8..HEADER_LEN + 16].copy_from_slice(&(1u64 << 30).to_be_bytes());
+        reseal(&mut longer);
+        let err = Index::read_from(&longer[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn finds_blocks() {
+        let mut index = Index::new();
+        assert_eq!(index.find(0), None);
+        for i in 0..3 {
+            index.push(Entry {
+                bits: i * 1000 + 32..i * 1000 + 1000,
+                bytes: i * 10..i * 10 + 10,
+                level: 9,
+                crc: 0,
+            });
+        }
+        assert_eq!(index.find(0), Some(0));
+        assert_eq!(index.find(9), Some(0));
+        assert_eq!(index.find(10), Some(1));
+        assert_eq!(index.find(29), Some(2));
+        assert_eq!(index.find(30), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! A structured view of the internals of bzip2 data, for tuning and
+//! debugging encoders.
+//!
+//! `inspect` parses every block with the decoder's own parser, up to its
+//! end-of-block symbol, and stops there: the inverse BWT is never run and
+//! CRCs are not checked, which `verify` is for. What it reports for each
+//! block is what the encoder chose: the BWT origin pointer, the byte values
+//! in use, the Huffman tables with their code lengths, how many selectors
+//! pick between them, and how often each symbol occurs.
+//!
+//! With the `serde` feature, every type here implements `Serialize`. Bit
+//! ranges serialise as `[start, end]`.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, Read};
+
+#[cfg(feature = "std")]
+use decompress::{Decompress, Status};
+use error::Error;
+
+#[cfg(feature = "std")]
+const BUF_SIZE: usize = 32 * 1024;
+
+/// The internals of one block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BlockInfo {
+    /// The position of the block in the input, counting from zero across
+    /// all streams.
+    pub index: u64,
+    /// The bits of the input the block occupies, from its magic to the
+    /// next block's.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "pair"))]
+    pub bits: Range<u64>,
+    /// The CRC stored in the block header.
+    pub crc: u32,
+    /// Whether the block is randomised.
+    pub randomised: bool,
+    /// The row of the sorted rotations that holds the original data.
+    pub orig_ptr: u32,
+    /// The byte values the used-symbol bitmap marks as occurring in the
+    /// block, in increasing order.
+    pub used: Vec<u8>,
+    /// The number of selectors, each choosing the table for a group of 50
+    /// symbols.
+    pub selectors: u32,
+    /// The code length of every symbol, for each Huffman table. Symbols are
+    /// RUNA, RUNB, the MTF positions `1..used.len()`, and end-of-block.
+    pub code_lengths: Vec<Vec<u8>>,
+    /// How often each symbol occurs in the block.
+    pub frequencies: Vec<u32>,
+}
+
+/// One stream and its blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct StreamInfo {
+    /// The bits of the input the stream occupies, from its header to the
+    /// end of its padding, or to the end of its last block if it is cut
+    /// short.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "pair"))]
+    pub bits: Range<u64>,
+    /// The block size level, `1..=9`.
+    pub level: u32,
+    /// The combined CRC stored at the end of the stream, if it was reached.
+    pub crc: Option<u32>,
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// The result of `inspect`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Inspection {
+    pub streams: Vec<StreamInfo>,
+    /// The error that stopped parsing, if any.
+    pub error: Option<Error>,
+}
+
+// Serialises a range as `[start, end]`.
+#[cfg(feature = "serde")]
+fn pair<S: Serializer>(range: &Range<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    (range.start, range.end).serialize(serializer)
+}
+
+/// Parses all of `inner` and returns the internals of every stream and
+/// block in it.
+///
+/// Only errors reading `inner` are returned as errors; invalid data is
+/// recorded in `Inspection::error`, after everything parsed before it.
+#[cfg(feature = "std")]
+pub fn inspect<R: Read>(inner: R) -> io::Result<Inspection> {
+    let mut inner = BufReader::with_capacity(BUF_SIZE, inner);
+    let mut decompress = Decompress::new();
+    decompress.start_inspection();
+
+    let error = loop {
+        let (consumed, status, eof) = {
+            let input = inner.fill_buf()?;
+            match decompress.decompress(input, &mut []) {
+                Ok((consumed, _, status)) => (consumed, status, input.is_empty()),
+                Err(err) => break Some(err),
+            }
+        };
+        inner.consume(consumed);
+
+        if status == Status::StreamEnd && eof {
+            break None;
+        }
+        if consumed == 0 && eof {
+            break Some(decompress.eof_error());
+        }
+    };
+
+    let streams = decompress.take_inspection().unwrap_or_default();
+    Ok(Inspection { streams, error })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use super::*;
+    use {verify, BzWriter};
+
+    fn compressed(data: &[u8], level: u32) -> Vec<u8> {
+        let mut writer = BzWriter::new(vec![], level);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn parses_without_decoding() {
+        // letters that never repeat, so none are run-length encoded
+        let (mut x, mut letter) = (7u32, 0);
+        let data: Vec<u8> = (0..150_000).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            letter = (letter + 1 + (x >> 16) % 7) % 8;
+            b'a' + letter as u8
+        }).collect();
+        let mut input = compressed(&data, 1);
+        input.extend(compressed(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaz", 9));
+
+        let inspection = inspect(&input[..]).unwrap();
+        assert_eq!(inspection.error, None);
+        let report = verify(&input[..]).unwrap();
+        assert_eq!(inspection.streams.len(), 2);
+        for (stream, reported) in inspection.streams.iter().zip(&report.streams) {
+            assert_eq!(stream.bits, reported.bits);
+            assert_eq!(stream.level, reported.level);
+            assert_eq!(stream.crc, Some(reported.stored_crc));
+        }
+
+        let blocks: Vec<&BlockInfo> = inspection.streams.iter().flat_map(|stream| &stream.blocks).collect();
+        assert_eq!(blocks.len(), report.blocks.len());
+        for (block, reported) in blocks.iter().zip(&report.blocks) {
+            assert_eq!(block.index, reported.index);
+            assert_eq!(block.bits, reported.bits);
+            assert_eq!(block.crc, reported.stored_crc);
+            assert_eq!(block.code_lengths.len() as u32, reported.tables);
+            assert!(!block.randomised);
+
+            let alpha_size = block.used.len() + 2;
+            assert!(block.code_lengths.iter().all(|lens| lens.len() == alpha_size));
+            assert_eq!(block.frequencies.len(), alpha_size);
+            assert_eq!(block.frequencies[alpha_size - 1], 1);
+            // every group of 50 symbols needs a selector
+            let symbols: u32 = block.frequencies.iter().sum();
+            assert!(block.selectors >= symbols.div_ceil(50));
+            assert!(block.code_lengths.iter().flatten().all(|&len| (1..=20).contains(&len)));
+        }
+        assert_eq!(blocks[0].used, b"abcdefgh");
+        let last = blocks.last().unwrap();
+        // the initial run-length encoding turns 42 "a"s into "aaaa" and 38
+        assert_eq!(last.used, [38, b'a', b'z']);
+        assert_eq!(last.frequencies.len(), 5);
+    }
+
+    #[test]
+    fn keeps_what_came_before_an_error() {
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let input = compressed(&data, 1);
+
+        let inspection = inspect(&input[..input.len() - 100]).unwrap();
+        assert!(matches!(inspection.error, Some(Error::UnexpectedEof { .. })));
+        assert_eq!(inspection.streams.len(), 1);
+        assert_eq!(inspection.streams[0].crc, None);
+        assert_eq!(inspection.streams[0].blocks.len(), 1);
+        assert_eq!(inspection.streams[0].bits.end, inspection.streams[0].blocks[0].bits.end);
+
+        let inspection = inspect(&b"BZh0"[..]).unwrap();
+        assert!(matches!(inspection.error, Some(Error::UnsupportedBlockSize { .. })));
+        assert!(inspection.streams.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialises_to_json() {
+        let inspection = inspect(&compressed(b"hello", 9)[..]).unwrap();
+        let json = ::serde_json::to_value(&inspection).unwrap();
+        let block = &json["streams"][0]["blocks"][0];
+        assert_eq!(json["streams"][0]["level"], 9);
+        assert_eq!(block["used"], ::serde_json::json!([b'e', b'h', b'l', b'o']));
+        assert_eq!(block["bits"][0], 32);
+        assert_eq!(json["error"], ::serde_json::Value::Null);
+    }
+}
@@ -1,3 +1,19 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate core;
+#[macro_use]
+extern crate alloc;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+#[cfg(feature = "futures-io")]
+extern crate futures_io;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 #[cfg(test)]
 extern crate bzip2;
@@ -5,22 +21,78 @@ extern crate bzip2;
 #[macro_use]
 extern crate proptest;
 
-use std::cmp::Ordering;
-use std::cmp::Ordering::*;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::cmp::Ordering::*;
 
 pub mod rle;
 pub mod mtf;
-
-fn sa(data: Vec<u8>) -> Vec<u32> {
+pub mod error;
+#[cfg(feature = "std")]
+pub mod bufread;
+#[cfg(feature = "std")]
+pub mod read;
+#[cfg(feature = "std")]
+pub mod write;
+pub mod scan;
+#[cfg(feature = "std")]
+pub mod parallel;
+pub mod index;
+#[cfg(feature = "std")]
+pub mod split;
+#[cfg(feature = "std")]
+pub mod recover;
+pub mod verify;
+pub mod inspect;
+#[cfg(feature = "std")]
+pub mod compat;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub mod async_io;
+
+mod bits;
+mod block;
+mod compress;
+mod crc;
+mod decompress;
+mod huffman;
+mod randomise;
+#[cfg(feature = "std")]
+mod seekable;
+
+pub use compress::{Action, Compress};
+pub use decompress::{decompress_into, scratch_len, Decompress, Limits, Scratch, Status};
+pub use error::{Error, Result};
+pub use index::Index;
+pub use inspect::Inspection;
+#[cfg(feature = "std")]
+pub use inspect::inspect;
+#[cfg(feature = "std")]
+pub use parallel::{par_decompress, ParBzWriter};
+#[cfg(feature = "std")]
+pub use read::BzReader;
+#[cfg(feature = "std")]
+pub use recover::Recover;
+#[cfg(feature = "std")]
+pub use seekable::SeekableReader;
+#[cfg(feature = "std")]
+pub use split::Split;
+pub use scan::scan_blocks;
+pub use verify::Report;
+#[cfg(feature = "std")]
+pub use verify::verify;
+#[cfg(feature = "std")]
+pub use write::BzWriter;
+
+fn sa(_data: Vec<u8>) -> Vec<u32> {
     unimplemented!()
 }
 
 pub fn bwt_sa(data: &[u8]) -> (Vec<u8>, u32) {
     let n = data.len();
     if n == 0 { return (vec![], 0); }
-    assert!(n <= std::u32::MAX as usize);
+    assert!(n <= u32::MAX as usize);
 
-    let mut d2 = data.to_owned();
+    let mut d2 = data.to_vec();
     d2.extend_from_slice(data);
     let sa = sa(d2);
     
@@ -44,10 +116,10 @@ fn sa_naive(data: &[u8]) -> Vec<u32> {
 pub fn bwt_sa_naive(data: &[u8]) -> (Vec<u8>, u32) {
     let n = data.len();
     if n == 0 { return (vec![], 0); }
-    assert!(n <= std::u32::MAX as usize);
+    assert!(n <= u32::MAX as usize);
 
     let sa = {
-        let mut d2 = data.to_owned();
+        let mut d2 = data.to_vec();
         d2.extend_from_slice(data);
         sa_naive(&d2)
     };
@@ -64,33 +136,48 @@ pub fn bwt_sa_naive(data: &[u8]) -> (Vec<u8>, u32) {
 }
 
 pub fn bwt(data: &[u8]) -> (Vec<u8>, u32) {
+    let mut last_col = vec![];
+    let idx = bwt_into(data, &mut BwtBuffers::default(), &mut last_col);
+    (last_col, idx)
+}
+
+/// The working space of `bwt_into`, kept from one call to the next.
+#[derive(Default)]
+pub struct BwtBuffers {
+    rows: Vec<u32>,
+    stack: Vec<(usize, usize, usize)>,
+}
+
+/// Like `bwt`, but sorts in `buffers` and replaces the contents of
+/// `last_col`, so nothing is allocated once they have grown to the size of
+/// `data`.
+pub fn bwt_into(data: &[u8], buffers: &mut BwtBuffers, last_col: &mut Vec<u8>) -> u32 {
+    last_col.clear();
     let n = data.len();
-    if n == 0 { return (vec![], 0); }
-    assert!(n <= std::u32::MAX as usize);
+    if n == 0 { return 0; }
+    assert!(n <= u32::MAX as usize);
 
-    let matrix = matrix_sort(data);
+    sort_rows(data, buffers);
 
     let mut idx = 0;
-    let last_idx = data.as_ptr() as usize + n;
-    let last_col: Vec<u8> = matrix.into_iter().enumerate().map(|(i, row)| {
-        if row as usize == data.as_ptr() as usize {
+    last_col.extend(buffers.rows.iter().enumerate().map(|(i, &row)| {
+        if row == 0 {
             idx = i as u32;
         }
-        let mut row = row as usize + n - 1;
-        if row >= last_idx { row -= n; }
-        unsafe { *(row as *const u8) }
-    }).collect();
+        data[if row == 0 { n - 1 } else { row as usize - 1 }]
+    }));
 
-    (last_col, idx)
+    idx
 }
 
-pub fn ibwt(data: &[u8], start: u32) -> Vec<u8> {
+/// Inverts the BWT of `data`, whose original first row is row `start`.
+/// Returns `None` if `start` is out of range, as it may be in damaged input.
+pub fn ibwt(data: &[u8], start: u32) -> Option<Vec<u8>> {
     let n = data.len();
-    if n == 0 { return vec![]; }
-    assert!(n <= std::u32::MAX as usize);
+    if n == 0 { return Some(vec![]); }
+    if start as usize >= n || n > u32::MAX as usize { return None; }
 
-    let mut shortcut = Vec::with_capacity(n);
-    unsafe { shortcut.set_len(n); }
+    let mut shortcut = vec![0u32; n];
     let mut counts = [0u32; 256];
 
     for i in 0..n {
@@ -99,14 +186,14 @@ pub fn ibwt(data: &[u8], start: u32) -> Vec<u8> {
         counts[current_byte] += 1;
     }
 
-    let mut first_occ = [std::u32::MAX; 256];
+    let mut first_occ = [u32::MAX; 256];
     let total_distinct = counts.iter().filter(|n| **n > 0).count();
     let mut num_distinct = 0;
     let first_col = sorted(data, &counts);
 
-    for i in 0..n {
-        let idx = first_col[i] as usize;
-        if first_occ[idx] == std::u32::MAX {
+    for (i, &byte) in first_col.iter().enumerate() {
+        let idx = byte as usize;
+        if first_occ[idx] == u32::MAX {
             first_occ[idx] = i as _;
             num_distinct += 1;
             if num_distinct >= total_distinct {
@@ -124,23 +211,18 @@ pub fn ibwt(data: &[u8], start: u32) -> Vec<u8> {
         local_idx = first_occ[next_byte as usize] as usize + shortcut[local_idx] as usize;
     }
 
-    output
+    Some(output)
 }
 
 pub fn sorted(data: &[u8], counts: &[u32; 256]) -> Vec<u8> {
-    unsafe {
-        let mut output = Vec::with_capacity(data.len());
-        output.set_len(data.len());
-        let mut ptr: *mut u8 = output.as_mut_ptr();
-
-        for i in 0..256 {
-            let count = counts[i];
-            std::ptr::write_bytes(ptr, i as u8, count as usize);
-            ptr = ptr.offset(count as isize);
-        }
+    let mut output = Vec::with_capacity(data.len());
 
-        output
+    for (byte, &count) in counts.iter().enumerate() {
+        let len = output.len() + count as usize;
+        output.resize(len, byte as u8);
     }
+
+    output
 }
 
 pub fn naive_matrix_sort(data: &[u8]) -> Vec<u32> {
@@ -173,40 +255,47 @@ fn median(a: u8, b: u8, c: u8) -> u8 {
 }
 
 pub fn matrix_sort(data: &[u8]) -> Vec<*const u8> {
-    type Ptr = *const u8;
+    let mut buffers = BwtBuffers::default();
+    sort_rows(data, &mut buffers);
+    buffers.rows.iter().map(|&row| unsafe { data.as_ptr().add(row as usize) }).collect()
+}
+
+// Sorts the rotations of `data` into `buffers.rows`, each given by the
+// offset it starts at.
+fn sort_rows(data: &[u8], buffers: &mut BwtBuffers) {
+    type Row = u32;
 
+    // byte `d` of rotation `a[i]`
     #[inline]
-    fn ix(a: &[Ptr], i: usize, d: isize, n: usize, b: usize) -> u8 {
-        unsafe {
-            let mut ptr = a[i].offset(d) as usize;
-            if ptr >= b { ptr -= n; }
-            *(ptr as Ptr)
-        }
+    fn ix(data: &[u8], a: &[Row], i: usize, d: usize) -> u8 {
+        let mut at = a[i] as usize + d;
+        if at >= data.len() { at -= data.len(); }
+        data[at]
     }
 
-    fn pivot(a: &mut [Ptr], d: isize, s: usize, b: usize) -> u8 {
-        fn med3(a: &mut [Ptr], d: isize, s: usize, b: usize) -> u8 {
+    fn pivot(data: &[u8], a: &mut [Row], d: usize) -> u8 {
+        fn med3(data: &[u8], a: &mut [Row], d: usize) -> u8 {
             let li = 0;
             let mi = a.len() / 2;
             let ri = a.len() - 1;
 
-            let l = ix(a, li, d, s, b);
-            let m = ix(a, mi, d, s, b);
-            let r = ix(a, ri, d, s, b);
+            let l = ix(data, a, li, d);
+            let m = ix(data, a, mi, d);
+            let r = ix(data, a, ri, d);
 
             median(l, m, r)
         }
 
-        med3(a, d, s, b)
+        med3(data, a, d)
     }
 
-    fn partition(a: &mut [Ptr], d: isize, p: u8, s: usize, e: usize) -> (usize, usize) {
+    fn partition(data: &[u8], a: &mut [Row], d: usize, p: u8) -> (usize, usize) {
         let mut i = 0;
         let mut j = 0;
         let mut n = a.len() - 1;
 
         while j <= n {
-            match (ix(a, j, d, s, e)).cmp(&p) {
+            match (ix(data, a, j, d)).cmp(&p) {
                 Less => {
                     a.swap(i, j);
                     i += 1;
@@ -225,11 +314,11 @@ pub fn matrix_sort(data: &[u8]) -> Vec<*const u8> {
         (i, j)
     }
 
-    fn isort(a: &mut [Ptr], d: isize, s: usize, e: usize) {
+    fn isort(data: &[u8], a: &mut [Row], d: usize) {
 
-        fn cmp(a: Ptr, b: Ptr, mut d: isize, s: usize, e: usize) -> Ordering {
-            while (d as usize) < s {
-                match ix(&mut [a], 0, d, s, e).cmp(&ix(&mut [b], 0, d, s, e)) {
+        fn cmp(data: &[u8], a: Row, b: Row, mut d: usize) -> Ordering {
+            while d < data.len() {
+                match ix(data, &[a], 0, d).cmp(&ix(data, &[b], 0, d)) {
                     Equal => {}
                     non_eq => return non_eq,
                 }
@@ -242,7 +331,7 @@ pub fn matrix_sort(data: &[u8]) -> Vec<*const u8> {
         let mut i = 1;
         while i < a.len() {
             let mut j = i;
-            while j > 0 && cmp(a[j - 1], a[j], d, s, e) == Greater {
+            while j > 0 && cmp(data, a[j - 1], a[j], d) == Greater {
                 a.swap(j, j - 1);
                 j -= 1;
             }
@@ -251,29 +340,25 @@ pub fn matrix_sort(data: &[u8]) -> Vec<*const u8> {
     }
 
     let n = data.len();
-    let base = data.as_ptr();
-    let mut matrix = (0..n).map(|i| unsafe { base.offset(i as isize) }).collect::<Vec<_>>();
-
-    let s = n;
-    let e = base as usize + n;
-    {
-        let mut stack = vec![(&mut matrix[..], 0)];
-
-        while let Some((slice, depth)) = stack.pop() {
-            if depth as usize >= s { continue; }
-            if slice.len() < 10 { isort(slice, depth, s, e); continue; }
-            let p = pivot(slice, depth, s, e);
-            let (i, j) = partition(slice, depth, p, s, e);
-
-            let (first, rest) = slice.split_at_mut(i);
-            let (second, third) = rest.split_at_mut(j - i);
-
-            stack.push((first, depth));
-            stack.push((second, depth + 1));
-            stack.push((third, depth));
-        }
+    let BwtBuffers { ref mut rows, ref mut stack } = *buffers;
+    rows.clear();
+    rows.extend(0..n as Row);
+
+    // ranges of `rows` still to sort, and the depth they are sorted to
+    stack.clear();
+    stack.push((0, n, 0));
+
+    while let Some((start, end, depth)) = stack.pop() {
+        let slice = &mut rows[start..end];
+        if depth >= n { continue; }
+        if slice.len() < 10 { isort(data, slice, depth); continue; }
+        let p = pivot(data, slice, depth);
+        let (i, j) = partition(data, slice, depth, p);
+
+        stack.push((start, start + i, depth));
+        stack.push((start + i, start + j, depth + 1));
+        stack.push((start + j, end, depth));
     }
-    matrix
 }
 
 #[cfg(test)]
@@ -287,7 +372,7 @@ pub fn bwt_ref(data: &[u8]) -> (Vec<u8>, u32) {
     let n = data.len();
     let mut matrix = Vec::with_capacity(n);
 
-    let mut row: VecDeque<u8> = data.iter().cloned().collect();;
+    let mut row: VecDeque<u8> = data.iter().cloned().collect();
     for _ in 0..n {
         let b = row.pop_back().unwrap();
         row.push_front(b);
@@ -315,28 +400,13 @@ pub fn ibwt_ref(data: &[u8], start: u32) -> Vec<u8> {
     let mut matrix = (0..n).map(|_| VecDeque::with_capacity(n)).collect::<Vec<_>>();
 
     for _ in 0..n {
-        for (row, ch) in matrix.iter_mut().zip(&data[..]) {
+        for (row, ch) in matrix.iter_mut().zip(data) {
             row.push_front(*ch);
         }
         matrix.sort();
     }
 
-    std::mem::replace(&mut matrix[start as usize], Default::default()).into()
-}
-
-fn insertion_sort<T, F>(slice: &mut [T], cmp: F)
-    where T: Copy,
-          F: Fn(T, T) -> Ordering
-{
-    let mut i = 1;
-    while i < slice.len() {
-        let mut j = i;
-        while j > 0 && cmp(slice[j - 1], slice[j]) == Greater {
-            slice.swap(j, j - 1);
-            j -= 1;
-        }
-        i += 1;
-    }
+    std::mem::take(&mut matrix[start as usize]).into()
 }
 
 #[cfg(test)]
@@ -350,6 +420,15 @@ mod tests {
     use *;
 
     proptest! {
+        #[test]
+        fn ibwt_of_arbitrary_data(ref data in bytes_regex(".*").unwrap(), start in 0u32..64) {
+            // not every input is the BWT of something, but none may panic
+            match ibwt(data, start) {
+                Some(out) => prop_assert_eq!(out.len(), data.len()),
+                None => prop_assert!(start as usize >= data.len() && !data.is_empty()),
+            }
+        }
+
         #[ignore]
         #[test]
         fn test_sa(ref data in bytes_regex(".*").unwrap()) {
@@ -383,7 +462,7 @@ mod tests {
         #[test]
         fn bwt_round_trip(ref data in bytes_regex(".*").unwrap()) {
             let (bwt, idx) = bwt(&data[..]);
-            let out = ibwt(&bwt[..], idx);
+            let out = ibwt(&bwt[..], idx).unwrap();
             prop_assert_eq!(&out[..], &data[..])
         }
 
@@ -397,7 +476,7 @@ mod tests {
         #[test]
         fn test_ibwt(ref data in bytes_regex(".*").unwrap()) {
             let (bwt, idx) = bwt_ref(&data[..]);
-            let ibwt = ibwt(&bwt[..], idx);
+            let ibwt = ibwt(&bwt[..], idx).unwrap();
             prop_assert_eq!(&ibwt[..], &data[..]);
         }
 
@@ -416,14 +495,42 @@ mod tests {
         #[test]
         fn test_multi_key_quicksort(ref data in bytes_regex(".+").unwrap()) {
             let test_data = matrix_sort(data).into_iter().map(|e| unsafe {*e}).collect::<Vec<_>>();
-            let reference_data = naive_matrix_sort(data).into_iter().map(|e| data[e as usize]).collect::<Vec<_>>();;
+            let reference_data = naive_matrix_sort(data).into_iter().map(|e| data[e as usize]).collect::<Vec<_>>();
             prop_assert_eq!(test_data, reference_data);
         }
 
         #[test]
         fn test_initial_rle_encode(ref data in bytes_regex(".+").unwrap()) {
-            // let mut encoder = rle::Encoder::new();
-            
+            let mut encoder = rle::Encoder::new(data.len() * 2);
+            prop_assert_eq!(encoder.encode(data), data.len());
+            let decoded = rle::initial_decode(&encoder.finish());
+            prop_assert_eq!(&decoded[..], &data[..]);
+        }
+
+        #[test]
+        fn test_initial_rle_reuse(ref a in bytes_regex(".+").unwrap(), ref b in bytes_regex(".+").unwrap()) {
+            let mut encoder = rle::Encoder::new(a.len().max(b.len()) * 2);
+            for data in &[a, b] {
+                let mut fresh = rle::Encoder::new(data.len() * 2);
+                fresh.encode(data);
+                let crc = fresh.block_crc();
+
+                encoder.reset();
+                encoder.encode(data);
+                prop_assert_eq!(encoder.block_crc(), crc);
+                prop_assert_eq!(encoder.finish_block(), &fresh.finish()[..]);
+                prop_assert_eq!(encoder.block_crc(), crc);
+            }
+        }
+
+        #[test]
+        fn test_bwt_into_reuse(ref a in bytes_regex(".*").unwrap(), ref b in bytes_regex(".*").unwrap()) {
+            let mut buffers = BwtBuffers::default();
+            let mut last_col = vec![];
+            for data in &[a, b] {
+                let idx = bwt_into(data, &mut buffers, &mut last_col);
+                prop_assert_eq!((last_col.clone(), idx), bwt_ref(data));
+            }
         }
 
         // #[test]
@@ -1,5 +1,4 @@
-
-use std::{ptr, mem};
+use alloc::vec::Vec;
 
 pub struct Encoder<I> {
     table: [u8; 256],
@@ -54,78 +53,72 @@ impl<I> Iterator for Decoder<I> where I: Iterator<Item = u8> {
     type Item = u8;
     fn next(&mut self) -> Option<u8> {
         match self.src.next() {
-            Some(idx) => {
-                let idx = idx as usize;
-                let byte = self.table[idx];
-
-                if idx != 0 {
-                    // slow path
-                    shift_table(&mut self.table, idx);
-                    self.table[0] = byte;
-                }
-
-                Some(byte)
-            }
+            Some(idx) => Some(to_front(&mut self.table, idx as usize)),
             _ => None
         }
     }
 }
 
 
+// returns the byte at `idx`, moving it to the front of the table
+#[inline(always)]
+pub(crate) fn to_front(table: &mut [u8; 256], idx: usize) -> u8 {
+    let byte = table[idx];
+    if idx != 0 {
+        // slow path
+        shift_table(table, idx);
+        table[0] = byte;
+    }
+    byte
+}
+
 // moves a 'hole' at `idx` to index 0 by shifting [0..idx - 1] to [1..idx]
 #[inline(always)]
 fn shift_table(table: &mut [u8; 256], idx: usize) {
-    unsafe {
-        let src = table.as_ptr();
-        let dst = table.as_mut_ptr().offset(1);
-        ptr::copy(src, dst, idx);
-    }
+    table.copy_within(..idx, 1);
 }
 
 fn table() -> [u8; 256] {
-    let mut table: [u8; 256] = unsafe { mem::uninitialized() };
-    for i in 0..256 {
-        table[i] = i as u8;
+    let mut table = [0u8; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = i as u8;
     }
     table
 }
 
 pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut output = data.to_vec();
+    encode_in_place(&mut output);
+    output
+}
+
+/// Like `encode`, but replaces `data` with its encoding rather than
+/// allocating a new output.
+pub fn encode_in_place(data: &mut [u8]) {
     let mut table = table();
-    let mut output  = Vec::with_capacity(data.len());
-    unsafe { output.set_len(data.len()); }
 
-    for (byte, ptr) in data.iter().cloned().zip(output.iter_mut()) {
+    for ptr in data.iter_mut() {
+        let byte = *ptr;
         // fast path
         if table[0] == byte {
             *ptr = 0;
             continue;
         }
 
-        let idx = (&table[1..]).iter().position(|e| *e == byte).map(|i| i + 1).unwrap_or(0);
+        let idx = table[1..].iter().position(|e| *e == byte).map(|i| i + 1).unwrap_or(0);
         *ptr = idx as u8;
 
         shift_table(&mut table, idx);
         table[0] = byte;
     }
-
-    output
 }
 
 pub fn decode(data: &[u8]) -> Vec<u8> {
     let mut table = table();
-    let mut output = Vec::with_capacity(data.len());
-    unsafe { output.set_len(data.len()); }
+    let mut output = vec![0; data.len()];
 
     for (idx, ptr) in data.iter().cloned().zip(output.iter_mut()) {
-        let idx = idx as usize;
-        let byte = table[idx];
-        *ptr = byte;
-
-        if idx == 0 { continue; } // fast path
-
-        shift_table(&mut table, idx);
-        table[0] = byte;
+        *ptr = to_front(&mut table, idx as usize);
     }
 
     output
@@ -152,6 +145,13 @@ mod tests {
             prop_assert_eq!(&decoded, data);
         }
 
+        #[test]
+        fn test_encode_in_place(ref data in bytes_regex(".*").unwrap()) {
+            let mut encoded = data.clone();
+            encode_in_place(&mut encoded);
+            prop_assert_eq!(encoded, encode(data));
+        }
+
         #[test]
         fn test_encode_iter(ref data in bytes_regex(".*").unwrap()) {
             prop_assert_eq!(Encoder::new(data.iter().cloned()).collect::<Vec<_>>(), encode(data));
@@ -0,0 +1,719 @@
+//! Multithreaded compression.
+//!
+//! Once the initial run-length encoder has cut the input into blocks, each
+//! block is compressed independently of the others. `ParBzWriter` does the
+//! cutting on the calling thread and hands the blocks to a pool of workers,
+//! then stitches their output back together in order. Blocks are cut exactly
+//! where `BzWriter` cuts them and encoded by the same code, so the output is
+//! bit for bit the same as `BzWriter`'s.
+//!
+//! `par_decompress` goes the other way, which is harder because block
+//! boundaries are only known once the block before has been decoded. Like
+//! `lbzip2`, it scans ahead for block magics and decodes every candidate
+//! speculatively on the workers. A candidate is only used once the chain of
+//! blocks decoded in order reaches exactly its offset, and only if its CRC
+//! matched, so magics that merely occur inside compressed data are thrown
+//! away.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use bits::BitWriter;
+use block::{self, BLOCK_MAGIC, EOS_MAGIC};
+use compress::block_size;
+use error::{Error, Result};
+use index::{Entry, Index};
+use scan::{self, Marker};
+use {crc, rle};
+
+struct Job {
+    index: u64,
+    data: Vec<u8>,
+    crc: u32,
+}
+
+type Output = (u64, thread::Result<BitWriter>);
+
+// What is known of a block before it is compressed.
+struct Pending {
+    len: u64,
+    crc: u32,
+}
+
+/// Compresses everything written to it into a bzip2 stream written to `W`,
+/// using a pool of worker threads.
+///
+/// As with `BzWriter`, the stream is ended by `finish`, or failing that when
+/// the writer is dropped, in which case any error is lost.
+pub struct ParBzWriter<W: Write> {
+    inner: Option<W>,
+    level: u32,
+    rle: rle::Encoder,
+    out: BitWriter,
+    combined: u32,
+    // blocks handed to the workers, and appended to `out`
+    sent: u64,
+    written: u64,
+    // finished blocks waiting for an earlier one
+    pending: BTreeMap<u64, BitWriter>,
+    // blocks handed to the workers but not yet written
+    queued: VecDeque<Pending>,
+    block_in: u64,
+    record_index: bool,
+    index: Index,
+    max_in_flight: u64,
+    jobs: Option<Sender<Job>>,
+    results: Receiver<Output>,
+    workers: Vec<JoinHandle<()>>,
+    total_in: u64,
+    total_out: u64,
+    done: bool,
+}
+
+impl<W: Write> ParBzWriter<W> {
+    /// Creates a writer using blocks of `level` * 100k bytes, for `level` in
+    /// `1..=9`, and compressing them on `threads` worker threads.
+    ///
+    /// At most two blocks per thread are held in memory at once.
+    pub fn new(inner: W, level: u32, threads: usize) -> Self {
+        assert!((1..=9).contains(&level), "block size level must be 1..=9, got {}", level);
+        assert!(threads > 0, "at least one worker thread is needed");
+
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let (done, results) = mpsc::channel();
+        let workers = (0..threads).map(|_| {
+            let queue = queue.clone();
+            let done = done.clone();
+            thread::spawn(move || work(&queue, &done))
+        }).collect();
+
+        let mut out = BitWriter::new();
+        out.write(24, 0x42_5a_68);
+        out.write(8, b'0' as u32 + level);
+
+        ParBzWriter {
+            inner: Some(inner),
+            level,
+            rle: rle::Encoder::new(block_size(level)),
+            out,
+            combined: 0,
+            sent: 0,
+            written: 0,
+            pending: BTreeMap::new(),
+            queued: VecDeque::new(),
+            block_in: 0,
+            record_index: false,
+            index: Index::new(),
+            max_in_flight: 2 * threads as u64,
+            jobs: Some(jobs),
+            results,
+            workers,
+            total_in: 0,
+            total_out: 0,
+            done: false,
+        }
+    }
+
+    /// Records the position of every block in `index`, which is off by
+    /// default. It can only be changed before anything is written.
+    pub fn record_index(mut self, record_index: bool) -> Self {
+        assert!(self.total_in == 0, "the index can only be switched before anything is written");
+        self.record_index = record_index;
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// The number of uncompressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// The number of compressed bytes written to the inner writer so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// The positions of the blocks written so far, complete once the stream
+    /// is finished, if `record_index` was set.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Ends the stream, waits for every block and writes out the rest of the
+    /// stream, without giving up the inner writer.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.end_block()?;
+        while self.written < self.sent {
+            self.collect(true)?;
+        }
+        self.out.write_u48(EOS_MAGIC);
+        self.out.write(32, self.combined);
+        self.out.align();
+        self.dump()?;
+        self.done = true;
+
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+
+    /// Ends the stream and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        if self.rle.is_empty() {
+            return Ok(());
+        }
+        let crc = self.rle.block_crc();
+        let rle = mem::replace(&mut self.rle, rle::Encoder::new(block_size(self.level)));
+        self.combined = crc::combine(self.combined, crc);
+
+        let job = Job {
+            index: self.sent,
+            data: rle.finish(),
+            crc,
+        };
+        if self.jobs.as_ref().unwrap().send(job).is_err() {
+            return Err(worker_lost());
+        }
+        self.queued.push_back(Pending {
+            len: self.block_in,
+            crc,
+        });
+        self.block_in = 0;
+        self.sent += 1;
+
+        self.collect(false)?;
+        while self.sent - self.written >= self.max_in_flight {
+            self.collect(true)?;
+        }
+        Ok(())
+    }
+
+    // Gathers finished blocks, waiting for one if `wait` is set, and writes
+    // out those that are next in line.
+    fn collect(&mut self, wait: bool) -> io::Result<()> {
+        if wait {
+            let (index, result) = self.results.recv().map_err(|_| worker_lost())?;
+            self.pending.insert(index, result.map_err(|_| worker_lost())?);
+        }
+        while let Ok((index, result)) = self.results.try_recv() {
+            self.pending.insert(index, result.map_err(|_| worker_lost())?);
+        }
+
+        while let Some(block) = self.pending.remove(&self.written) {
+            let start = self.out.position();
+            self.out.append(&block);
+            self.written += 1;
+
+            let Pending { len, crc } = self.queued.pop_front().unwrap();
+            if self.record_index {
+                let offset = self.index.len();
+                self.index.push(Entry {
+                    bits: start..self.out.position(),
+                    bytes: offset..offset + len,
+                    level: self.level,
+                    crc,
+                });
+            }
+        }
+        self.dump()
+    }
+
+    fn dump(&mut self) -> io::Result<()> {
+        let n = self.out.bytes().len();
+        self.inner.as_mut().unwrap().write_all(self.out.bytes())?;
+        self.out.drain(n);
+        self.total_out += n as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParBzWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Err(io::Error::other("write after the stream was finished"));
+        }
+        loop {
+            let consumed = self.rle.encode(data);
+            self.total_in += consumed as u64;
+            self.block_in += consumed as u64;
+            if consumed < data.len() {
+                // the block is full
+                self.end_block()?;
+            }
+            if consumed > 0 || data.is_empty() {
+                return Ok(consumed);
+            }
+        }
+    }
+
+    /// Flushes the inner writer. Blocks still being filled or compressed are
+    /// not affected.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for ParBzWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+fn work(queue: &Mutex<Receiver<Job>>, done: &Sender<Output>) {
+    // every block starts by clearing the buffers, so they can be reused
+    // even after one panics
+    let mut encoder = block::Encoder::new();
+    loop {
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut out = BitWriter::new();
+            encoder.encode(&job.data, job.crc, &mut out);
+            out
+        }));
+        if done.send((job.index, result)).is_err() {
+            return;
+        }
+    }
+}
+
+fn worker_lost() -> io::Error {
+    io::Error::other("a compression worker thread failed")
+}
+
+/// Decompresses `data`, which may hold concatenated streams, into `out`
+/// using `threads` worker threads. Returns the number of bytes written.
+///
+/// The output and any error are the same as `BzReader` would give for the
+/// same data.
+pub fn par_decompress<W: Write>(data: &[u8], mut out: W, threads: usize) -> io::Result<u64> {
+    let mut written = 0;
+    decode_blocks(data, threads, |_, output| {
+        written += output.len() as u64;
+        out.write_all(output)
+    })?;
+    Ok(written)
+}
+
+// Decodes every block of `data` in parallel, passing each one's place in the
+// input and output, and its output, to `sink` in order.
+pub(crate) fn decode_blocks<F>(data: &[u8], threads: usize, sink: F) -> io::Result<()>
+    where F: FnMut(Entry, &[u8]) -> io::Result<()>
+{
+    assert!(threads > 0, "at least one worker thread is needed");
+    let candidates = scan::scan_blocks(data).filter_map(|marker| match marker {
+        Marker::Block(block) => Some(block.bits.start),
+        Marker::StreamEnd { .. } => None,
+    });
+    decode_blocks_with(data, candidates, threads, sink)
+}
+
+type Decoded = (u32, thread::Result<Result<(Vec<u8>, u64)>>);
+
+fn decode_blocks_with<I, F>(data: &[u8], candidates: I, threads: usize, mut sink: F) -> io::Result<()>
+    where I: Iterator<Item = u64>, F: FnMut(Entry, &[u8]) -> io::Result<()>
+{
+    let (jobs, queue) = mpsc::channel::<(u64, u64, u32)>();
+    let queue = Mutex::new(queue);
+    let (done, results) = mpsc::channel::<(u64, Decoded)>();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let queue = &queue;
+            let done = done.clone();
+            scope.spawn(move || {
+                let mut decoder = block::Decoder::new();
+                loop {
+                    let (id, start, level) = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    // the block number is only known once the chain reaches
+                    // the candidate, so errors are reproduced by decoding it
+                    // again
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        decoder.decode_at(data, 0, start, 0, level)
+                    }));
+                    if done.send((id, (level, result))).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(done);
+
+        let mut chain = Chain {
+            data,
+            candidates,
+            jobs,
+            results,
+            max_in_flight: 2 * threads,
+            queued: VecDeque::new(),
+            next_id: 0,
+            in_flight: 0,
+            decoded: HashMap::new(),
+            discarded: HashSet::new(),
+            decoder: block::Decoder::new(),
+            level: 0,
+            pos: 0,
+            blocks: 0,
+            combined: 0,
+            total_out: 0,
+        };
+        // dropping the chain on the way out closes the job queue, letting
+        // the workers exit
+        chain.run(&mut sink)
+    })
+}
+
+// Follows the blocks of the input in order, using speculatively decoded
+// candidates where they line up.
+struct Chain<'a, I> {
+    data: &'a [u8],
+    candidates: I,
+    jobs: Sender<(u64, u64, u32)>,
+    results: Receiver<(u64, Decoded)>,
+    max_in_flight: usize,
+    // candidates handed to the workers, as (id, bit offset)
+    queued: VecDeque<(u64, u64)>,
+    next_id: u64,
+    in_flight: usize,
+    decoded: HashMap<u64, Decoded>,
+    // candidates left behind by the chain whose results are still to come
+    discarded: HashSet<u64>,
+    decoder: block::Decoder,
+    level: u32,
+    pos: u64,
+    blocks: u64,
+    combined: u32,
+    total_out: u64,
+}
+
+impl<'a, I: Iterator<Item = u64>> Chain<'a, I> {
+    fn run<F>(&mut self, sink: &mut F) -> io::Result<()>
+        where F: FnMut(Entry, &[u8]) -> io::Result<()>
+    {
+        self.header(false)?;
+        loop {
+            self.dispatch();
+            match self.magic() {
+                Some(BLOCK_MAGIC) => {
+                    let (output, end) = self.block()?;
+                    // decoding checked the output against the stored CRC
+                    let crc = scan::read_u32(self.data, self.pos + 48);
+                    let entry = Entry {
+                        bits: self.pos..end,
+                        bytes: self.total_out..self.total_out + output.len() as u64,
+                        level: self.level,
+                        crc,
+                    };
+                    sink(entry, &output)?;
+                    self.combined = crc::combine(self.combined, crc);
+                    self.total_out += output.len() as u64;
+                    self.blocks += 1;
+                    self.pos = end;
+                }
+                Some(EOS_MAGIC) => {
+                    if self.pos + 80 > self.len() {
+                        return Err(self.eof().into());
+                    }
+                    let expected = scan::read_u32(self.data, self.pos + 48);
+                    if expected != self.combined {
+                        return Err(Error::StreamCrcMismatch {
+                            expected,
+                            actual: self.combined,
+                            block: self.blocks,
+                            bit_offset: self.pos + 48,
+                        }.into());
+                    }
+                    self.pos = (self.pos + 80).div_ceil(8) * 8;
+                    if self.pos == self.len() {
+                        return Ok(());
+                    }
+                    self.header(true)?;
+                }
+                Some(_) => {
+                    return Err(Error::BadMagic {
+                        block: self.blocks,
+                        bit_offset: self.pos,
+                    }.into());
+                }
+                None => return Err(self.eof().into()),
+            }
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.data.len() as u64 * 8
+    }
+
+    fn eof(&self) -> Error {
+        Error::UnexpectedEof {
+            block: self.blocks,
+            bit_offset: self.len(),
+        }
+    }
+
+    // Reads the stream header at `pos`, which is byte aligned.
+    fn header(&mut self, more: bool) -> Result<()> {
+        let at = (self.pos / 8) as usize;
+        let header = &self.data[at..self.data.len().min(at + 4)];
+        if more && (header.len() < 4 || &header[..3] != b"BZh") {
+            return Err(Error::TrailingGarbage {
+                block: self.blocks,
+                bit_offset: self.pos,
+            });
+        }
+        if header.len() < 4 {
+            return Err(self.eof());
+        }
+        if &header[..3] != b"BZh" {
+            return Err(Error::BadMagic {
+                block: self.blocks,
+                bit_offset: self.pos,
+            });
+        }
+        if !(b'1'..=b'9').contains(&header[3]) {
+            return Err(Error::UnsupportedBlockSize {
+                level: header[3],
+                block: self.blocks,
+                bit_offset: self.pos + 24,
+            });
+        }
+        self.level = (header[3] - b'0') as u32;
+        self.pos += 32;
+        self.combined = 0;
+        Ok(())
+    }
+
+    // The 48 bits at `pos`, if there are that many left.
+    fn magic(&self) -> Option<u64> {
+        if self.pos + 48 > self.len() {
+            return None;
+        }
+        let high = scan::read_u32(self.data, self.pos) as u64;
+        let low = scan::read_u32(self.data, self.pos + 32) as u64 >> 16;
+        Some(high << 16 | low)
+    }
+
+    // Keeps the workers supplied with candidates.
+    fn dispatch(&mut self) {
+        while self.in_flight < self.max_in_flight {
+            let start = match self.candidates.next() {
+                Some(start) => start,
+                None => return,
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            // the workers outlive the sender, so this cannot fail
+            let _ = self.jobs.send((id, start, self.level));
+            self.queued.push_back((id, start));
+            self.in_flight += 1;
+        }
+    }
+
+    // Decodes the block at `pos`, preferring a candidate's result.
+    fn block(&mut self) -> Result<(Vec<u8>, u64)> {
+        while let Some(&(id, start)) = self.queued.front() {
+            if start >= self.pos {
+                break;
+            }
+            self.queued.pop_front();
+            if self.decoded.remove(&id).is_none() {
+                self.discarded.insert(id);
+            }
+        }
+
+        if let Some(&(id, start)) = self.queued.front() {
+            if start == self.pos {
+                self.queued.pop_front();
+                if let (level, Ok(Ok(block))) = self.wait(id) {
+                    if level == self.level {
+                        return Ok(block);
+                    }
+                }
+            }
+        }
+        // no usable candidate, or one that failed: decode the block here to
+        // report the error properly
+        self.decoder.decode_at(self.data, 0, self.pos, self.blocks, self.level)
+    }
+
+    fn wait(&mut self, id: u64) -> Decoded {
+        loop {
+            if let Some(decoded) = self.decoded.remove(&id) {
+                return decoded;
+            }
+            let (done, decoded) = match self.results.recv() {
+                Ok(result) => result,
+                // a worker died; fall back to decoding here
+                Err(_) => return (0, Ok(Err(self.eof()))),
+            };
+            self.in_flight -= 1;
+            if !self.discarded.remove(&done) {
+                self.decoded.insert(done, decoded);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use super::*;
+    use {BzReader, BzWriter};
+
+    fn serial(data: &[u8], level: u32) -> Vec<u8> {
+        let mut writer = BzWriter::new(vec![], level);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn parallel(data: &[u8], level: u32, threads: usize, chunk: usize) -> Vec<u8> {
+        let mut writer = ParBzWriter::new(vec![], level, threads);
+        for chunk in data.chunks(chunk) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 98765u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 4 {
+                0 => (x >> 8) as u8,
+                1 => b'a' + (x >> 24) as u8 % 8,
+                _ => (i / 700) as u8,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn matches_serial_output() {
+        let mut data = sample(600_000);
+        data.extend(vec![b'x'; 50_000]);
+        let expected = serial(&data, 1);
+
+        for &threads in &[1, 3, 8] {
+            for &chunk in &[1000, 65_536, data.len()] {
+                assert_eq!(parallel(&data, 1, threads, chunk), expected);
+            }
+        }
+
+        let mut out = vec![];
+        BzReader::new(&expected[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn small_streams_match_serial_output() {
+        assert_eq!(parallel(b"", 9, 2, 1), serial(b"", 9));
+        assert_eq!(parallel(b"hello", 9, 2, 1), serial(b"hello", 9));
+    }
+
+    fn serial_decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        BzReader::new(data).read_to_end(&mut out).map(|_| out)
+    }
+
+    fn parallel_decode(data: &[u8], threads: usize) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        let written = par_decompress(data, &mut out, threads)?;
+        assert_eq!(written, out.len() as u64);
+        Ok(out)
+    }
+
+    fn same_error(data: &[u8]) {
+        let expected = serial_decode(data).unwrap_err();
+        let actual = parallel_decode(data, 3).unwrap_err();
+        assert_eq!(actual.kind(), expected.kind());
+        assert_eq!(actual.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn decompresses_in_parallel() {
+        let data = sample(650_000);
+        let mut compressed = serial(&data, 1);
+        for &threads in &[1, 2, 8] {
+            assert_eq!(parallel_decode(&compressed, threads).unwrap(), data);
+        }
+
+        compressed.extend(serial(b"and a second stream", 3));
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"and a second stream");
+        assert_eq!(parallel_decode(&compressed, 4).unwrap(), expected);
+    }
+
+    #[test]
+    fn ignores_false_candidates() {
+        let data = sample(450_000);
+        let compressed = serial(&data, 1);
+        let real: Vec<_> = scan::scan_blocks(&compressed).filter_map(|marker| match marker {
+            Marker::Block(block) => Some(block.bits.start),
+            Marker::StreamEnd { .. } => None,
+        }).collect();
+
+        // bogus offsets everywhere, with some of the real blocks missing
+        let mut candidates: Vec<u64> = (0..compressed.len() as u64 * 8).step_by(997).collect();
+        candidates.extend(real.iter().skip(1).step_by(2));
+        candidates.sort();
+
+        let mut out = vec![];
+        decode_blocks_with(&compressed, candidates.into_iter(), 4, |_, output| {
+            out.extend_from_slice(output);
+            Ok(())
+        }).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn reports_the_same_errors() {
+        let compressed = serial(&sample(250_000), 1);
+
+        let mut bad_block = compressed.clone();
+        bad_block[compressed.len() / 2] ^= 0x10;
+        same_error(&bad_block);
+
+        let mut bad_crc = compressed.clone();
+        bad_crc[10] ^= 1;
+        same_error(&bad_crc);
+
+        same_error(&compressed[..compressed.len() - 3]);
+        same_error(&compressed[..compressed.len() / 3]);
+        same_error(b"BZh0");
+        same_error(b"not bzip2");
+
+        let mut garbage = compressed.clone();
+        garbage.extend_from_slice(b"trailing");
+        same_error(&garbage);
+    }
+}
@@ -0,0 +1,339 @@
+use std::io::{self, BufReader, Read};
+
+use bufread;
+use decompress::Limits;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Decompresses bzip2 data read from `R`.
+///
+/// Concatenated streams, such as those written by `pbzip2`, are decoded one
+/// after another unless `multi_stream(false)` is set.
+pub struct BzReader<R> {
+    inner: bufread::BzReader<BufReader<R>>,
+}
+
+impl<R: Read> BzReader<R> {
+    pub fn new(inner: R) -> Self {
+        BzReader {
+            inner: bufread::BzReader::new(BufReader::with_capacity(BUF_SIZE, inner)),
+        }
+    }
+
+    /// Sets whether to go on decoding streams that follow the first one.
+    /// When this is off, reading stops at the end of the first stream,
+    /// `total_in` reports its length and `unused` holds the input that was
+    /// read past it.
+    pub fn multi_stream(self, multi_stream: bool) -> Self {
+        BzReader {
+            inner: self.inner.multi_stream(multi_stream),
+        }
+    }
+
+    /// Sets the limits past which reading fails rather than produce more
+    /// output, for decoding untrusted input without running out of memory
+    /// or disk.
+    pub fn limits(self, limits: Limits) -> Self {
+        BzReader {
+            inner: self.inner.limits(limits),
+        }
+    }
+}
+
+impl<R> BzReader<R> {
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref().get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Returns the inner reader. Any input that was read from it but not
+    /// decoded, as returned by `unused`, is lost.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner().into_inner()
+    }
+
+    /// Input that was read from the inner reader but not consumed by the
+    /// decoder.
+    pub fn unused(&self) -> &[u8] {
+        self.inner.get_ref().buffer()
+    }
+
+    /// The number of compressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// The number of decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+impl<R: Read> Read for BzReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2::Compression;
+    use bzip2::write::BzEncoder;
+    use proptest::string::*;
+
+    use super::*;
+    use Error;
+
+    fn reference(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decode(compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        BzReader::new(compressed).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn decode_err(compressed: &[u8]) -> Error {
+        let err = decode(compressed).unwrap_err();
+        *err.into_inner().unwrap().downcast::<Error>().unwrap()
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 12345u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 4 {
+                0 => (x >> 8) as u8,
+                1 => b'a' + (x >> 24) as u8 % 8,
+                _ => (i / 300) as u8,
+            }
+        }).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn decodes_reference_streams(ref data in bytes_regex(".*").unwrap()) {
+            let compressed = reference(data, Compression::Best);
+            prop_assert_eq!(&decode(&compressed).unwrap()[..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn decodes_multiple_blocks() {
+        let data = sample(350_000);
+        let compressed = reference(&data, Compression::Fastest);
+        assert_eq!(decode(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn decodes_long_runs() {
+        let mut data = vec![7; 1000];
+        data.extend(vec![0; 255]);
+        data.extend(vec![1; 256]);
+        data.extend(vec![2; 4]);
+        data.extend(vec![3; 5]);
+        data.extend(sample(1000));
+        let compressed = reference(&data, Compression::Default);
+        assert_eq!(decode(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn decodes_with_tiny_buffers() {
+        let data = sample(20_000);
+        let compressed = reference(&data, Compression::Best);
+
+        let mut reader = bufread::BzReader::new(BufReader::with_capacity(1, &compressed[..]));
+        let mut out = vec![];
+        let mut byte = [0];
+        while reader.read(&mut byte).unwrap() == 1 {
+            out.push(byte[0]);
+        }
+        assert_eq!(out, data);
+        assert_eq!(reader.total_in(), compressed.len() as u64);
+    }
+
+    #[test]
+    fn bad_magic() {
+        assert_eq!(decode_err(b"BZx9"), Error::BadMagic { block: 0, bit_offset: 0 });
+
+        let mut compressed = reference(b"hello", Compression::Best);
+        compressed[4] ^= 1;
+        assert_eq!(decode_err(&compressed), Error::BadMagic { block: 0, bit_offset: 32 });
+    }
+
+    #[test]
+    fn unsupported_block_size() {
+        let err = decode_err(b"BZh0");
+        assert_eq!(err, Error::UnsupportedBlockSize { level: b'0', block: 0, bit_offset: 24 });
+    }
+
+    #[test]
+    fn block_crc_mismatch() {
+        let data = sample(350_000);
+        let mut compressed = reference(&data, Compression::Fastest);
+        compressed[10] ^= 0x80;
+        match decode_err(&compressed) {
+            Error::BlockCrcMismatch { expected, actual, block: 0, bit_offset: 80 } => {
+                assert_eq!(expected ^ actual, 0x8000_0000);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn stream_crc_mismatch() {
+        let mut compressed = reference(b"", Compression::Best);
+        assert_eq!(compressed.len(), 14);
+        compressed[13] = 1;
+        let err = decode_err(&compressed);
+        assert_eq!(err, Error::StreamCrcMismatch { expected: 1, actual: 0, block: 0, bit_offset: 80 });
+    }
+
+    #[test]
+    fn unexpected_eof() {
+        let compressed = reference(&sample(1000), Compression::Best);
+        for len in &[0, 3, 10, compressed.len() / 2, compressed.len() - 1] {
+            let err = decode(&compressed[..*len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        let a = sample(150_000);
+        let b = b"second stream".to_vec();
+        let c = sample(1000);
+
+        let mut compressed = reference(&a, Compression::Fastest);
+        let first_len = compressed.len();
+        compressed.extend(reference(&b, Compression::Best));
+        compressed.extend(reference(b"", Compression::Default));
+        compressed.extend(reference(&c, Compression::Best));
+
+        let mut expected = a.clone();
+        expected.extend(&b);
+        expected.extend(&c);
+        assert_eq!(decode(&compressed).unwrap(), expected);
+
+        let mut reader = BzReader::new(&compressed[..]).multi_stream(false);
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, a);
+        assert_eq!(reader.total_in(), first_len as u64);
+        assert_eq!(reader.unused(), &compressed[first_len..]);
+    }
+
+    #[test]
+    fn concatenated_stream_crc_mismatch() {
+        let mut compressed = reference(&sample(150_000), Compression::Fastest);
+        let len = compressed.len();
+        compressed.extend(reference(b"", Compression::Best));
+        compressed[len + 13] = 1;
+        let err = decode_err(&compressed);
+        assert_eq!(err, Error::StreamCrcMismatch { expected: 1, actual: 0, block: 2, bit_offset: (len as u64 + 10) * 8 });
+    }
+
+    #[test]
+    fn trailing_garbage() {
+        let mut compressed = reference(b"hello", Compression::Best);
+        let len = compressed.len() as u64;
+        compressed.extend(b"garbage");
+        let err = decode_err(&compressed);
+        assert_eq!(err, Error::TrailingGarbage { block: 1, bit_offset: len * 8 });
+
+        compressed.truncate(len as usize + 2);
+        let err = decode_err(&compressed);
+        assert_eq!(err, Error::TrailingGarbage { block: 1, bit_offset: len * 8 });
+
+        // everything before the garbage is still read
+        let mut out = vec![];
+        assert!(BzReader::new(&compressed[..]).read_to_end(&mut out).is_err());
+        assert_eq!(out, b"hello");
+
+        let mut out = vec![];
+        BzReader::new(&compressed[..]).multi_stream(false).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn corruption_never_panics() {
+        let compressed = reference(&sample(5000), Compression::Best);
+        for i in 4..compressed.len() {
+            let mut corrupt = compressed.clone();
+            corrupt[i] ^= 0x10;
+            let _ = decode(&corrupt);
+        }
+    }
+
+    fn decode_limited(compressed: &[u8], limits: Limits) -> (Vec<u8>, io::Result<usize>) {
+        let mut out = vec![];
+        let result = BzReader::new(compressed).limits(limits).read_to_end(&mut out);
+        (out, result)
+    }
+
+    fn limit_err(result: io::Result<usize>) -> Error {
+        *result.unwrap_err().into_inner().unwrap().downcast::<Error>().unwrap()
+    }
+
+    #[test]
+    fn output_and_ratio_limits() {
+        let zeros = vec![0; 1_000_000];
+        let compressed = reference(&zeros, Compression::Best);
+        assert!(compressed.len() < 100);
+
+        let (out, result) = decode_limited(&compressed, Limits { max_output: Some(1_000_000), ..Limits::default() });
+        assert_eq!(out, zeros);
+        assert_eq!(result.unwrap(), 1_000_000);
+
+        let (out, result) = decode_limited(&compressed, Limits { max_output: Some(999_999), ..Limits::default() });
+        assert_eq!(out.len(), 999_999);
+        match limit_err(result) {
+            Error::OutputLimitExceeded { limit: 999_999, block: 0, .. } => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let (out, result) = decode_limited(&compressed, Limits { max_ratio: Some(1000), ..Limits::default() });
+        assert!(out.len() as u64 <= 1000 * compressed.len() as u64);
+        match limit_err(result) {
+            Error::RatioLimitExceeded { limit: 1000, .. } => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let data = sample(100_000);
+        let (out, result) = decode_limited(&reference(&data, Compression::Best),
+                                           Limits { max_ratio: Some(100), ..Limits::default() });
+        result.unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn stream_and_block_limits() {
+        let data = sample(250_000);
+        let mut compressed = reference(&data, Compression::Fastest);
+        let first_len = compressed.len() as u64;
+        compressed.extend(reference(b"second", Compression::Best));
+
+        let (out, result) = decode_limited(&compressed, Limits { max_streams: Some(2), max_blocks: Some(4),
+                                                                 ..Limits::default() });
+        result.unwrap();
+        assert_eq!(out.len(), data.len() + 6);
+
+        let (out, result) = decode_limited(&compressed, Limits { max_streams: Some(1), ..Limits::default() });
+        assert_eq!(out, data);
+        assert_eq!(limit_err(result), Error::StreamLimitExceeded { limit: 1, block: 3, bit_offset: first_len * 8 });
+
+        let (_, result) = decode_limited(&compressed, Limits { max_blocks: Some(2), ..Limits::default() });
+        match limit_err(result) {
+            Error::BlockLimitExceeded { limit: 2, block: 2, .. } => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+}
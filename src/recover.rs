@@ -0,0 +1,338 @@
+//! Salvaging what is left of damaged bzip2 data, as `bzip2recover` does.
+//!
+//! Blocks are independent, so damage loses only the blocks it touches.
+//! `Recover` finds every block magic in the input, at any bit offset, and
+//! decodes the block there on its own. A block that decodes and matches its
+//! CRC is intact; any other is reported with the error that stopped it, as
+//! running from its magic to the next magic, which is the best guess of
+//! where it ends.
+//!
+//! Every block can also be written out as a stream of its own with
+//! `Recovered::to_stream`, which is what `bzip2recover` leaves behind.
+//!
+//! The input is read once, front to back, and only a few blocks' worth of
+//! it is held at a time, so there is no limit on its size.
+
+use std::io::{self, Read};
+use std::ops::Range;
+
+use bits::BitWriter;
+use block::{self, BLOCK_MAGIC, EOS_MAGIC, MAX_BLOCK_BITS};
+use error::Error;
+use scan;
+
+const CHUNK: usize = 1 << 20;
+
+/// A block found by `Recover`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recovered {
+    /// The position of the block among all the blocks found, counting from
+    /// zero.
+    pub index: u64,
+    /// The bits of the input the block occupies. For a damaged block, these
+    /// run to the next magic.
+    pub bits: Range<u64>,
+    /// The CRC stored in the block header.
+    pub crc: u32,
+    /// The block's data, or the error that stopped it decoding.
+    pub result: Result<Vec<u8>, Error>,
+    // the block's bits, from its magic on
+    raw: Vec<u8>,
+}
+
+impl Recovered {
+    /// Whether the block decoded and matched its CRC.
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// The block as a complete bzip2 stream on its own.
+    pub fn to_stream(&self) -> Vec<u8> {
+        let mut out = BitWriter::new();
+        out.write(24, 0x42_5a_68);
+        out.write(8, b'9' as u32);
+        let len = self.bits.end - self.bits.start;
+        for (i, &byte) in self.raw.iter().enumerate() {
+            let n = (len - i as u64 * 8).min(8) as u32;
+            out.write(n, byte as u32 >> (8 - n));
+        }
+        out.write_u48(EOS_MAGIC);
+        // the combined CRC of a single block is its own
+        out.write(32, self.crc);
+        out.align();
+        out.bytes().to_owned()
+    }
+}
+
+/// Iterator over every block in some possibly damaged bzip2 data.
+///
+/// Stream headers and ends are skipped over, so blocks of concatenated
+/// streams are all found. Only errors reading the input are returned as
+/// errors; damaged blocks are reported through `Recovered::result`.
+pub struct Recover<R> {
+    inner: R,
+    chunk: usize,
+    // the input from byte `base` on
+    window: Vec<u8>,
+    base: u64,
+    eof: bool,
+    // the bit offset to search for the next block from
+    pos: u64,
+    blocks: u64,
+    decoder: block::Decoder,
+    done: bool,
+}
+
+impl<R: Read> Recover<R> {
+    pub fn new(inner: R) -> Self {
+        Recover::with_chunk(inner, CHUNK)
+    }
+
+    fn with_chunk(inner: R, chunk: usize) -> Self {
+        Recover {
+            inner,
+            chunk,
+            window: Vec::new(),
+            base: 0,
+            eof: false,
+            pos: 0,
+            blocks: 0,
+            decoder: block::Decoder::new(),
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn next_block(&mut self) -> io::Result<Option<Recovered>> {
+        loop {
+            let start = match self.find(self.pos, None, u64::MAX)? {
+                Some((start, BLOCK_MAGIC)) => start,
+                Some((start, _)) => {
+                    self.pos = start + 48;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+
+            let index = self.blocks;
+            self.blocks += 1;
+            let (end, result) = match self.decode(start, index)? {
+                Ok((data, end)) => (end, Ok(data)),
+                Err(err) => {
+                    let limit = start + MAX_BLOCK_BITS;
+                    let end = match self.find(start + 48, Some(start), limit)? {
+                        Some((next, _)) => next,
+                        None => limit.min(self.window_end()),
+                    };
+                    (end, Err(err))
+                }
+            };
+
+            let at = start - self.base * 8;
+            let recovered = Recovered {
+                index,
+                bits: start..end,
+                crc: scan::read_u32(&self.window, at + 48),
+                result,
+                raw: self.copy_bits(at, end - start),
+            };
+            self.pos = end;
+            return Ok(Some(recovered));
+        }
+    }
+
+    // Finds the first magic at or after bit `from`, and before bit `limit`,
+    // keeping the input from bit `keep`, or else from where the search has
+    // got to, on.
+    fn find(&mut self, mut from: u64, keep: Option<u64>, limit: u64) -> io::Result<Option<(u64, u64)>> {
+        loop {
+            if let Some((start, magic)) = scan::find_magic(&self.window, from - self.base * 8) {
+                let start = self.base * 8 + start;
+                return Ok(if start < limit { Some((start, magic)) } else { None });
+            }
+            let searched = self.window_end();
+            if searched >= limit || !self.fill()? {
+                return Ok(None);
+            }
+            // a magic may straddle what was there and what was read
+            from = from.max(searched.saturating_sub(47));
+            self.trim(keep.unwrap_or(from));
+        }
+    }
+
+    // Decodes the block at bit `start`, reading more of the input as needed.
+    fn decode(&mut self, start: u64, index: u64) -> io::Result<Result<(Vec<u8>, u64), Error>> {
+        self.trim(start);
+        loop {
+            match self.decoder.decode_at(&self.window, self.base, start, index, 9) {
+                Err(Error::UnexpectedEof { .. }) if !self.eof => {
+                    self.fill()?;
+                }
+                result => return Ok(result),
+            }
+        }
+    }
+
+    // Copies `len` bits of the window from bit `at` on.
+    fn copy_bits(&self, at: u64, len: u64) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len.div_ceil(8) as usize);
+        let mut done = 0;
+        while done < len {
+            let word = scan::read_u32(&self.window, at + done).to_be_bytes();
+            let n = (len - done).min(32);
+            out.extend_from_slice(&word[..n.div_ceil(8) as usize]);
+            done += n;
+        }
+        out
+    }
+
+    fn window_end(&self) -> u64 {
+        (self.base + self.window.len() as u64) * 8
+    }
+
+    // Reads another chunk of the input, returning false at its end.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let len = self.window.len();
+        self.window.resize(len + self.chunk, 0);
+        let n = loop {
+            match self.inner.read(&mut self.window[len..]) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.window.truncate(len);
+                    return Err(e);
+                }
+            }
+        };
+        self.window.truncate(len + n);
+        if n == 0 {
+            self.eof = true;
+        }
+        Ok(n > 0)
+    }
+
+    // Drops the input before bit `pos` once there is enough of it.
+    fn trim(&mut self, pos: u64) {
+        let n = (pos / 8 - self.base) as usize;
+        if n >= self.chunk {
+            self.window.drain(..n);
+            self.base += n as u64;
+        }
+    }
+}
+
+impl<R: Read> Iterator for Recover<R> {
+    type Item = io::Result<Recovered>;
+
+    fn next(&mut self) -> Option<io::Result<Recovered>> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_block();
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2::read::BzDecoder;
+
+    use super::*;
+    use {BzWriter, Index};
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 2024u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 3 {
+                0 => (x >> 8) as u8,
+                _ => (i / 300) as u8,
+            }
+        }).collect()
+    }
+
+    fn compressed(data: &[u8]) -> (Vec<u8>, Index) {
+        let mut writer = BzWriter::new(vec![], 1).record_index(true);
+        writer.write_all(data).unwrap();
+        writer.try_finish().unwrap();
+        let index = writer.index().clone();
+        (writer.finish().unwrap(), index)
+    }
+
+    fn recover(data: &[u8], chunk: usize) -> Vec<Recovered> {
+        Recover::with_chunk(data, chunk).map(|block| block.unwrap()).collect()
+    }
+
+    #[test]
+    fn finds_every_block() {
+        let mut data = sample(250_000);
+        let (mut compressed, index) = compressed(&data);
+        compressed.extend(self::compressed(b"a second stream").0);
+        data.extend_from_slice(b"a second stream");
+
+        for &chunk in &[997, CHUNK] {
+            let blocks = recover(&compressed, chunk);
+            assert_eq!(blocks.len(), 4);
+            for (block, entry) in blocks.iter().zip(index.entries()) {
+                assert_eq!(block.bits, entry.bits);
+                assert_eq!(block.crc, entry.crc);
+            }
+            let out: Vec<u8> = blocks.iter().flat_map(|block| block.result.clone().unwrap()).collect();
+            assert_eq!(out, data);
+
+            // and each block stands on its own
+            for block in &blocks {
+                let mut out = vec![];
+                BzDecoder::new(&block.to_stream()[..]).read_to_end(&mut out).unwrap();
+                assert_eq!(&out, block.result.as_ref().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn reports_damaged_blocks() {
+        let data = sample(250_000);
+        let (compressed, index) = compressed(&data);
+        let entries = index.entries();
+        assert_eq!(entries.len(), 3);
+
+        let mut damaged = b"junk".to_vec();
+        damaged.extend_from_slice(&compressed);
+        let middle = 4 + ((entries[1].bits.start + entries[1].bits.end) / 16) as usize;
+        damaged[middle] ^= 0x20;
+        damaged.truncate(damaged.len() - 200);
+
+        for &chunk in &[997, CHUNK] {
+            let blocks = recover(&damaged, chunk);
+            assert_eq!(blocks.len(), 3);
+
+            let range = entries[0].bytes.start as usize..entries[0].bytes.end as usize;
+            assert_eq!(blocks[0].result.as_ref().unwrap(), &data[range]);
+            assert!(!blocks[1].is_ok());
+            assert_eq!(blocks[1].bits, entries[1].bits.start + 32..entries[2].bits.start + 32);
+            assert!(matches!(blocks[2].result, Err(Error::UnexpectedEof { .. })));
+            assert_eq!(blocks[2].bits.end, damaged.len() as u64 * 8);
+
+            // a damaged block is still written out whole, CRC and all
+            let stream = blocks[1].to_stream();
+            assert!(BzDecoder::new(&stream[..]).read_to_end(&mut vec![]).is_err());
+            let stream_blocks = recover(&stream, CHUNK);
+            assert_eq!(stream_blocks.len(), 1);
+            assert_eq!(stream_blocks[0].crc, entries[1].crc);
+        }
+
+        assert!(recover(b"", CHUNK).is_empty());
+        assert!(recover(&damaged[..100], CHUNK).iter().all(|block| !block.is_ok()));
+    }
+}
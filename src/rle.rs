@@ -1,12 +1,15 @@
 
-use std::mem::*;
-use std::iter::*;
+use alloc::vec::Vec;
+use core::iter::*;
+
+use crc::Crc;
 
 pub struct Encoder {
     block: Vec<u8>,
     max_size: usize,
     in_len: usize,
     in_char: u16,
+    crc: Crc,
 }
 
 impl Encoder {
@@ -16,6 +19,7 @@ impl Encoder {
             max_size: block_size,
             in_len: 0,
             in_char: 256,
+            crc: Crc::new(),
         }
     }
 
@@ -31,7 +35,7 @@ impl Encoder {
 
             if b != self.in_char && self.in_len == 1 {
                 let ib = self.in_char as u8;
-                // update crc ib
+                self.crc.update(ib);
                 self.block.push(ib);
                 self.in_char = b;
             } else if b != self.in_char || self.in_len == 255 {
@@ -54,8 +58,35 @@ impl Encoder {
         self.block
     }
 
-    fn is_empty(&self) -> bool {
-        self.in_char > 255 || self.in_len == 0
+    /// Ends the block, like `finish`, but keeps the encoder, which takes no
+    /// more input until `reset`.
+    pub fn finish_block(&mut self) -> &[u8] {
+        self.flush();
+        self.in_char = 256;
+        self.in_len = 0;
+        &self.block
+    }
+
+    /// Starts a new block, keeping the buffer of the last one.
+    pub fn reset(&mut self) {
+        self.block.clear();
+        self.in_len = 0;
+        self.in_char = 256;
+        self.crc = Crc::new();
+    }
+
+    /// The CRC of all input accepted so far, as stored in the block header.
+    pub fn block_crc(&self) -> u32 {
+        let mut crc = self.crc;
+        if self.in_char < 256 {
+            crc.update_run(self.in_char as u8, self.in_len);
+        }
+        crc.finish()
+    }
+
+    /// Whether no input has been accepted since the encoder was created.
+    pub fn is_empty(&self) -> bool {
+        self.block.is_empty() && (self.in_char > 255 || self.in_len == 0)
     }
 
     fn flush(&mut self) {
@@ -69,61 +100,91 @@ impl Encoder {
 
         let ch = self.in_char as u8;
 
-        for _ in 0..self.in_len {
-            // update crc ch
-        }
+        self.crc.update_run(ch, self.in_len);
 
-        self.block.extend(repeat(ch).take(self.in_len).take(4));
+        self.block.extend(repeat_n(ch, self.in_len.min(4)));
         if self.in_len >= 4 {
             self.block.push(self.in_len as u8 - 4);
         }
     }
 }
 
-pub fn initial_decode(encoded: &[u8]) -> Vec<u8> {
-    type Chunk = (u8, u8, u8, u8);
-    debug_assert_eq!(align_of::<Chunk>(), 1);
-
-    let n = encoded.len();
-    let s = size_of::<Chunk>();
-    let mut output = Vec::with_capacity(1024);
-
-    let mut i = 0;
-    while i + s < n - 1 {
-        let (a, b, c, d) = unsafe { *(encoded.as_ptr().offset(i as isize) as *const Chunk) };
-        let mut run_len = 1;
-        if a == b {
-            run_len += 1;
-            if a == c {
-                run_len += 1;
-                if a == d {
-                    i += 1 + run_len;
-                    run_len += 1 + encoded[i] as usize;
-                    output.extend(repeat(a).take(run_len));
-                    continue;
+/// Undoes the initial run-length encoding, a slice at a time.
+#[derive(Default)]
+pub struct Decoder {
+    last: u8,
+    run: usize,
+    pending: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    /// Decodes as much of `input` into `output` as fits, returning the number
+    /// of bytes read and written.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> (usize, usize) {
+        let mut i = 0;
+        let mut o = 0;
+
+        while o < output.len() {
+            if self.pending > 0 {
+                let n = self.pending.min(output.len() - o);
+                for b in &mut output[o..o + n] {
+                    *b = self.last;
                 }
+                o += n;
+                self.pending -= n;
+                continue;
             }
-        }
 
-        i += run_len;
-        output.extend(repeat(a).take(run_len));
-    }
-    println!("debug: {:?}", &output[..]);
-    println!("encoded: {:?}", &encoded[i..]);
-
-    if i < n && output.len() >= 4 {
-        let on = output.len();
-        let b = *output.last().unwrap();
-        let b = [b; 4];
-        if &output[on - 4..] == &b[..] && encoded[i] == 0 {
-            // skip zero
+            if i == input.len() {
+                break;
+            }
+
+            let b = input[i];
             i += 1;
+
+            if self.run == 4 {
+                // a run of four is followed by its extra length
+                self.pending = b as usize;
+                self.run = 0;
+                continue;
+            }
+
+            if self.run > 0 && b == self.last {
+                self.run += 1;
+            } else {
+                self.last = b;
+                self.run = 1;
+            }
+            output[o] = b;
+            o += 1;
         }
+
+        (i, o)
+    }
+
+    /// Whether all repetitions read so far have been written out.
+    pub fn is_flushed(&self) -> bool {
+        self.pending == 0
     }
+}
 
-    output.reserve_exact(n - i);
-    for i in i..n {
-        output.push(encoded[i]);
+pub fn initial_decode(encoded: &[u8]) -> Vec<u8> {
+    let mut decoder = Decoder::new();
+    let mut output = Vec::with_capacity(encoded.len());
+    let mut buf = [0; 4096];
+    let mut input = encoded;
+
+    loop {
+        let (read, written) = decoder.decode(input, &mut buf);
+        output.extend_from_slice(&buf[..written]);
+        input = &input[read..];
+        if written < buf.len() {
+            break;
+        }
     }
 
     output
@@ -0,0 +1,230 @@
+//! Finding block boundaries without decoding.
+//!
+//! Every block starts with the 48-bit magic 0x314159265359 and every stream
+//! ends with 0x177245385090 followed by the stream CRC. Neither is byte
+//! aligned, but both can be found by sliding a 48-bit window over the input
+//! one bit at a time, as `bzip2recover` does. Each block runs from its magic
+//! to the next one, so blocks can be located, salvaged or handed out as
+//! independent units of work before any of them is decoded.
+//!
+//! The magics are not escaped inside compressed data, so a match is only a
+//! candidate: the stored CRC is what confirms a block once it is decoded.
+
+use core::ops::Range;
+
+use block::{BLOCK_MAGIC, EOS_MAGIC};
+
+const MAGIC_MASK: u64 = (1 << 48) - 1;
+
+/// A block found by `scan_blocks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// The bits the block occupies, from the start of its magic to the start
+    /// of the next magic, or to the end of the input for the last one.
+    pub bits: Range<u64>,
+    /// The CRC of the block's uncompressed data, as stored in its header.
+    pub crc: u32,
+}
+
+/// A boundary found by `scan_blocks`, in order of bit offset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Marker {
+    Block(Block),
+    /// The end of a stream, with the bit offset of its magic and the stored
+    /// combined CRC of the stream's blocks.
+    StreamEnd { bit_offset: u64, crc: u32 },
+}
+
+/// Returns an iterator over every block and end-of-stream magic in `data`,
+/// at any bit offset.
+///
+/// Magics too close to the end of `data` to be followed by a CRC are
+/// skipped.
+pub fn scan_blocks(data: &[u8]) -> ScanBlocks<'_> {
+    ScanBlocks {
+        data,
+        pos: 0,
+        next: None,
+    }
+}
+
+/// Iterator returned by `scan_blocks`.
+pub struct ScanBlocks<'a> {
+    data: &'a [u8],
+    // the bit offset to resume searching from
+    pos: u64,
+    // a magic already found while looking for the end of a block
+    next: Option<(u64, u64)>,
+}
+
+impl<'a> ScanBlocks<'a> {
+    // Finds the next magic at or after `self.pos` that is followed by a CRC,
+    // returning its bit offset and value.
+    fn find(&mut self) -> Option<(u64, u64)> {
+        if let Some(found) = self.next.take() {
+            return Some(found);
+        }
+
+        let len = self.data.len() as u64 * 8;
+        match find_magic(self.data, self.pos) {
+            Some((start, magic)) if start + 48 + 32 <= len => {
+                self.pos = start + 48;
+                Some((start, magic))
+            }
+            _ => {
+                self.pos = len;
+                None
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for ScanBlocks<'a> {
+    type Item = Marker;
+
+    fn next(&mut self) -> Option<Marker> {
+        let (start, magic) = self.find()?;
+        let crc = read_u32(self.data, start + 48);
+        if magic == EOS_MAGIC {
+            return Some(Marker::StreamEnd { bit_offset: start, crc });
+        }
+
+        self.next = self.find();
+        let end = match self.next {
+            Some((end, _)) => end,
+            None => self.data.len() as u64 * 8,
+        };
+        Some(Marker::Block(Block {
+            bits: start..end,
+            crc,
+        }))
+    }
+}
+
+// Finds the first block or end-of-stream magic that starts at or after bit
+// `from` and lies wholly within `data`, returning its bit offset and value.
+pub(crate) fn find_magic(data: &[u8], from: u64) -> Option<(u64, u64)> {
+    let len = data.len() as u64 * 8;
+    // the window holds up to 48 of the bits before `pos`
+    let mut window = 0;
+    let mut filled = 0;
+    let mut pos = from;
+    while pos < len {
+        let bit = data[(pos / 8) as usize] >> (7 - pos % 8) & 1;
+        window = (window << 1 | bit as u64) & MAGIC_MASK;
+        filled += 1;
+        pos += 1;
+        if filled >= 48 && (window == BLOCK_MAGIC || window == EOS_MAGIC) {
+            return Some((pos - 48, window));
+        }
+    }
+    None
+}
+
+// Reads the 32 bits starting at bit offset `pos`, which must all lie within
+// `data`.
+pub(crate) fn read_u32(data: &[u8], pos: u64) -> u32 {
+    let first = (pos / 8) as usize;
+    let mut acc = 0u64;
+    for i in 0..5 {
+        acc = acc << 8 | *data.get(first + i).unwrap_or(&0) as u64;
+    }
+    (acc >> (8 - pos % 8)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2::read::BzEncoder;
+    use bzip2::Compression;
+
+    use super::*;
+    use crc::{self, Crc};
+
+    fn reference(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut out = vec![];
+        BzEncoder::new(data, level).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 1u32;
+        (0..len).map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            b"abcdefgh"[(x >> 16) as usize % 8]
+        }).collect()
+    }
+
+    fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update_slice(data);
+        crc.finish()
+    }
+
+    #[test]
+    fn finds_every_block() {
+        let compressed = reference(&sample(350_000), Compression::Fastest);
+        let markers: Vec<_> = scan_blocks(&compressed).collect();
+        assert_eq!(markers.len(), 5);
+
+        let mut combined = 0;
+        let mut pos = 32;
+        for marker in &markers[..4] {
+            match *marker {
+                Marker::Block(ref block) => {
+                    assert_eq!(block.bits.start, pos);
+                    pos = block.bits.end;
+                    combined = crc::combine(combined, block.crc);
+                }
+                _ => panic!("expected a block, got {:?}", marker),
+            }
+        }
+        // later blocks generally start mid-byte
+        assert!(markers.iter().any(|m| match *m {
+            Marker::Block(ref block) => block.bits.start % 8 != 0,
+            _ => false,
+        }));
+
+        match markers[4] {
+            Marker::StreamEnd { bit_offset, crc } => {
+                assert_eq!(bit_offset, pos);
+                assert_eq!(crc, combined);
+            }
+            ref marker => panic!("expected the end of the stream, got {:?}", marker),
+        }
+    }
+
+    #[test]
+    fn finds_blocks_after_damage() {
+        let first = reference(b"first", Compression::Default);
+        let second = reference(b"second", Compression::Default);
+        let mut data = b"some leading junk".to_vec();
+        data.extend_from_slice(&first[..first.len() - 11]);
+        data.extend_from_slice(&second);
+
+        let markers: Vec<_> = scan_blocks(&data).collect();
+        assert_eq!(markers.len(), 3);
+        match (&markers[0], &markers[1]) {
+            (Marker::Block(a), Marker::Block(b)) => {
+                assert_eq!(a.bits.start, (17 + 4) * 8);
+                assert_eq!(a.crc, checksum(b"first"));
+                // the damaged block runs into the next stream
+                assert_eq!(a.bits.end, b.bits.start);
+                assert_eq!(b.crc, checksum(b"second"));
+            }
+            _ => panic!("expected two blocks, got {:?}", markers),
+        }
+        match markers[2] {
+            Marker::StreamEnd { crc, .. } => assert_eq!(crc, checksum(b"second")),
+            ref marker => panic!("expected the end of the stream, got {:?}", marker),
+        }
+    }
+
+    #[test]
+    fn ignores_truncated_magic() {
+        let compressed = reference(b"hello", Compression::Default);
+        assert_eq!(scan_blocks(&compressed[..4 + 6 + 3]).count(), 0);
+        assert_eq!(scan_blocks(&[]).count(), 0);
+    }
+}
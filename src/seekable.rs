@@ -0,0 +1,198 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use block;
+use index::Index;
+
+/// Reads the uncompressed data of an indexed bzip2 file at any position,
+/// decoding only the blocks that hold the bytes asked for.
+///
+/// The most recently decoded block is kept, so reading sequentially decodes
+/// each block once.
+pub struct SeekableReader<R> {
+    inner: R,
+    index: Index,
+    pos: u64,
+    decoder: block::Decoder,
+    // the entry the decoded block belongs to
+    block: Option<usize>,
+    data: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek> SeekableReader<R> {
+    /// Creates a reader over compressed data that `index` describes.
+    pub fn new(inner: R, index: Index) -> Self {
+        SeekableReader {
+            inner,
+            index,
+            pos: 0,
+            decoder: block::Decoder::new(),
+            block: None,
+            data: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    fn load(&mut self, i: usize) -> io::Result<()> {
+        self.block = None;
+        self.data = decode_entry(&mut self.inner, &self.index, i, &mut self.decoder, &mut self.buf)?;
+        self.block = Some(i);
+        Ok(())
+    }
+}
+
+// Decodes entry `i` of `index` from `inner`, checking that the block is where
+// the index says and holds what it says.
+pub fn decode_entry<R: Read + Seek>(inner: &mut R, index: &Index, i: usize,
+                                    decoder: &mut block::Decoder, buf: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    let entry = &index.entries()[i];
+    if entry.bits.start >= entry.bits.end || !entry.fits_a_block() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("block {} of the index is larger than a block can be", i)));
+    }
+    let first = entry.bits.start / 8;
+    buf.resize((entry.bits.end.div_ceil(8) - first) as usize, 0);
+    inner.seek(SeekFrom::Start(first))?;
+    inner.read_exact(buf)?;
+
+    let (data, end) = decoder.decode_at(buf, first, entry.bits.start, i as u64, entry.level)?;
+    if end != entry.bits.end || decoder.crc() != entry.crc ||
+       data.len() as u64 != entry.bytes.end - entry.bytes.start {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("block {} does not match the index", i)));
+    }
+    Ok(data)
+}
+
+impl<R: Read + Seek> Read for SeekableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let i = match self.index.find(self.pos) {
+            Some(i) => i,
+            None => return Ok(0),
+        };
+        if self.block != Some(i) {
+            self.load(i)?;
+        }
+
+        let at = (self.pos - self.index.entries()[i].bytes.start) as usize;
+        let n = buf.len().min(self.data.len() - at);
+        buf[..n].copy_from_slice(&self.data[at..at + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableReader<R> {
+    /// Moves to a position in the uncompressed data. Nothing is decoded
+    /// until the next read.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.index.len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{Cursor, SeekFrom};
+
+    use super::*;
+    use index::Entry;
+    use BzWriter;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 4242u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 3 {
+                0 => (x >> 8) as u8,
+                _ => (i / 400) as u8,
+            }
+        }).collect()
+    }
+
+    fn compressed(data: &[u8]) -> (Vec<u8>, Index) {
+        let mut writer = BzWriter::new(vec![], 1).record_index(true);
+        writer.write_all(data).unwrap();
+        writer.try_finish().unwrap();
+        let index = writer.index().clone();
+        (writer.finish().unwrap(), index)
+    }
+
+    #[test]
+    fn reads_ranges() {
+        let data = sample(350_000);
+        let (compressed, index) = compressed(&data);
+        let mut reader = SeekableReader::new(Cursor::new(compressed), index);
+
+        for &(start, len) in &[(250_000, 1000), (0, 10), (99_990, 20), (349_000, 5000), (123_456, 150_000)] {
+            assert_eq!(reader.seek(SeekFrom::Start(start)).unwrap(), start);
+            let mut out = vec![];
+            reader.by_ref().take(len).read_to_end(&mut out).unwrap();
+            let end = (start + len).min(data.len() as u64) as usize;
+            assert_eq!(out, &data[start as usize..end]);
+        }
+
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), data.len() as u64 - 3);
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, &data[data.len() - 3..]);
+        assert_eq!(reader.seek(SeekFrom::Current(-10)).unwrap(), data.len() as u64 - 10);
+        assert!(reader.seek(SeekFrom::Current(-350_000)).is_err());
+
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn rejects_a_mismatched_index() {
+        let (compressed, _) = compressed(&sample(150_000));
+        let (_, index) = self::compressed(&sample(250_000));
+        let mut reader = SeekableReader::new(Cursor::new(compressed), index);
+        reader.seek(SeekFrom::Start(120_000)).unwrap();
+        assert!(reader.read(&mut [0; 10]).is_err());
+    }
+
+    #[test]
+    fn rejects_entries_larger_than_a_block() {
+        let (compressed, _) = compressed(b"some data");
+        let mut index = Index::new();
+        // would need a terabyte to read in
+        index.push(Entry { bits: 32..1 << 43, bytes: 0..9, level: 1, crc: 0 });
+        let mut reader = SeekableReader::new(Cursor::new(compressed), index);
+        let err = reader.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
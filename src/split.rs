@@ -0,0 +1,535 @@
+//! Decoding part of a file, for processing one file in many pieces.
+//!
+//! As with Hadoop's `BZip2Codec`, the compressed file is cut into splits at
+//! arbitrary byte offsets. A split owns the blocks whose magic starts inside
+//! it: it searches from its start for the first block magic, then decodes
+//! whole blocks until the next one starts at or past its end. Every block is
+//! thus decoded by exactly one split.
+//!
+//! Records, such as lines, straddle blocks, so `Records` follows the same
+//! rule Hadoop's line reader does: a record belongs to the split owning the
+//! delimiter just before it, or to the first split for the first record. A
+//! split skips what comes before its first delimiter and reads on past its
+//! end to complete its last record.
+//!
+//! Unlike Hadoop, the first magic found is not trusted: magics are not
+//! escaped, so at scale some occur by chance inside compressed data. A
+//! candidate that fails to decode or to match its CRC is passed over for the
+//! next one, and an error is only reported if no candidate before the end of
+//! the split decodes. A damaged block is still reported, by the split before
+//! it, which decodes the block after its last one to find where it stops, or
+//! straight away by the first split. The stream header of a split's first
+//! block is usually in some earlier split, so blocks are decoded as if the
+//! block size were the largest.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use block::{self, BLOCK_MAGIC, EOS_MAGIC};
+use error::Error;
+use scan;
+
+const CHUNK: usize = 1 << 20;
+
+/// A block owned by a split.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SplitBlock {
+    /// The bits of the file the block occupies.
+    pub bits: Range<u64>,
+    pub data: Vec<u8>,
+}
+
+/// The blocks whose magic starts between two byte offsets of a file.
+pub struct Split<R> {
+    inner: R,
+    start: u64,
+    end: u64,
+    // compressed data from byte `base` of the file on
+    window: Vec<u8>,
+    base: u64,
+    eof: bool,
+    // the bit offset of the next block or end-of-stream magic, once found
+    pos: Option<u64>,
+    level: u32,
+    blocks: u64,
+    decoder: block::Decoder,
+    done: bool,
+}
+
+impl<R: Read + Seek> Split<R> {
+    /// Creates a split of `inner` from byte `start` up to byte `end`.
+    pub fn new(mut inner: R, start: u64, end: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Split {
+            inner,
+            start,
+            end,
+            window: Vec::new(),
+            base: start,
+            eof: false,
+            pos: None,
+            level: 9,
+            blocks: 0,
+            decoder: block::Decoder::new(),
+            done: false,
+        })
+    }
+
+    /// Decodes the next block the split owns.
+    pub fn next_block(&mut self) -> io::Result<Option<SplitBlock>> {
+        match self.next_any()? {
+            Some(ref block) if block.bits.start >= self.end * 8 => {
+                self.done = true;
+                Ok(None)
+            }
+            block => Ok(block),
+        }
+    }
+
+    /// Reads the records the split owns, separated by `delimiter`.
+    pub fn records(self, delimiter: u8) -> Records<R> {
+        Records {
+            at_start: self.start == 0,
+            split: self,
+            delimiter,
+            buf: Vec::new(),
+            buf_start: 0,
+            pos: 0,
+            started: false,
+            owned: 0,
+            owned_done: false,
+            eof: false,
+            failed: false,
+        }
+    }
+
+    // Decodes the next block whether the split owns it or not.
+    fn next_any(&mut self) -> io::Result<Option<SplitBlock>> {
+        if self.done {
+            return Ok(None);
+        }
+        let pos = match self.pos {
+            Some(pos) => pos,
+            None => return self.find_first(),
+        };
+
+        match self.bits_at(pos, 48)? {
+            Some(BLOCK_MAGIC) => {
+                let (data, end) = self.decode(pos)?.map_err(io::Error::from)?;
+                self.pos = Some(end);
+                Ok(Some(SplitBlock { bits: pos..end, data }))
+            }
+            Some(EOS_MAGIC) => {
+                if self.bits_at(pos + 48, 32)?.is_none() {
+                    return Err(self.eof_error().into());
+                }
+                let header = (pos + 80).div_ceil(8) * 8;
+                match self.bits_at(header, 32)? {
+                    None if self.base + self.window.len() as u64 == header / 8 => {
+                        self.done = true;
+                        Ok(None)
+                    }
+                    Some(magic) if magic >> 8 == 0x42_5a_68 &&
+                                   (b'1' as u64..=b'9' as u64).contains(&(magic & 0xff)) => {
+                        self.level = (magic & 0xff) as u32 - b'0' as u32;
+                        self.pos = Some(header + 32);
+                        self.next_any()
+                    }
+                    _ => {
+                        Err(Error::TrailingGarbage {
+                            block: self.blocks,
+                            bit_offset: header,
+                        }.into())
+                    }
+                }
+            }
+            Some(_) => {
+                Err(Error::BadMagic {
+                    block: self.blocks,
+                    bit_offset: pos,
+                }.into())
+            }
+            None => Err(self.eof_error().into()),
+        }
+    }
+
+    // Searches from the start of the split for the first block magic that
+    // decodes and decodes the block there.
+    fn find_first(&mut self) -> io::Result<Option<SplitBlock>> {
+        let mut from = self.start * 8;
+        // why the first candidate before the end of the split failed
+        let mut failed: Option<Error> = None;
+        loop {
+            self.trim(from);
+            let len = self.window.len() as u64 * 8;
+            // a magic too near the end to be followed by a CRC waits for more
+            // of the file
+            let found = scan::find_magic(&self.window, from - self.base * 8).filter(|&(at, _)| at + 80 <= len);
+
+            match found {
+                Some((at, BLOCK_MAGIC)) => {
+                    let start = self.base * 8 + at;
+                    if start >= self.end * 8 {
+                        if let Some(err) = failed {
+                            return Err(err.into());
+                        }
+                    }
+                    match self.decode(start)? {
+                        Ok((data, end)) => {
+                            self.pos = Some(end);
+                            return Ok(Some(SplitBlock { bits: start..end, data }));
+                        }
+                        // no earlier split decodes up to the first block
+                        Err(err) if self.start == 0 => return Err(err.into()),
+                        Err(err) => {
+                            if start < self.end * 8 && failed.is_none() {
+                                failed = Some(err);
+                            }
+                            from = start + 1;
+                        }
+                    }
+                }
+                Some((at, _)) => from = self.base * 8 + at + 1,
+                None => {
+                    let searched = (self.base + self.window.len() as u64) * 8;
+                    if !self.fill()? {
+                        self.done = true;
+                        return match failed {
+                            Some(err) => Err(err.into()),
+                            None => Ok(None),
+                        };
+                    }
+                    from = from.max(searched.saturating_sub(80));
+                }
+            }
+        }
+    }
+
+    // Decodes the block at bit `start`, reading more of the file as needed.
+    fn decode(&mut self, start: u64) -> io::Result<Result<(Vec<u8>, u64), Error>> {
+        loop {
+            match self.decoder.decode_at(&self.window, self.base, start, self.blocks, self.level) {
+                Err(Error::UnexpectedEof { .. }) if !self.eof => {
+                    self.fill()?;
+                }
+                Ok(block) => {
+                    self.blocks += 1;
+                    self.trim(block.1);
+                    return Ok(Ok(block));
+                }
+                result => return Ok(result),
+            }
+        }
+    }
+
+    // Returns the `n` bits at bit `pos`, if the file is that long.
+    fn bits_at(&mut self, pos: u64, n: u32) -> io::Result<Option<u64>> {
+        while (self.base + self.window.len() as u64) * 8 < pos + n as u64 {
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+        let at = pos - self.base * 8;
+        let high = scan::read_u32(&self.window, at) as u64;
+        let bits = if n <= 32 {
+            high >> (32 - n)
+        } else {
+            high << (n - 32) | scan::read_u32(&self.window, at + 32) as u64 >> (64 - n)
+        };
+        Ok(Some(bits))
+    }
+
+    // Reads another chunk of the file, returning false at its end.
+    fn fill(&mut self) -> io::Result<bool> {
+        let len = self.window.len();
+        self.window.resize(len + CHUNK, 0);
+        let n = loop {
+            match self.inner.read(&mut self.window[len..]) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.window.truncate(len);
+                    return Err(e);
+                }
+            }
+        };
+        self.window.truncate(len + n);
+        if n == 0 {
+            self.eof = true;
+        }
+        Ok(n > 0)
+    }
+
+    // Drops the data before bit `pos` once there is enough of it.
+    fn trim(&mut self, pos: u64) {
+        let n = (pos / 8 - self.base) as usize;
+        if n >= CHUNK {
+            self.window.drain(..n);
+            self.base += n as u64;
+        }
+    }
+
+    fn eof_error(&self) -> Error {
+        Error::UnexpectedEof {
+            block: self.blocks,
+            bit_offset: (self.base + self.window.len() as u64) * 8,
+        }
+    }
+}
+
+/// Iterator over the records owned by a split, returned by
+/// `Split::records`. Records do not include their delimiter.
+pub struct Records<R> {
+    split: Split<R>,
+    delimiter: u8,
+    at_start: bool,
+    // decoded data from offset `buf_start` of what the split decoded
+    buf: Vec<u8>,
+    buf_start: u64,
+    // the offset of the next record
+    pos: u64,
+    started: bool,
+    // the length of the decoded data from blocks the split owns
+    owned: u64,
+    owned_done: bool,
+    eof: bool,
+    failed: bool,
+}
+
+impl<R: Read + Seek> Records<R> {
+    // Decodes another block, returning false if there are no more.
+    fn pull(&mut self) -> io::Result<bool> {
+        let block = match self.split.next_any()? {
+            Some(block) => block,
+            None => {
+                self.owned_done = true;
+                self.eof = true;
+                return Ok(false);
+            }
+        };
+        if block.bits.start < self.split.end * 8 && !self.owned_done {
+            self.owned += block.data.len() as u64;
+        } else {
+            self.owned_done = true;
+        }
+        self.buf.extend_from_slice(&block.data);
+        Ok(true)
+    }
+
+    // Finds the next delimiter at or after `pos`, decoding more blocks as
+    // long as `more` allows.
+    fn find(&mut self, more: fn(&Self) -> bool) -> io::Result<Option<u64>> {
+        let mut from = self.pos;
+        loop {
+            let at = (from - self.buf_start) as usize;
+            if let Some(i) = self.buf[at..].iter().position(|&b| b == self.delimiter) {
+                return Ok(Some(from + i as u64));
+            }
+            from = self.buf_start + self.buf.len() as u64;
+            if !more(self) || !self.pull()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.started {
+            self.started = true;
+            if !self.at_start {
+                // the first record, whole or not, belongs to an earlier split
+                match self.find(|records| !records.owned_done)? {
+                    Some(i) if i < self.owned => self.pos = i + 1,
+                    _ => return Ok(None),
+                }
+            }
+        }
+
+        // the record is ours if the delimiter before it is, or if it is the
+        // first in the file and we own the first block
+        while (self.pos > self.owned || self.owned == 0) && !self.owned_done {
+            self.pull()?;
+        }
+        if self.pos > self.owned || self.owned == 0 {
+            return Ok(None);
+        }
+        while self.pos >= self.buf_start + self.buf.len() as u64 {
+            if !self.pull()? {
+                return Ok(None);
+            }
+        }
+
+        let end = match self.find(|records| !records.eof)? {
+            Some(end) => end,
+            None => self.buf_start + self.buf.len() as u64,
+        };
+        let record = self.buf[(self.pos - self.buf_start) as usize..(end - self.buf_start) as usize].to_vec();
+        self.pos = end + 1;
+
+        let consumed = (self.pos - self.buf_start).min(self.buf.len() as u64) as usize;
+        self.buf.drain(..consumed);
+        self.buf_start += consumed as u64;
+        Ok(Some(record))
+    }
+}
+
+impl<R: Read + Seek> Iterator for Records<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_record();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+impl<R> Records<R> {
+    pub fn into_inner(self) -> R {
+        self.split.inner
+    }
+}
+
+impl<R> Split<R> {
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::Cursor;
+
+    use super::*;
+    use BzWriter;
+
+    fn lines(n: usize) -> Vec<u8> {
+        let mut x = 31337u32;
+        let mut data = vec![];
+        for i in 0..n {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            let len = (x >> 16) as usize % 200;
+            data.extend(format!("{} ", i).bytes());
+            data.extend((0..len).map(|j| b'a' + ((x >> 8) as usize + j * j) as u8 % 26));
+            data.push(b'\n');
+        }
+        data
+    }
+
+    fn compress(data: &[u8], level: u32) -> Vec<u8> {
+        let mut writer = BzWriter::new(vec![], level);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn cuts(len: u64, n: u64) -> Vec<u64> {
+        let mut cuts: Vec<u64> = (0..n).map(|i| i * len / n).collect();
+        cuts.push(len);
+        cuts
+    }
+
+    fn split_records(file: &[u8], cuts: &[u64]) -> Vec<Vec<u8>> {
+        let mut records = vec![];
+        for pair in cuts.windows(2) {
+            let split = Split::new(Cursor::new(file), pair[0], pair[1]).unwrap();
+            for record in split.records(b'\n') {
+                records.push(record.unwrap());
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn splits_own_every_block_once() {
+        let data = lines(6000);
+        let file = compress(&data, 1);
+        for &n in &[1, 2, 5, 13, 64] {
+            let mut out = vec![];
+            for pair in cuts(file.len() as u64, n).windows(2) {
+                let mut split = Split::new(Cursor::new(&file), pair[0], pair[1]).unwrap();
+                while let Some(block) = split.next_block().unwrap() {
+                    assert!(block.bits.start >= pair[0] * 8 && block.bits.start < pair[1] * 8);
+                    out.extend(block.data);
+                }
+            }
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn splits_own_every_record_once() {
+        let data = lines(6000);
+        let expected: Vec<Vec<u8>> = data.split(|&b| b == b'\n').map(|line| line.to_vec()).collect();
+        let expected = &expected[..expected.len() - 1];
+
+        let mut file = compress(&data[..400_000], 1);
+        file.extend(compress(&data[400_000..], 2));
+        for &n in &[1, 3, 7, 40] {
+            assert_eq!(split_records(&file, &cuts(file.len() as u64, n)), expected);
+        }
+    }
+
+    #[test]
+    fn records_may_straddle_many_blocks() {
+        let mut data = b"first\n".to_vec();
+        data.extend(vec![b'x'; 50_000]);
+        data.extend(lines(2000));
+        data.extend(b"no delimiter at the end");
+        let mut expected: Vec<Vec<u8>> = data.split(|&b| b == b'\n').map(|line| line.to_vec()).collect();
+
+        let file = compress(&data, 1);
+        assert_eq!(split_records(&file, &cuts(file.len() as u64, 9)), expected);
+
+        expected.truncate(1);
+        assert_eq!(split_records(&file[..], &[0, 5, 9, file.len() as u64])[..1], expected[..]);
+    }
+
+    #[test]
+    fn passes_over_magics_inside_blocks() {
+        let data = lines(6000);
+        let mut writer = BzWriter::new(vec![], 1).record_index(true);
+        writer.write_all(&data).unwrap();
+        writer.try_finish().unwrap();
+        let second = writer.index().entries()[1].clone();
+        let mut file = writer.finish().unwrap();
+
+        // a block magic in the first block, between the split's start and
+        // the first real one
+        let start = second.bits.start / 8 - 1000;
+        let at = start as usize + 10;
+        file[at..at + 6].copy_from_slice(&[0x31, 0x41, 0x59, 0x26, 0x53, 0x59]);
+
+        let mut split = Split::new(Cursor::new(&file), start, file.len() as u64).unwrap();
+        let block = split.next_block().unwrap().unwrap();
+        assert_eq!(block.bits.start, second.bits.start);
+        let mut out = block.data;
+        while let Some(block) = split.next_block().unwrap() {
+            out.extend(block.data);
+        }
+        assert_eq!(out, &data[second.bytes.start as usize..]);
+
+        // with no other candidate before its end, the split reports the one
+        // that failed
+        let mut split = Split::new(Cursor::new(&file), start, start + 100).unwrap();
+        assert!(split.next_block().is_err());
+    }
+
+    #[test]
+    fn reports_damage() {
+        let data = lines(1000);
+        let mut file = compress(&data, 9);
+        let len = file.len();
+        file[len / 2] ^= 1;
+        let mut split = Split::new(Cursor::new(&file), 0, len as u64).unwrap();
+        assert!(split.next_block().is_err());
+
+        let mut split = Split::new(Cursor::new(&file), 1, len as u64).unwrap();
+        assert!(split.next_block().is_err());
+
+        // nothing is owned past the only block
+        let mut split = Split::new(Cursor::new(&file), 10, len as u64).unwrap();
+        assert_eq!(split.next_block().unwrap(), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Checking bzip2 data without keeping what it decodes to, as `bzip2 -t`
+//! does.
+//!
+//! `verify` decodes every block, checks its CRC and every stream's combined
+//! CRC, and reports what it found in each, in the manner of `bzip2 -tvv`.
+//! Decoding stops at the first error, which the report records along with
+//! everything checked before it.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, Read};
+
+#[cfg(feature = "std")]
+use decompress::{Decompress, Status};
+use error::Error;
+
+#[cfg(feature = "std")]
+const BUF_SIZE: usize = 32 * 1024;
+
+/// What `verify` found in one block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockReport {
+    /// The position of the block in the input, counting from zero across
+    /// all streams.
+    pub index: u64,
+    /// The bits of the input the block occupies, from its magic to the
+    /// next block's.
+    pub bits: Range<u64>,
+    /// The number of bytes the block decodes to.
+    pub len: u64,
+    /// The CRC stored in the block header.
+    pub stored_crc: u32,
+    /// The CRC of the data the block decoded to.
+    pub computed_crc: u32,
+    /// The number of Huffman tables the block uses.
+    pub tables: u32,
+    /// Whether the block is randomised.
+    pub randomised: bool,
+}
+
+impl BlockReport {
+    /// Whether the stored and computed CRCs match.
+    pub fn is_ok(&self) -> bool {
+        self.stored_crc == self.computed_crc
+    }
+}
+
+/// What `verify` found in one stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamReport {
+    /// The bits of the input the stream occupies, from its header to the
+    /// end of its padding.
+    pub bits: Range<u64>,
+    /// The block size level, `1..=9`.
+    pub level: u32,
+    /// The indices of the stream's blocks.
+    pub blocks: Range<u64>,
+    /// The combined CRC stored at the end of the stream.
+    pub stored_crc: u32,
+    /// The combined CRC of the stream's blocks.
+    pub computed_crc: u32,
+}
+
+impl StreamReport {
+    /// Whether the stored and computed combined CRCs match.
+    pub fn is_ok(&self) -> bool {
+        self.stored_crc == self.computed_crc
+    }
+}
+
+/// The result of `verify`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Every stream whose end was reached, in order.
+    pub streams: Vec<StreamReport>,
+    /// Every block decoded in full, in order, including one whose CRC does
+    /// not match.
+    pub blocks: Vec<BlockReport>,
+    /// The number of bytes of input consumed.
+    pub total_in: u64,
+    /// The number of bytes the input decoded to.
+    pub total_out: u64,
+    /// The error that stopped decoding, if the input is not intact.
+    pub error: Option<Error>,
+}
+
+impl Report {
+    /// Whether the input decoded in full with every CRC matching.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Decodes all of `inner`, discarding the output, and reports on every
+/// block and stream in it.
+///
+/// Only errors reading `inner` are returned as errors; invalid data is
+/// recorded in `Report::error`.
+#[cfg(feature = "std")]
+pub fn verify<R: Read>(inner: R) -> io::Result<Report> {
+    let mut inner = BufReader::with_capacity(BUF_SIZE, inner);
+    let mut decompress = Decompress::new();
+    decompress.start_report();
+    let mut out = vec![0; BUF_SIZE];
+
+    let error = loop {
+        let (consumed, produced, status, eof) = {
+            let input = inner.fill_buf()?;
+            match decompress.decompress(input, &mut out) {
+                Ok((consumed, produced, status)) => (consumed, produced, status, input.is_empty()),
+                Err(err) => break Some(err),
+            }
+        };
+        inner.consume(consumed);
+
+        if status == Status::StreamEnd && eof {
+            break None;
+        }
+        if produced == 0 && eof {
+            break Some(decompress.eof_error());
+        }
+    };
+
+    let report = decompress.take_report().unwrap_or_default();
+    Ok(Report {
+        total_in: decompress.total_in(),
+        total_out: decompress.total_out(),
+        error,
+        ..report
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use super::*;
+    use {BzWriter, Index};
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 42u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 3 {
+                0 => (x >> 8) as u8,
+                _ => (i / 200) as u8,
+            }
+        }).collect()
+    }
+
+    fn compressed(data: &[u8], level: u32) -> (Vec<u8>, Index) {
+        let mut writer = BzWriter::new(vec![], level).record_index(true);
+        writer.write_all(data).unwrap();
+        writer.try_finish().unwrap();
+        let index = writer.index().clone();
+        (writer.finish().unwrap(), index)
+    }
+
+    // Flips a bit of the input.
+    fn flip(data: &mut [u8], bit: u64) {
+        data[(bit / 8) as usize] ^= 0x80 >> (bit % 8);
+    }
+
+    #[test]
+    fn reports_every_block_and_stream() {
+        let data = sample(250_000);
+        let (mut input, index) = compressed(&data, 1);
+        let first = input.len() as u64;
+        input.extend(compressed(b"a second stream", 5).0);
+
+        let report = verify(&input[..]).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.total_in, input.len() as u64);
+        assert_eq!(report.total_out, data.len() as u64 + 15);
+
+        assert_eq!(report.blocks.len(), 4);
+        for (i, (block, entry)) in report.blocks.iter().zip(index.entries()).enumerate() {
+            assert_eq!(block.index, i as u64);
+            assert_eq!(block.bits, entry.bits);
+            assert_eq!(block.len, entry.bytes.end - entry.bytes.start);
+            assert_eq!(block.stored_crc, entry.crc);
+            assert!(block.is_ok());
+            assert!((2..=6).contains(&block.tables));
+            assert!(!block.randomised);
+        }
+        assert_eq!(report.blocks[3].bits.start, first * 8 + 32);
+        assert_eq!(report.blocks[3].len, 15);
+
+        assert_eq!(report.streams.len(), 2);
+        assert_eq!(report.streams[0].bits, 0..first * 8);
+        assert_eq!(report.streams[0].level, 1);
+        assert_eq!(report.streams[0].blocks, 0..3);
+        assert_eq!(report.streams[1].bits, first * 8..input.len() as u64 * 8);
+        assert_eq!(report.streams[1].level, 5);
+        assert_eq!(report.streams[1].blocks, 3..4);
+        assert!(report.streams.iter().all(StreamReport::is_ok));
+
+        let report = verify(&b""[..]).unwrap();
+        assert!(matches!(report.error, Some(Error::UnexpectedEof { .. })));
+        assert!(report.blocks.is_empty() && report.streams.is_empty());
+    }
+
+    #[test]
+    fn reports_crc_mismatches() {
+        let data = sample(250_000);
+        let (input, index) = compressed(&data, 1);
+        let entries = index.entries();
+
+        // the CRC stored for the second block
+        let mut damaged = input.clone();
+        flip(&mut damaged, entries[1].bits.start + 48 + 7);
+        let report = verify(&damaged[..]).unwrap();
+        assert_eq!(report.blocks.len(), 2);
+        assert!(report.blocks[0].is_ok());
+        assert_eq!(report.blocks[1].stored_crc, entries[1].crc ^ 1 << 24);
+        assert_eq!(report.blocks[1].computed_crc, entries[1].crc);
+        assert!(matches!(report.error, Some(Error::BlockCrcMismatch { block: 1, .. })));
+        assert!(report.streams.is_empty());
+
+        // the stream's combined CRC
+        let mut damaged = input.clone();
+        flip(&mut damaged, entries[2].bits.end + 48);
+        let report = verify(&damaged[..]).unwrap();
+        assert_eq!(report.blocks.len(), 3);
+        assert!(report.blocks.iter().all(BlockReport::is_ok));
+        assert_eq!(report.streams.len(), 1);
+        assert_eq!(report.streams[0].stored_crc ^ report.streams[0].computed_crc, 1 << 31);
+        assert!(matches!(report.error, Some(Error::StreamCrcMismatch { .. })));
+
+        // a truncated stream keeps what came before the end
+        let report = verify(&input[..input.len() - 100]).unwrap();
+        assert_eq!(report.blocks.len(), 2);
+        assert!(matches!(report.error, Some(Error::UnexpectedEof { .. })));
+
+        let mut garbage = input.clone();
+        garbage.extend_from_slice(b"garbage");
+        let report = verify(&garbage[..]).unwrap();
+        assert_eq!(report.streams.len(), 1);
+        assert!(matches!(report.error, Some(Error::TrailingGarbage { .. })));
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::{self, Write};
+
+use compress::{Action, Compress};
+use decompress::Status;
+use index::Index;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Compresses everything written to it into a bzip2 stream written to `W`.
+///
+/// The stream is ended by `finish`, or failing that when the writer is
+/// dropped, in which case any error is lost.
+pub struct BzWriter<W: Write> {
+    inner: Option<W>,
+    compress: Compress,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<W: Write> BzWriter<W> {
+    /// Creates a writer using blocks of `level` * 100k bytes, for `level` in
+    /// `1..=9`.
+    pub fn new(inner: W, level: u32) -> Self {
+        BzWriter {
+            inner: Some(inner),
+            compress: Compress::new(level),
+            buf: Vec::with_capacity(BUF_SIZE),
+            done: false,
+        }
+    }
+
+    /// Records the position of every block in `index`, which is off by
+    /// default. It can only be changed before anything is written.
+    pub fn record_index(mut self, record_index: bool) -> Self {
+        self.compress.set_record_index(record_index);
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// The number of uncompressed bytes consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.compress.total_in()
+    }
+
+    /// The number of compressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.compress.total_out()
+    }
+
+    /// The positions of the blocks compressed so far, complete once the
+    /// stream is finished, if `record_index` was set.
+    pub fn index(&self) -> &Index {
+        self.compress.index()
+    }
+
+    /// Ends the stream and writes out everything still buffered, without
+    /// giving up the inner writer.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        loop {
+            self.dump()?;
+            if self.done {
+                return Ok(());
+            }
+            self.done = self.compress_into_buf(&[], Action::Finish).1 == Status::StreamEnd;
+        }
+    }
+
+    /// Ends the current block and writes out everything compressed so far,
+    /// without ending the stream, so that a decoder reading the output can
+    /// reproduce all the data written up to now. The block is padded to end
+    /// on a byte boundary within the bzip2 format, so no padding comes
+    /// between it and the next one.
+    ///
+    /// Every flush costs a block, so flushing often compresses poorly.
+    pub fn flush_block(&mut self) -> io::Result<()> {
+        if self.done {
+            return Err(io::Error::other("flush after the stream was finished"));
+        }
+        loop {
+            self.dump()?;
+            if self.compress_into_buf(&[], Action::Flush).1 == Status::Flushed {
+                self.dump()?;
+                return self.inner.as_mut().unwrap().flush();
+            }
+        }
+    }
+
+    /// Ends the stream and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn compress_into_buf(&mut self, data: &[u8], action: Action) -> (usize, Status) {
+        debug_assert!(self.buf.is_empty());
+        self.buf.resize(BUF_SIZE, 0);
+        let (consumed, produced, status) = self.compress.compress(data, &mut self.buf, action);
+        self.buf.truncate(produced);
+        (consumed, status)
+    }
+
+    fn dump(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            let n = self.inner.as_mut().unwrap().write(&self.buf)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BzWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Err(io::Error::other("write after the stream was finished"));
+        }
+        loop {
+            self.dump()?;
+            let (consumed, _) = self.compress_into_buf(data, Action::Run);
+            if consumed > 0 || data.is_empty() {
+                return Ok(consumed);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.dump()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BzWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use bzip2::read::BzDecoder;
+    use proptest::string::*;
+
+    use super::*;
+    use {BzReader, Decompress};
+
+    fn encode(data: &[u8], level: u32) -> Vec<u8> {
+        let mut writer = BzWriter::new(vec![], level);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn reference_decode(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        BzDecoder::new(compressed).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut x = 54321u32;
+        (0..len).map(|i| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            match (x >> 16) % 4 {
+                0 => (x >> 8) as u8,
+                1 => b'a' + (x >> 24) as u8 % 8,
+                _ => (i / 300) as u8,
+            }
+        }).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn reference_decodes_output(ref data in bytes_regex(".*").unwrap()) {
+            let compressed = encode(data, 9);
+            prop_assert_eq!(&reference_decode(&compressed)[..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn empty_stream_matches_reference() {
+        assert_eq!(encode(b"", 9), b"BZh9\x17\x72\x45\x38\x50\x90\0\0\0\0");
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let data = sample(250_000);
+        let compressed = encode(&data, 1);
+        assert_eq!(reference_decode(&compressed), data);
+
+        let mut out = vec![];
+        BzReader::new(&compressed[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn long_runs() {
+        let mut data = vec![7; 100_000];
+        data.extend(vec![0; 255]);
+        data.extend(vec![1; 256]);
+        data.extend(sample(1000));
+        for level in 1..10 {
+            assert_eq!(reference_decode(&encode(&data, level)), data);
+        }
+    }
+
+    #[test]
+    fn flushed_blocks_decode_straight_away() {
+        let data = sample(30_000);
+        let mut writer = BzWriter::new(vec![], 9);
+        let mut decompress = Decompress::new();
+        let mut buf = vec![0; 40_000];
+        let mut seen = 0;
+        let mut pos = 0;
+        // uneven pieces, so that blocks end at every offset within a byte
+        for len in (1..200).map(|i| i * 7 % 311 + 1) {
+            if pos + len > data.len() {
+                break;
+            }
+            writer.write_all(&data[pos..pos + len]).unwrap();
+            writer.flush_block().unwrap();
+            pos += len;
+
+            let (consumed, produced, _) = decompress.decompress(&writer.get_ref()[seen..], &mut buf).unwrap();
+            assert_eq!(consumed, writer.get_ref().len() - seen);
+            assert_eq!(decompress.total_out(), pos as u64);
+            assert_eq!(&buf[..produced], &data[pos - produced..pos]);
+            seen += consumed;
+        }
+
+        writer.write_all(&data[pos..]).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert_eq!(reference_decode(&compressed), data);
+    }
+
+    #[test]
+    fn write_after_finish_fails() {
+        let mut writer = BzWriter::new(vec![], 9);
+        writer.try_finish().unwrap();
+        assert!(writer.write(b"more").is_err());
+    }
+}
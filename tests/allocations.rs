@@ -0,0 +1,93 @@
+//! Checks that `Compress` allocates nothing once its working space is set
+//! up. The counting allocator is global, so these tests get a binary of
+//! their own.
+
+extern crate bzip2_rs;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use bzip2_rs::{Action, Compress, Decompress, Status};
+
+// Counts the allocations made on each thread.
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+#[test]
+fn reused_compress_does_not_allocate() {
+    let data: Vec<u8> = (0..250_000u32).map(|i| (i.wrapping_mul(i) >> 13) as u8).collect();
+    let mut encoder = Compress::new(1);
+    let mut out = vec![0; 300_000];
+
+    let mut stream = |encoder: &mut Compress| {
+        let mut input = &data[..];
+        let mut produced = 0;
+        loop {
+            let chunk = &input[..input.len().min(10_000)];
+            let action = if input.is_empty() { Action::Finish } else { Action::Run };
+            let (consumed, n, status) = encoder.compress(chunk, &mut out[produced..], action);
+            input = &input[consumed..];
+            produced += n;
+            if status == Status::StreamEnd {
+                return produced;
+            }
+        }
+    };
+
+    let first = stream(&mut encoder);
+    encoder.reset();
+    let before = ALLOCATIONS.with(Cell::get);
+    let second = stream(&mut encoder);
+    assert_eq!(ALLOCATIONS.with(Cell::get), before);
+    assert_eq!(second, first);
+
+    let mut decoded = vec![0; data.len()];
+    let (_, n, status) = Decompress::new().decompress(&out[..second], &mut decoded).unwrap();
+    assert_eq!((n, status), (data.len(), Status::StreamEnd));
+    assert_eq!(decoded, data);
+}
+
+#[test]
+fn flushing_does_not_allocate() {
+    // a long-lived stream ending a block per event
+    let mut encoder = Compress::new(1);
+    let mut out = vec![0; 10_000];
+    let mut event = |encoder: &mut Compress, i: u32| {
+        let data = format!("event {:05}: something happened\n", i);
+        let (consumed, _, status) = encoder.compress(data.as_bytes(), &mut out, Action::Flush);
+        assert_eq!((consumed, status), (data.len(), Status::Flushed));
+    };
+
+    // the working space settles within the first couple of blocks
+    event(&mut encoder, 0);
+    event(&mut encoder, 1);
+    let before = ALLOCATIONS.with(Cell::get);
+    for i in 2..1000 {
+        event(&mut encoder, i);
+    }
+    // only the formatting of each event allocates
+    assert_eq!(ALLOCATIONS.with(Cell::get), before + 998);
+    assert!(encoder.index().is_empty());
+}